@@ -0,0 +1,65 @@
+//! Timing shared by the benchmarks, which use no harness so that they need no dependencies.
+//!
+//! Each benchmark runs a workload a fixed number of times and prints the median, fastest and mean
+//! time. Arguments that do not start with `-` select the workloads whose names contain one of them,
+//! e.g. `cargo bench --bench evaluator -- fib`.
+
+// Each benchmark compiles this module on its own and uses only part of it.
+#![allow(dead_code)]
+
+use std::env;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+pub struct Bench {
+    filters: Vec<String>,
+}
+
+impl Bench {
+    pub fn from_args() -> Self {
+        // Cargo passes `--bench` to benchmarks without a harness.
+        let filters = env::args().skip(1).filter(|a| !a.starts_with('-'));
+        Self {
+            filters: filters.collect(),
+        }
+    }
+
+    /// Times `runs` runs of `f` and returns the median, or None when the workload is not selected
+    pub fn run<T>(&self, name: &str, runs: usize, mut f: impl FnMut() -> T) -> Option<Duration> {
+        if !self.filters.is_empty() && !self.filters.iter().any(|f| name.contains(f.as_str())) {
+            return None;
+        }
+        let mut times = Vec::with_capacity(runs);
+        for _ in 0..runs {
+            let start = Instant::now();
+            black_box(f());
+            times.push(start.elapsed());
+        }
+        times.sort();
+        let median = times[runs / 2];
+        let total = times.iter().sum::<Duration>();
+        println!(
+            "{}: median {:?}, fastest {:?}, mean {:?} over {} runs",
+            name,
+            median,
+            times[0],
+            total / runs as u32,
+            runs
+        );
+        Some(median)
+    }
+
+    /// Like `run`, and also prints how many bytes of input were processed per second
+    pub fn run_throughput<T>(&self, name: &str, runs: usize, bytes: usize, f: impl FnMut() -> T) {
+        if let Some(median) = self.run(name, runs, f) {
+            let megabytes = bytes as f64 / 1_000_000.0;
+            println!(
+                "{}: {:.1} MB in {:?}, {:.1} MB/s",
+                name,
+                megabytes,
+                median,
+                megabytes / median.as_secs_f64()
+            );
+        }
+    }
+}
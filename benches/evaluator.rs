@@ -0,0 +1,68 @@
+//! Times workloads on both engines: recursive fib, which is dominated by function calls,
+//! closures created and called in a loop, and a string built by repeated concatenation.
+//! Run with `cargo bench --bench evaluator`.
+
+mod common;
+
+use common::Bench;
+use Monkey::compiler;
+use Monkey::evaluator::Evaluator;
+use Monkey::lexer::Lexer;
+use Monkey::parser::Parser;
+use Monkey::vm::Vm;
+
+const WORKLOADS: [(&str, &str, &str); 3] = [
+    (
+        "fib(22)",
+        "
+let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } };
+fib(22)
+",
+        "17711",
+    ),
+    (
+        "closures",
+        "
+let adder = fn(n) { fn(x) { x + n } };
+let compose = fn(f, g) { fn(x) { g(f(x)) } };
+let mut total = 0;
+for (i in 0..20000) {
+    let add = compose(adder(i), adder(1));
+    total = add(total);
+}
+total
+",
+        "200010000",
+    ),
+    (
+        "string building",
+        r#"
+let mut s = "";
+let mut i = 0;
+while (i < 10000) {
+    s += "ab";
+    i += 1;
+}
+s[19998] + s[19999]
+"#,
+        "\"ab\"",
+    ),
+];
+
+const RUNS: usize = 10;
+
+fn main() {
+    let bench = Bench::from_args();
+    for (name, input, expected) in WORKLOADS {
+        let program = Parser::new(Lexer::new(input)).parse_program().unwrap();
+        let bytecode = compiler::compile(&program).unwrap();
+        bench.run(&format!("eval {}", name), RUNS, || {
+            let result = Evaluator::new().eval_program(&program).unwrap();
+            assert_eq!(result.to_string(), expected);
+        });
+        bench.run(&format!("vm {}", name), RUNS, || {
+            let result = Vm::new().run(&bytecode).unwrap();
+            assert_eq!(result.to_string(), expected);
+        });
+    }
+}
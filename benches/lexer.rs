@@ -0,0 +1,30 @@
+//! Times the lexer on a large input that uses every kind of token.
+//! Run with `cargo bench --bench lexer`.
+
+mod common;
+
+use common::Bench;
+use Monkey::lexer::Lexer;
+use Monkey::token::TokenType;
+
+const SNIPPET: &str = r#"
+let mut total = 0;
+const LIMIT = 1000;
+let add = fn(a, b = 1, ...rest) { a + b };
+for (i in 0..=LIMIT) {
+    if (i != 3 == !false) { total += add(i, b: 2) * (10 / 5) - 1; } else { continue; }
+}
+let greeting = "hello, \"world\"\n";
+let pairs = {"one": 1, "two": [1, 2, 3]};
+match pairs["two"] { [x, ..._] if x > 0 => x, _ => -1 };
+while (total < 0) { break; }
+"#;
+
+const RUNS: usize = 10;
+
+fn main() {
+    let bench = Bench::from_args();
+    let input = SNIPPET.repeat(1_000_000 / SNIPPET.len() + 1);
+    assert!(Lexer::new(&input).all(|token| token.token_type != TokenType::ILLEGAL));
+    bench.run_throughput("lex 1 MB", RUNS, input.len(), || Lexer::new(&input).count());
+}
@@ -0,0 +1,70 @@
+//! Times `Parser::parse_program` on long and on deeply nested programs.
+//! Run with `cargo bench --bench parser`.
+
+mod common;
+
+use common::Bench;
+use Monkey::lexer::Lexer;
+use Monkey::parser::Parser;
+
+const RUNS: usize = 10;
+
+/// Many short functions and calls, as in a long script
+fn long_program(statements: usize) -> String {
+    (0..statements)
+        .map(|i| {
+            let f = name(i);
+            format!(
+                "let {f} = fn(x, y = {i}) {{ if (x < y) {{ x * 2 }} else {{ [x, y][0] }} }};\n{f}({i}, y: 3);\n"
+            )
+        })
+        .collect()
+}
+
+/// Returns a distinct name for each number, since identifiers consist of letters
+fn name(mut i: usize) -> String {
+    let mut name = String::from("q");
+    loop {
+        name.push((b'a' + (i % 26) as u8) as char);
+        i /= 26;
+        if i == 0 {
+            return name;
+        }
+    }
+}
+
+/// Expressions, blocks and functions nested close to the parser's limit
+fn nested_program(statements: usize) -> String {
+    let depth = 60;
+    let expression = format!("{}1{}", "(1 + ".repeat(depth), ")".repeat(depth));
+    let blocks = format!(
+        "{}x{}",
+        "if (x) { ".repeat(depth / 2),
+        " }".repeat(depth / 2)
+    );
+    let functions = format!(
+        "{}x{}",
+        "fn(x) { ".repeat(depth / 2),
+        " }".repeat(depth / 2)
+    );
+    (0..statements)
+        .map(|_| format!("{};\n{};\n{};\n", expression, blocks, functions))
+        .collect()
+}
+
+fn parse(input: &str) -> usize {
+    let program = Parser::new(Lexer::new(input)).parse_program().unwrap();
+    program.statements.len()
+}
+
+fn main() {
+    let bench = Bench::from_args();
+    let long = long_program(5000);
+    let nested = nested_program(300);
+    assert_eq!(parse(&long), 10000);
+    assert_eq!(parse(&nested), 900);
+    bench.run_throughput("parse long program", RUNS, long.len(), || parse(&long));
+    bench.run_throughput("parse nested program", RUNS, nested.len(), || {
+        parse(&nested)
+    });
+}
@@ -1,19 +1,55 @@
+use crate::symbol::Symbol;
+use crate::token::Position;
+use std::cell::Cell;
 use std::fmt::Display;
+use std::rc::Rc;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BindingKind {
+    Let,
+    LetMut,
+    Const,
+}
+
+impl BindingKind {
+    pub fn is_mutable(&self) -> bool {
+        *self == BindingKind::LetMut
+    }
+}
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Statement {
     EmptyStatement,
-    LetStatement { name: String, value: Expression },
+    LetStatement {
+        kind: BindingKind,
+        pattern: Pattern,
+        value: Expression,
+        position: Position,
+    },
     ReturnStatement(Expression),
     ExpressionStatement(Expression),
-    BlockStatement { statements: Vec<Statement> },
+    BlockStatement {
+        statements: Vec<Statement>,
+    },
+    WhileStatement {
+        condition: Expression,
+        body: Box<Statement>,
+    },
+    ForStatement {
+        variable: Identifier,
+        iterable: Expression,
+        body: Box<Statement>,
+    },
+    BreakStatement,
+    ContinueStatement,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Expression {
     EmptyExpression,
-    Identifier(String),
+    Identifier(Identifier),
     IntegerLiteral(i64),
+    StringLiteral(String),
     Boolean(bool),
     PrefixExpression {
         operator: String,
@@ -29,19 +65,132 @@ pub enum Expression {
         consequence: Box<Statement>,
         alternative: Option<Box<Statement>>,
     },
-    FunctionLiteral {
-        parameters: Vec<Expression>,
-        body: Box<Statement>,
+    MatchExpression {
+        subject: Box<Expression>,
+        arms: Vec<MatchArm>,
     },
+    /// Shared with the functions created from it, so that they do not copy its body
+    FunctionLiteral(Rc<FunctionLiteral>),
     CallExpression {
         function: Box<Expression>,
-        arguments: Vec<Expression>,
+        arguments: Vec<Argument>,
+        /// Where the call expression starts
+        position: Position,
+    },
+    AssignExpression {
+        name: Identifier,
+        operator: String,
+        value: Box<Expression>,
+    },
+    ArrayLiteral(Vec<Expression>),
+    HashLiteral(Vec<(Expression, Expression)>),
+    IndexExpression {
+        left: Box<Expression>,
+        index: Box<Expression>,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Pattern {
+    Wildcard,
+    Literal(Expression),
+    Binding(Identifier),
+    Array {
+        elements: Vec<Pattern>,
+        rest: Option<Identifier>,
     },
+    Hash(Vec<(Expression, Pattern)>),
+}
+
+/// A function literal. `fn(x) { x }` has the parameter `x` and the body `{ x }`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct FunctionLiteral {
+    /// The name of the `let` binding the function is assigned to, if any
+    pub name: Option<Symbol>,
+    pub parameters: Vec<Parameter>,
+    pub rest: Option<Identifier>,
+    pub body: Statement,
+}
+
+impl Display for FunctionLiteral {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut params = self
+            .parameters
+            .iter()
+            .map(|p| format!("{}", p))
+            .collect::<Vec<String>>();
+        if let Some(rest) = &self.rest {
+            params.push(format!("...{}", rest));
+        }
+        write!(f, "fn({}) {}", params.join(", "), self.body)
+    }
+}
+
+/// Where a variable is stored at run time: `index` in the scope `depth` scopes out from the one it is used in
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Slot {
+    pub depth: usize,
+    pub index: usize,
+}
+
+/// A variable name where it is declared or used. The resolver fills in its slot after parsing.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Identifier {
+    pub name: Symbol,
+    pub slot: Cell<Option<Slot>>,
+}
+
+impl From<&str> for Identifier {
+    fn from(name: &str) -> Self {
+        Self {
+            name: Symbol::intern(name),
+            slot: Cell::new(None),
+        }
+    }
+}
+
+impl Display for Identifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
 }
 
-#[derive(Debug, PartialEq)]
+/// A function parameter, which takes the value of its default expression when no argument is given
+#[derive(Debug, PartialEq, Clone)]
+pub struct Parameter {
+    pub pattern: Pattern,
+    pub default: Option<Expression>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Argument {
+    Positional(Expression),
+    /// `...xs` passes every element of `xs` as a positional argument
+    Spread(Expression),
+    /// `name: value` binds the parameter called `name`
+    Named(Symbol, Expression),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MatchArm {
+    pub pattern: Pattern,
+    pub guard: Option<Expression>,
+    pub body: Statement,
+}
+
+#[derive(Debug, Clone)]
 pub struct Program {
     pub statements: Vec<Statement>,
+    /// The memory that parsing the program added to the symbol table, which counts against
+    /// the memory limit of the engines that run it
+    pub names_size: usize,
+}
+
+// Programs with the same statements are equal, whichever of them added the names to the table.
+impl PartialEq for Program {
+    fn eq(&self, other: &Self) -> bool {
+        self.statements == other.statements
+    }
 }
 
 impl Display for Expression {
@@ -50,6 +199,7 @@ impl Display for Expression {
             Expression::EmptyExpression => write!(f, ""),
             Expression::Identifier(ident) => write!(f, "{}", ident),
             Expression::IntegerLiteral(int) => write!(f, "{}", int),
+            Expression::StringLiteral(s) => write!(f, "{:?}", s),
             Expression::Boolean(b) => write!(f, "{}", b),
             Expression::PrefixExpression { operator, right } => {
                 write!(f, "({}{})", operator, right)
@@ -65,22 +215,25 @@ impl Display for Expression {
                 alternative,
             } => {
                 let alt = match alternative {
-                    Some(alt) => format!("else {}", alt),
+                    // An else-if alternative is an if expression, which prints the rest of the chain
+                    Some(alt) => format!(" else {}", alt),
                     None => "".to_string(),
                 };
-                write!(f, "if {} {} {}", condition, consequence, alt)
+                write!(f, "if {} {}{}", condition, consequence, alt)
             }
-            Expression::FunctionLiteral { parameters, body } => {
-                let params = parameters
+            Expression::MatchExpression { subject, arms } => {
+                let arms = arms
                     .iter()
-                    .map(|p| format!("{}", p))
+                    .map(|a| format!("{}", a))
                     .collect::<Vec<String>>()
                     .join(", ");
-                write!(f, "fn({}) {}", params, body)
+                write!(f, "match {} {{ {} }}", subject, arms)
             }
+            Expression::FunctionLiteral(function) => write!(f, "{}", function),
             Expression::CallExpression {
                 function,
                 arguments,
+                ..
             } => {
                 let args = arguments
                     .iter()
@@ -89,6 +242,94 @@ impl Display for Expression {
                     .join(", ");
                 write!(f, "{}({})", function, args)
             }
+            Expression::AssignExpression {
+                name,
+                operator,
+                value,
+            } => write!(f, "({} {} {})", name, operator, value),
+            Expression::ArrayLiteral(elements) => {
+                let elements = elements
+                    .iter()
+                    .map(|e| format!("{}", e))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                write!(f, "[{}]", elements)
+            }
+            Expression::HashLiteral(pairs) => {
+                let pairs = pairs
+                    .iter()
+                    .map(|(k, v)| format!("{}: {}", k, v))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                write!(f, "{{{}}}", pairs)
+            }
+            Expression::IndexExpression { left, index } => write!(f, "({}[{}])", left, index),
+        }
+    }
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Pattern::Wildcard => write!(f, "_"),
+            Pattern::Literal(expr) => write!(f, "{}", expr),
+            Pattern::Binding(name) => write!(f, "{}", name),
+            Pattern::Array { elements, rest } => {
+                let mut elements = elements
+                    .iter()
+                    .map(|e| format!("{}", e))
+                    .collect::<Vec<String>>();
+                if let Some(rest) = rest {
+                    elements.push(format!("...{}", rest));
+                }
+                write!(f, "[{}]", elements.join(", "))
+            }
+            Pattern::Hash(pairs) => {
+                let pairs = pairs
+                    .iter()
+                    .map(|(k, v)| format!("{}: {}", k, v))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                write!(f, "{{{}}}", pairs)
+            }
+        }
+    }
+}
+
+impl Display for Parameter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.default {
+            Some(default) => write!(f, "{} = {}", self.pattern, default),
+            None => write!(f, "{}", self.pattern),
+        }
+    }
+}
+
+impl Display for Argument {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Argument::Positional(expr) => write!(f, "{}", expr),
+            Argument::Spread(expr) => write!(f, "...{}", expr),
+            Argument::Named(name, expr) => write!(f, "{}: {}", name, expr),
+        }
+    }
+}
+
+impl Display for MatchArm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.guard {
+            Some(guard) => write!(f, "{} if {} => {}", self.pattern, guard, self.body),
+            None => write!(f, "{} => {}", self.pattern, self.body),
+        }
+    }
+}
+
+impl Display for BindingKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BindingKind::Let => write!(f, "let"),
+            BindingKind::LetMut => write!(f, "let mut"),
+            BindingKind::Const => write!(f, "const"),
         }
     }
 }
@@ -97,7 +338,12 @@ impl Display for Statement {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Statement::EmptyStatement => write!(f, ""),
-            Statement::LetStatement { name, value } => write!(f, "let {} = {};", name, value),
+            Statement::LetStatement {
+                kind,
+                pattern,
+                value,
+                ..
+            } => write!(f, "{} {} = {};", kind, pattern, value),
             Statement::ReturnStatement(expr) => write!(f, "return {};", expr),
             Statement::ExpressionStatement(expr) => write!(f, "{}", expr),
             Statement::BlockStatement { statements } => {
@@ -108,6 +354,16 @@ impl Display for Statement {
                     .join("\n");
                 write!(f, "{{\n {}\n}}", result)
             }
+            Statement::WhileStatement { condition, body } => {
+                write!(f, "while {} {}", condition, body)
+            }
+            Statement::ForStatement {
+                variable,
+                iterable,
+                body,
+            } => write!(f, "for ({} in {}) {}", variable, iterable, body),
+            Statement::BreakStatement => write!(f, "break;"),
+            Statement::ContinueStatement => write!(f, "continue;"),
         }
     }
 }
@@ -131,14 +387,32 @@ mod tests {
     #[test]
     fn test_string() {
         let program = Program {
+            names_size: 0,
             statements: vec![
                 Statement::LetStatement {
-                    name: "myVar".to_string(),
-                    value: Expression::Identifier("anotherVar".to_string()),
+                    kind: BindingKind::Let,
+                    pattern: Pattern::Binding("myVar".into()),
+                    value: Expression::Identifier("anotherVar".into()),
+                    position: Position::default(),
+                },
+                Statement::LetStatement {
+                    kind: BindingKind::LetMut,
+                    pattern: Pattern::Binding("counter".into()),
+                    value: Expression::IntegerLiteral(0),
+                    position: Position::default(),
+                },
+                Statement::LetStatement {
+                    kind: BindingKind::Const,
+                    pattern: Pattern::Binding("LIMIT".into()),
+                    value: Expression::IntegerLiteral(10),
+                    position: Position::default(),
                 },
                 Statement::ReturnStatement(Expression::IntegerLiteral(5)),
             ],
         };
-        assert_eq!(format!("{}", program), "let myVar = anotherVar;\nreturn 5;");
+        assert_eq!(
+            format!("{}", program),
+            "let myVar = anotherVar;\nlet mut counter = 0;\nconst LIMIT = 10;\nreturn 5;"
+        );
     }
 }
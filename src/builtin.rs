@@ -0,0 +1,47 @@
+//! Functions that every program can call without declaring them.
+//! A declaration of the same name shadows a builtin.
+
+use crate::gc;
+use crate::object::Object;
+use crate::symbol::Symbol;
+use anyhow::{bail, Result};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Builtin {
+    /// `gc()` runs the collector and returns how many environments it freed
+    Gc,
+}
+
+impl Builtin {
+    /// Returns the builtin called `name`, if there is one
+    pub fn lookup(name: Symbol) -> Option<Builtin> {
+        match name.as_str() {
+            "gc" => Some(Builtin::Gc),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Builtin::Gc => "gc",
+        }
+    }
+
+    pub fn apply(self, arguments: &[Object], named: &[(Symbol, Object)]) -> Result<Object> {
+        if let Some((name, _)) = named.first() {
+            bail!("unknown parameter: {}", name);
+        }
+        match self {
+            Builtin::Gc => {
+                if !arguments.is_empty() {
+                    bail!(
+                        "wrong number of arguments: expected=0, got={}",
+                        arguments.len()
+                    );
+                }
+                let freed = gc::collect();
+                Ok(Object::Integer(freed.try_into().unwrap_or(i64::MAX)))
+            }
+        }
+    }
+}
@@ -0,0 +1,381 @@
+use crate::ast::BindingKind;
+use crate::object::{HashKey, Object};
+use crate::symbol::Symbol;
+use crate::token::Position;
+use std::rc::Rc;
+
+/// An index into the constant pool
+pub type ConstantIndex = u32;
+
+/// An index into the instructions of a function
+pub type Address = u32;
+
+/// The instructions of the virtual machine. Each pops its operands from the stack and pushes its result.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Instruction {
+    /// Pushes an integer or string constant
+    Constant(ConstantIndex),
+    True,
+    False,
+    Null,
+    /// Discards the value on top of the stack
+    Pop,
+    /// Pushes the value bound to the name
+    GetName(ConstantIndex),
+    /// Matches the value against the pattern and declares its variables in the current scope
+    Let {
+        pattern: ConstantIndex,
+        kind: BindingKind,
+        position: Position,
+    },
+    /// Assigns the value to the nearest binding of the name, leaving the value on the stack
+    Assign(ConstantIndex),
+    /// Applies the operator to the value bound to the name and the value, and assigns the result
+    CompoundAssign {
+        name: ConstantIndex,
+        operator: Operator,
+    },
+    /// `!x`
+    Bang,
+    /// `-x`
+    Minus,
+    Infix(Operator),
+    /// `left[index]`
+    Index,
+    /// Collects the given number of values into an array
+    Array(u32),
+    /// Fails unless the value on top of the stack can be used as a hash key
+    CheckHashKey,
+    /// Collects the given number of key-value pairs into a hash
+    Hash(u32),
+    Jump(Address),
+    /// Jumps unless the value is true, after casting it to a boolean
+    JumpIfNotTrue(Address),
+    /// Creates a closure of the function over the current scope
+    Closure(ConstantIndex),
+    /// Fails unless the value on top of the stack can be called
+    CheckCallable,
+    /// Turns the value on top of the stack into an array of the items it spreads into
+    Spread,
+    /// Calls the function below the arguments of the call site
+    Call(ConstantIndex),
+    /// Calls the function in place of the current call
+    TailCall(ConstantIndex),
+    /// Returns the value on top of the stack from the current call
+    Return,
+    /// Pushes the argument given for the parameter and jumps to the target, or falls through
+    /// to the code of its default value if there is none
+    ArgumentOrJump {
+        parameter: u32,
+        target: Address,
+    },
+    /// Matches the value against the parameter's pattern, collecting the variables it binds
+    BindParameter(u32),
+    /// Enters the scope of the function body, which holds the bound parameters
+    EnterBody,
+    /// Enters a new scope enclosed by the current one
+    PushScope,
+    /// Returns to the scope enclosing the current one
+    PopScope,
+    /// Starts a loop. `break` jumps to `exit` and `continue` to `next`.
+    EnterLoop {
+        exit: Address,
+        next: Address,
+    },
+    ExitLoop,
+    Break,
+    Continue,
+    /// Starts iterating over the items of the value
+    Iterate,
+    /// Binds the next item to the variable in a new scope, or jumps to `exit` when there are none left
+    IterateNext {
+        variable: ConstantIndex,
+        exit: Address,
+    },
+    EndIteration,
+    /// Matches the subject on top of the stack against the pattern. On success the variables it binds
+    /// are put in a new scope, otherwise it jumps to `next`.
+    MatchArm {
+        pattern: ConstantIndex,
+        next: Address,
+    },
+    /// Fails because no match arm matched the subject on top of the stack
+    NoMatch,
+}
+
+/// The operators of infix expressions
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    LessThan,
+    GreaterThan,
+    Equal,
+    NotEqual,
+    Range,
+    RangeInclusive,
+}
+
+impl Operator {
+    pub fn from_symbol(operator: &str) -> Option<Operator> {
+        match operator {
+            "+" => Some(Operator::Add),
+            "-" => Some(Operator::Subtract),
+            "*" => Some(Operator::Multiply),
+            "/" => Some(Operator::Divide),
+            "<" => Some(Operator::LessThan),
+            ">" => Some(Operator::GreaterThan),
+            "==" => Some(Operator::Equal),
+            "!=" => Some(Operator::NotEqual),
+            ".." => Some(Operator::Range),
+            "..=" => Some(Operator::RangeInclusive),
+            _ => None,
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Operator::Add => "+",
+            Operator::Subtract => "-",
+            Operator::Multiply => "*",
+            Operator::Divide => "/",
+            Operator::LessThan => "<",
+            Operator::GreaterThan => ">",
+            Operator::Equal => "==",
+            Operator::NotEqual => "!=",
+            Operator::Range => "..",
+            Operator::RangeInclusive => "..=",
+        }
+    }
+}
+
+/// The values referred to by instructions
+#[derive(Debug, PartialEq, Clone)]
+pub enum Constant {
+    Integer(i64),
+    String(String),
+    /// The name of a variable
+    Name(Symbol),
+    Pattern(CompiledPattern),
+    Function(Rc<CompiledFunction>),
+    CallSite(CallSite),
+}
+
+impl Constant {
+    /// Returns the value an instruction pushes for the constant
+    pub fn to_object(&self) -> Option<Object> {
+        match self {
+            Constant::Integer(i) => Some(Object::Integer(*i)),
+            Constant::String(s) => Some(Object::String(s.clone())),
+            _ => None,
+        }
+    }
+}
+
+/// A pattern whose literals have been evaluated
+#[derive(Debug, PartialEq, Clone)]
+pub struct CompiledPattern {
+    pub pattern: PatternNode,
+    /// The pattern as written, for error messages
+    pub source: String,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum PatternNode {
+    Wildcard,
+    Literal(HashKey),
+    Binding(Symbol),
+    Array {
+        elements: Vec<PatternNode>,
+        rest: Option<Symbol>,
+    },
+    Hash(Vec<(HashKey, PatternNode)>),
+}
+
+/// What a call passes for each of its arguments
+#[derive(Debug, PartialEq, Clone)]
+pub enum ArgumentKind {
+    Positional,
+    /// An array of values passed as positional arguments
+    Spread,
+    Named(Symbol),
+}
+
+/// A call expression, described for the call instruction and the stack trace
+#[derive(Debug, PartialEq, Clone)]
+pub struct CallSite {
+    pub callee: Symbol,
+    pub position: Position,
+    pub arguments: Vec<ArgumentKind>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CompiledParameter {
+    pub pattern: CompiledPattern,
+    pub has_default: bool,
+}
+
+/// A function literal or the top-level program, compiled to instructions
+#[derive(Debug, PartialEq, Clone)]
+pub struct CompiledFunction {
+    pub name: Option<Symbol>,
+    pub parameters: Vec<CompiledParameter>,
+    pub rest: Option<Symbol>,
+    pub instructions: Vec<Instruction>,
+    /// The debug line table: where in the source the instructions at these addresses come from,
+    /// for the let statements and calls, in address order
+    pub lines: Vec<(Address, Position)>,
+    /// The function literal as written, which is how the function is displayed
+    pub source: String,
+}
+
+/// A compiled program: the instructions of its top level and the constants they refer to
+#[derive(Debug, Clone)]
+pub struct Bytecode {
+    pub main: Rc<CompiledFunction>,
+    pub constants: Vec<Constant>,
+    /// The memory that parsing or decoding the program added to the symbol table, which counts
+    /// against the memory limit of the virtual machine
+    pub names_size: usize,
+}
+
+// Like programs, bytecode is compared without the names it added to the table.
+impl PartialEq for Bytecode {
+    fn eq(&self, other: &Self) -> bool {
+        self.main == other.main && self.constants == other.constants
+    }
+}
+
+impl Bytecode {
+    /// Returns the top level followed by the functions in the constant pool, which is how
+    /// functions are numbered in bytecode files and disassembly
+    pub fn functions(&self) -> Vec<&Rc<CompiledFunction>> {
+        let mut functions = vec![&self.main];
+        for constant in &self.constants {
+            if let Constant::Function(function) = constant {
+                functions.push(function);
+            }
+        }
+        functions
+    }
+
+    /// Lists the instructions of each function, with the constants they refer to and their positions in the source
+    pub fn disassemble(&self) -> String {
+        let functions = self.functions();
+        let mut output = String::new();
+        for (number, function) in functions.iter().enumerate() {
+            if number > 0 {
+                output.push('\n');
+            }
+            let name = match (&function.name, number) {
+                (_, 0) => "<program>",
+                (Some(name), _) => name.as_str(),
+                (None, _) => "<anonymous>",
+            };
+            output += &format!("== function {}: {} ==\n", number, name);
+            for (address, instruction) in function.instructions.iter().enumerate() {
+                let position = function
+                    .lines
+                    .iter()
+                    .find(|(at, _)| *at as usize == address)
+                    .map_or(String::new(), |(_, position)| position.to_string());
+                let line = format!("{:04} {:>7}  {}", address, position, instruction);
+                match self.describe_operand(instruction, &functions) {
+                    Some(comment) => output += &format!("{:<40} ; {}\n", line, comment),
+                    None => output += &format!("{}\n", line),
+                }
+            }
+        }
+        output
+    }
+
+    /// Describes the constant an instruction refers to
+    fn describe_operand(
+        &self,
+        instruction: &Instruction,
+        functions: &[&Rc<CompiledFunction>],
+    ) -> Option<String> {
+        let index = match *instruction {
+            Instruction::Constant(index)
+            | Instruction::GetName(index)
+            | Instruction::Let { pattern: index, .. }
+            | Instruction::Assign(index)
+            | Instruction::CompoundAssign { name: index, .. }
+            | Instruction::Closure(index)
+            | Instruction::Call(index)
+            | Instruction::TailCall(index)
+            | Instruction::IterateNext {
+                variable: index, ..
+            }
+            | Instruction::MatchArm { pattern: index, .. } => index,
+            _ => return None,
+        };
+        let description = match self.constants.get(index as usize)? {
+            Constant::Integer(i) => i.to_string(),
+            Constant::String(s) => format!("{:?}", s),
+            Constant::Name(name) => name.to_string(),
+            Constant::Pattern(pattern) => pattern.source.clone(),
+            Constant::Function(function) => {
+                let number = functions.iter().position(|f| Rc::ptr_eq(f, function))?;
+                format!("function {}", number)
+            }
+            Constant::CallSite(call_site) => {
+                format!("{}/{}", call_site.callee, call_site.arguments.len())
+            }
+        };
+        Some(description)
+    }
+}
+
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Instruction::Constant(index) => write!(f, "Constant {}", index),
+            Instruction::True => write!(f, "True"),
+            Instruction::False => write!(f, "False"),
+            Instruction::Null => write!(f, "Null"),
+            Instruction::Pop => write!(f, "Pop"),
+            Instruction::GetName(name) => write!(f, "GetName {}", name),
+            Instruction::Let { pattern, kind, .. } => write!(f, "Let {} {}", pattern, kind),
+            Instruction::Assign(name) => write!(f, "Assign {}", name),
+            Instruction::CompoundAssign { name, operator } => {
+                write!(f, "CompoundAssign {} {}", name, operator.symbol())
+            }
+            Instruction::Bang => write!(f, "Bang"),
+            Instruction::Minus => write!(f, "Minus"),
+            Instruction::Infix(operator) => write!(f, "Infix {}", operator.symbol()),
+            Instruction::Index => write!(f, "Index"),
+            Instruction::Array(length) => write!(f, "Array {}", length),
+            Instruction::CheckHashKey => write!(f, "CheckHashKey"),
+            Instruction::Hash(length) => write!(f, "Hash {}", length),
+            Instruction::Jump(target) => write!(f, "Jump {}", target),
+            Instruction::JumpIfNotTrue(target) => write!(f, "JumpIfNotTrue {}", target),
+            Instruction::Closure(function) => write!(f, "Closure {}", function),
+            Instruction::CheckCallable => write!(f, "CheckCallable"),
+            Instruction::Spread => write!(f, "Spread"),
+            Instruction::Call(call_site) => write!(f, "Call {}", call_site),
+            Instruction::TailCall(call_site) => write!(f, "TailCall {}", call_site),
+            Instruction::Return => write!(f, "Return"),
+            Instruction::ArgumentOrJump { parameter, target } => {
+                write!(f, "ArgumentOrJump {} {}", parameter, target)
+            }
+            Instruction::BindParameter(parameter) => write!(f, "BindParameter {}", parameter),
+            Instruction::EnterBody => write!(f, "EnterBody"),
+            Instruction::PushScope => write!(f, "PushScope"),
+            Instruction::PopScope => write!(f, "PopScope"),
+            Instruction::EnterLoop { exit, next } => write!(f, "EnterLoop {} {}", exit, next),
+            Instruction::ExitLoop => write!(f, "ExitLoop"),
+            Instruction::Break => write!(f, "Break"),
+            Instruction::Continue => write!(f, "Continue"),
+            Instruction::Iterate => write!(f, "Iterate"),
+            Instruction::IterateNext { variable, exit } => {
+                write!(f, "IterateNext {} {}", variable, exit)
+            }
+            Instruction::EndIteration => write!(f, "EndIteration"),
+            Instruction::MatchArm { pattern, next } => write!(f, "MatchArm {} {}", pattern, next),
+            Instruction::NoMatch => write!(f, "NoMatch"),
+        }
+    }
+}
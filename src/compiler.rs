@@ -0,0 +1,628 @@
+use crate::ast::{
+    Argument, Expression, Identifier, MatchArm, Parameter, Pattern, Program, Statement,
+};
+use crate::code::{
+    Address, ArgumentKind, Bytecode, CallSite, CompiledFunction, CompiledParameter,
+    CompiledPattern, Constant, ConstantIndex, Instruction, Operator, PatternNode,
+};
+use crate::evaluator::callee_name;
+use crate::object::HashKey;
+use crate::resolver;
+use crate::symbol::{Symbol, SymbolMap};
+use crate::token::Position;
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Compiles the program to bytecode for the virtual machine, after the same static checks as the evaluator
+pub fn compile(program: &Program) -> Result<Bytecode> {
+    resolver::check_program(program)?;
+    let mut compiler = Compiler::default();
+    compiler.compile_block(&program.statements, false)?;
+    compiler.emit(Instruction::Return);
+    let main = CompiledFunction {
+        name: None,
+        parameters: Vec::new(),
+        rest: None,
+        instructions: compiler.instructions,
+        lines: compiler.lines,
+        source: "<program>".to_string(),
+    };
+    Ok(Bytecode {
+        main: Rc::new(main),
+        constants: compiler.constants,
+        names_size: program.names_size,
+    })
+}
+
+#[derive(Default)]
+struct Compiler {
+    constants: Vec<Constant>,
+    // The integer, string and name constants, so that each is stored once
+    integers: HashMap<i64, ConstantIndex>,
+    strings: HashMap<String, ConstantIndex>,
+    names: SymbolMap<ConstantIndex>,
+    // The instructions of the function being compiled
+    instructions: Vec<Instruction>,
+    // The debug line table of the function being compiled
+    lines: Vec<(Address, Position)>,
+    // Whether a function body is being compiled, where calls in tail position replace the current call
+    in_function: bool,
+    // The number of loops enclosing the code being compiled
+    loops: usize,
+}
+
+impl Compiler {
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        self.instructions.push(instruction);
+        self.instructions.len() - 1
+    }
+
+    /// Returns the address of the next instruction
+    fn here(&self) -> Address {
+        self.instructions.len() as Address
+    }
+
+    /// Records that the next instruction comes from the position in the source
+    fn mark(&mut self, position: Position) {
+        let here = self.here();
+        self.lines.push((here, position));
+    }
+
+    /// Points the jump at `at` to the next instruction
+    fn patch(&mut self, at: usize) {
+        let here = self.here();
+        match &mut self.instructions[at] {
+            Instruction::Jump(target)
+            | Instruction::JumpIfNotTrue(target)
+            | Instruction::ArgumentOrJump { target, .. }
+            | Instruction::IterateNext { exit: target, .. }
+            | Instruction::MatchArm { next: target, .. } => *target = here,
+            instruction => unreachable!("{:?} is not a jump", instruction),
+        }
+    }
+
+    fn add_constant(&mut self, constant: Constant) -> ConstantIndex {
+        self.constants.push(constant);
+        (self.constants.len() - 1) as ConstantIndex
+    }
+
+    fn add_integer(&mut self, i: i64) -> ConstantIndex {
+        if let Some(&index) = self.integers.get(&i) {
+            return index;
+        }
+        let index = self.add_constant(Constant::Integer(i));
+        self.integers.insert(i, index);
+        index
+    }
+
+    fn add_string(&mut self, s: &str) -> ConstantIndex {
+        if let Some(&index) = self.strings.get(s) {
+            return index;
+        }
+        let index = self.add_constant(Constant::String(s.to_string()));
+        self.strings.insert(s.to_string(), index);
+        index
+    }
+
+    fn add_name(&mut self, name: Symbol) -> ConstantIndex {
+        if let Some(&index) = self.names.get(&name) {
+            return index;
+        }
+        let index = self.add_constant(Constant::Name(name));
+        self.names.insert(name, index);
+        index
+    }
+
+    fn add_pattern(&mut self, pattern: &Pattern) -> Result<ConstantIndex> {
+        let pattern = compile_pattern(pattern)?;
+        Ok(self.add_constant(Constant::Pattern(pattern)))
+    }
+
+    /// Compiles the statements so that they leave the value of the last one on the stack
+    fn compile_block(&mut self, statements: &[Statement], tail: bool) -> Result<()> {
+        match statements.split_last() {
+            Some((last, statements)) => {
+                for statement in statements {
+                    self.compile_effect(statement)?;
+                }
+                self.compile_branch(last, tail)
+            }
+            None => {
+                self.emit(Instruction::Null);
+                Ok(())
+            }
+        }
+    }
+
+    /// Compiles a statement whose value is not used
+    fn compile_effect(&mut self, statement: &Statement) -> Result<()> {
+        match statement {
+            Statement::EmptyStatement => {}
+            Statement::LetStatement { .. }
+            | Statement::WhileStatement { .. }
+            | Statement::ForStatement { .. } => self.compile_statement_without_value(statement)?,
+            statement => {
+                self.compile_statement(statement)?;
+                self.emit(Instruction::Pop);
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_branch(&mut self, statement: &Statement, tail: bool) -> Result<()> {
+        if tail {
+            self.compile_tail_statement(statement)
+        } else {
+            self.compile_statement(statement)
+        }
+    }
+
+    /// Compiles a statement so that it leaves its value on the stack
+    fn compile_statement(&mut self, statement: &Statement) -> Result<()> {
+        match statement {
+            Statement::ExpressionStatement(expr) => self.compile_expression(expr)?,
+            Statement::BlockStatement { statements } => self.compile_block(statements, false)?,
+            Statement::ReturnStatement(expr) => {
+                // The returned expression is in tail position.
+                self.compile_tail_expression(expr)?;
+                self.emit(Instruction::Return);
+            }
+            Statement::BreakStatement => self.compile_loop_control("break", Instruction::Break)?,
+            Statement::ContinueStatement => {
+                self.compile_loop_control("continue", Instruction::Continue)?
+            }
+            statement => {
+                // Let statements, loops and empty statements evaluate to null.
+                self.compile_statement_without_value(statement)?;
+                self.emit(Instruction::Null);
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_statement_without_value(&mut self, statement: &Statement) -> Result<()> {
+        match statement {
+            Statement::LetStatement {
+                kind,
+                pattern,
+                value,
+                position,
+            } => {
+                self.compile_expression(value)?;
+                let pattern = self.add_pattern(pattern)?;
+                self.mark(*position);
+                self.emit(Instruction::Let {
+                    pattern,
+                    kind: *kind,
+                    position: *position,
+                });
+            }
+            Statement::WhileStatement { condition, body } => {
+                self.compile_while_statement(condition, body)?
+            }
+            Statement::ForStatement {
+                variable,
+                iterable,
+                body,
+            } => self.compile_for_statement(variable.name, iterable, body)?,
+            Statement::EmptyStatement => {}
+            statement => {
+                self.compile_statement(statement)?;
+                self.emit(Instruction::Pop);
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_loop_control(&mut self, keyword: &str, instruction: Instruction) -> Result<()> {
+        if self.loops == 0 {
+            bail!("{} outside of loop", keyword);
+        }
+        self.emit(instruction);
+        Ok(())
+    }
+
+    fn compile_while_statement(&mut self, condition: &Expression, body: &Statement) -> Result<()> {
+        // The condition is evaluated in the enclosing scope and the body in a fresh scope for each iteration.
+        let enter = self.emit(Instruction::EnterLoop { exit: 0, next: 0 });
+        let next = self.here();
+        self.compile_expression(condition)?;
+        let exit_jump = self.emit(Instruction::JumpIfNotTrue(0));
+        self.emit(Instruction::PushScope);
+        self.compile_loop_body(body)?;
+        self.emit(Instruction::PopScope);
+        self.emit(Instruction::Jump(next));
+        self.patch(exit_jump);
+        self.instructions[enter] = Instruction::EnterLoop {
+            exit: self.here(),
+            next,
+        };
+        self.emit(Instruction::ExitLoop);
+        Ok(())
+    }
+
+    fn compile_for_statement(
+        &mut self,
+        variable: Symbol,
+        iterable: &Expression,
+        body: &Statement,
+    ) -> Result<()> {
+        // Each iteration binds the item in a fresh scope, so closures capture the current item.
+        self.compile_expression(iterable)?;
+        self.emit(Instruction::Iterate);
+        let enter = self.emit(Instruction::EnterLoop { exit: 0, next: 0 });
+        let next = self.here();
+        let variable = self.add_name(variable);
+        let exit_jump = self.emit(Instruction::IterateNext { variable, exit: 0 });
+        self.compile_loop_body(body)?;
+        self.emit(Instruction::PopScope);
+        self.emit(Instruction::Jump(next));
+        self.patch(exit_jump);
+        self.instructions[enter] = Instruction::EnterLoop {
+            exit: self.here(),
+            next,
+        };
+        self.emit(Instruction::ExitLoop);
+        self.emit(Instruction::EndIteration);
+        Ok(())
+    }
+
+    fn compile_loop_body(&mut self, body: &Statement) -> Result<()> {
+        self.loops += 1;
+        let result = self.compile_effect(body);
+        self.loops -= 1;
+        result
+    }
+
+    /// Compiles a statement whose value is returned from the function, so that a call in its
+    /// last expression replaces the current call
+    fn compile_tail_statement(&mut self, statement: &Statement) -> Result<()> {
+        match statement {
+            Statement::BlockStatement { statements } => self.compile_block(statements, true),
+            Statement::ExpressionStatement(expr) => self.compile_tail_expression(expr),
+            statement => self.compile_statement(statement),
+        }
+    }
+
+    fn compile_tail_expression(&mut self, expression: &Expression) -> Result<()> {
+        // The top level is not a call, so it has no call to replace.
+        if !self.in_function {
+            return self.compile_expression(expression);
+        }
+        match expression {
+            Expression::CallExpression {
+                function,
+                arguments,
+                position,
+            } => self.compile_call_expression(function, arguments, *position, true),
+            Expression::IfExpression {
+                condition,
+                consequence,
+                alternative,
+            } => self.compile_if_expression(condition, consequence, alternative, true),
+            Expression::MatchExpression { subject, arms } => {
+                self.compile_match_expression(subject, arms, true)
+            }
+            expression => self.compile_expression(expression),
+        }
+    }
+
+    fn compile_expression(&mut self, expression: &Expression) -> Result<()> {
+        match expression {
+            Expression::EmptyExpression => {
+                self.emit(Instruction::Null);
+            }
+            Expression::Identifier(identifier) => {
+                let name = self.add_name(identifier.name);
+                self.emit(Instruction::GetName(name));
+            }
+            Expression::IntegerLiteral(i) => {
+                let constant = self.add_integer(*i);
+                self.emit(Instruction::Constant(constant));
+            }
+            Expression::StringLiteral(s) => {
+                let constant = self.add_string(s);
+                self.emit(Instruction::Constant(constant));
+            }
+            Expression::Boolean(true) => {
+                self.emit(Instruction::True);
+            }
+            Expression::Boolean(false) => {
+                self.emit(Instruction::False);
+            }
+            Expression::PrefixExpression { operator, right } => {
+                self.compile_expression(right)?;
+                match operator.as_str() {
+                    "!" => self.emit(Instruction::Bang),
+                    "-" => self.emit(Instruction::Minus),
+                    _ => {
+                        self.emit(Instruction::Pop);
+                        self.emit(Instruction::Null)
+                    }
+                };
+            }
+            Expression::InfixExpression {
+                left,
+                operator,
+                right,
+            } => {
+                let Some(operator) = Operator::from_symbol(operator) else {
+                    bail!("unknown operator: {}", operator);
+                };
+                self.compile_expression(left)?;
+                self.compile_expression(right)?;
+                self.emit(Instruction::Infix(operator));
+            }
+            Expression::IfExpression {
+                condition,
+                consequence,
+                alternative,
+            } => self.compile_if_expression(condition, consequence, alternative, false)?,
+            Expression::MatchExpression { subject, arms } => {
+                self.compile_match_expression(subject, arms, false)?
+            }
+            Expression::FunctionLiteral(function) => {
+                let function = self.compile_function(
+                    function.name,
+                    &function.parameters,
+                    &function.rest,
+                    &function.body,
+                )?;
+                let function = CompiledFunction {
+                    source: expression.to_string(),
+                    ..function
+                };
+                let constant = self.add_constant(Constant::Function(Rc::new(function)));
+                self.emit(Instruction::Closure(constant));
+            }
+            Expression::CallExpression {
+                function,
+                arguments,
+                position,
+            } => self.compile_call_expression(function, arguments, *position, false)?,
+            Expression::AssignExpression {
+                name,
+                operator,
+                value,
+            } => {
+                self.compile_expression(value)?;
+                let name = self.add_name(name.name);
+                if operator == "=" {
+                    self.emit(Instruction::Assign(name));
+                } else {
+                    // Compound assignment applies the operator without the trailing '='.
+                    let symbol = operator.trim_end_matches('=');
+                    let Some(operator) = Operator::from_symbol(symbol) else {
+                        bail!("unknown operator: {}", operator);
+                    };
+                    self.emit(Instruction::CompoundAssign { name, operator });
+                }
+            }
+            Expression::ArrayLiteral(elements) => {
+                for element in elements {
+                    self.compile_expression(element)?;
+                }
+                self.emit(Instruction::Array(elements.len() as u32));
+            }
+            Expression::HashLiteral(pairs) => {
+                for (key, value) in pairs {
+                    self.compile_expression(key)?;
+                    self.emit(Instruction::CheckHashKey);
+                    self.compile_expression(value)?;
+                }
+                self.emit(Instruction::Hash(pairs.len() as u32));
+            }
+            Expression::IndexExpression { left, index } => {
+                self.compile_expression(left)?;
+                self.compile_expression(index)?;
+                self.emit(Instruction::Index);
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_if_expression(
+        &mut self,
+        condition: &Expression,
+        consequence: &Statement,
+        alternative: &Option<Box<Statement>>,
+        tail: bool,
+    ) -> Result<()> {
+        self.compile_expression(condition)?;
+        let else_jump = self.emit(Instruction::JumpIfNotTrue(0));
+        self.compile_branch(consequence, tail)?;
+        let end_jump = self.emit(Instruction::Jump(0));
+        self.patch(else_jump);
+        match alternative {
+            Some(alternative) => self.compile_branch(alternative, tail)?,
+            None => {
+                self.emit(Instruction::Null);
+            }
+        }
+        self.patch(end_jump);
+        Ok(())
+    }
+
+    fn compile_match_expression(
+        &mut self,
+        subject: &Expression,
+        arms: &[MatchArm],
+        tail: bool,
+    ) -> Result<()> {
+        // The subject stays on the stack until an arm is chosen.
+        self.compile_expression(subject)?;
+        let mut end_jumps = Vec::new();
+        for arm in arms {
+            let pattern = self.add_pattern(&arm.pattern)?;
+            let next_arm = self.emit(Instruction::MatchArm { pattern, next: 0 });
+            let guard_jump = match &arm.guard {
+                Some(guard) => {
+                    self.compile_expression(guard)?;
+                    Some(self.emit(Instruction::JumpIfNotTrue(0)))
+                }
+                None => None,
+            };
+            self.emit(Instruction::Pop);
+            self.compile_branch(&arm.body, tail)?;
+            self.emit(Instruction::PopScope);
+            end_jumps.push(self.emit(Instruction::Jump(0)));
+            if let Some(guard_jump) = guard_jump {
+                self.patch(guard_jump);
+                self.emit(Instruction::PopScope);
+            }
+            self.patch(next_arm);
+        }
+        self.emit(Instruction::NoMatch);
+        for end_jump in end_jumps {
+            self.patch(end_jump);
+        }
+        Ok(())
+    }
+
+    fn compile_call_expression(
+        &mut self,
+        function: &Expression,
+        arguments: &[Argument],
+        position: Position,
+        tail: bool,
+    ) -> Result<()> {
+        let callee = callee_name(function);
+        self.compile_expression(function)?;
+        self.emit(Instruction::CheckCallable);
+        let mut kinds = Vec::new();
+        for argument in arguments {
+            match argument {
+                Argument::Positional(arg) => {
+                    self.compile_expression(arg)?;
+                    kinds.push(ArgumentKind::Positional);
+                }
+                Argument::Spread(arg) => {
+                    self.compile_expression(arg)?;
+                    self.emit(Instruction::Spread);
+                    kinds.push(ArgumentKind::Spread);
+                }
+                Argument::Named(name, arg) => {
+                    self.compile_expression(arg)?;
+                    kinds.push(ArgumentKind::Named(*name));
+                }
+            }
+        }
+        let call_site = self.add_constant(Constant::CallSite(CallSite {
+            callee,
+            position,
+            arguments: kinds,
+        }));
+        self.mark(position);
+        if tail {
+            self.emit(Instruction::TailCall(call_site));
+        } else {
+            self.emit(Instruction::Call(call_site));
+        }
+        Ok(())
+    }
+
+    fn compile_function(
+        &mut self,
+        name: Option<Symbol>,
+        parameters: &[Parameter],
+        rest: &Option<Identifier>,
+        body: &Statement,
+    ) -> Result<CompiledFunction> {
+        let instructions = std::mem::take(&mut self.instructions);
+        let lines = std::mem::take(&mut self.lines);
+        let in_function = std::mem::replace(&mut self.in_function, true);
+        let loops = std::mem::take(&mut self.loops);
+        let function = self.compile_function_body(name, parameters, rest, body);
+        self.instructions = instructions;
+        self.lines = lines;
+        self.in_function = in_function;
+        self.loops = loops;
+        function
+    }
+
+    fn compile_function_body(
+        &mut self,
+        name: Option<Symbol>,
+        parameters: &[Parameter],
+        rest: &Option<Identifier>,
+        body: &Statement,
+    ) -> Result<CompiledFunction> {
+        // Bind the parameters in order. Defaults are evaluated in the scope the function was defined in,
+        // which is the current scope until the body is entered.
+        let mut compiled_parameters = Vec::new();
+        for (index, parameter) in parameters.iter().enumerate() {
+            let argument = self.emit(Instruction::ArgumentOrJump {
+                parameter: index as u32,
+                target: 0,
+            });
+            if let Some(default) = &parameter.default {
+                self.compile_expression(default)?;
+            }
+            self.patch(argument);
+            self.emit(Instruction::BindParameter(index as u32));
+            compiled_parameters.push(CompiledParameter {
+                pattern: compile_pattern(&parameter.pattern)?,
+                has_default: parameter.default.is_some(),
+            });
+        }
+        self.emit(Instruction::EnterBody);
+        self.compile_tail_statement(body)?;
+        self.emit(Instruction::Return);
+        Ok(CompiledFunction {
+            name,
+            parameters: compiled_parameters,
+            rest: rest.as_ref().map(|rest| rest.name),
+            instructions: std::mem::take(&mut self.instructions),
+            lines: std::mem::take(&mut self.lines),
+            source: String::new(),
+        })
+    }
+}
+
+fn compile_pattern(pattern: &Pattern) -> Result<CompiledPattern> {
+    Ok(CompiledPattern {
+        pattern: compile_pattern_node(pattern)?,
+        source: pattern.to_string(),
+    })
+}
+
+fn compile_pattern_node(pattern: &Pattern) -> Result<PatternNode> {
+    match pattern {
+        Pattern::Wildcard => Ok(PatternNode::Wildcard),
+        Pattern::Literal(literal) => Ok(PatternNode::Literal(literal_value(literal)?)),
+        Pattern::Binding(identifier) => Ok(PatternNode::Binding(identifier.name)),
+        Pattern::Array { elements, rest } => Ok(PatternNode::Array {
+            elements: elements
+                .iter()
+                .map(compile_pattern_node)
+                .collect::<Result<Vec<PatternNode>>>()?,
+            rest: rest.as_ref().map(|rest| rest.name),
+        }),
+        Pattern::Hash(pairs) => Ok(PatternNode::Hash(
+            pairs
+                .iter()
+                .map(|(key, pattern)| Ok((literal_value(key)?, compile_pattern_node(pattern)?)))
+                .collect::<Result<Vec<(HashKey, PatternNode)>>>()?,
+        )),
+    }
+}
+
+/// Evaluates a literal in a pattern, which the parser limits to integers, strings and booleans
+fn literal_value(literal: &Expression) -> Result<HashKey> {
+    match literal {
+        Expression::IntegerLiteral(i) => Ok(HashKey::Integer(*i)),
+        Expression::StringLiteral(s) => Ok(HashKey::String(s.clone())),
+        Expression::Boolean(b) => Ok(HashKey::Boolean(*b)),
+        Expression::PrefixExpression { operator, right } if operator == "-" => {
+            match literal_value(right)? {
+                HashKey::Integer(i) => match i.checked_neg() {
+                    Some(i) => Ok(HashKey::Integer(i)),
+                    None => bail!("integer overflow: -{}", i),
+                },
+                _ => bail!("unsupported literal in pattern: {}", literal),
+            }
+        }
+        literal => bail!("unsupported literal in pattern: {}", literal),
+    }
+}
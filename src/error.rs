@@ -0,0 +1,106 @@
+use crate::token::Position;
+use std::fmt::Display;
+
+/// Errors that stop the evaluation of a program because it exceeded a limit set by the embedder.
+/// They are returned inside `anyhow::Error` and can be told apart with `downcast_ref`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum RuntimeError {
+    /// Too many nested calls. `call_stack` holds the callee of each active call, outermost first.
+    StackOverflow { call_stack: Vec<String> },
+    /// The program took more evaluation steps than its budget allows
+    OutOfFuel { steps: u64 },
+    /// The evaluation was cancelled through its token or ran past its timeout
+    Cancelled,
+    /// The program allocated more memory than its limit of `limit` bytes
+    OutOfMemory { limit: usize },
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuntimeError::StackOverflow { call_stack } => write!(
+                f,
+                "stack overflow at call depth {} in {}",
+                call_stack.len(),
+                call_stack.last().map(String::as_str).unwrap_or("<program>")
+            ),
+            RuntimeError::OutOfFuel { steps } => write!(f, "out of fuel after {} steps", steps),
+            RuntimeError::Cancelled => write!(f, "evaluation cancelled"),
+            RuntimeError::OutOfMemory { limit } => {
+                write!(f, "out of memory: more than {} bytes in use", limit)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// A call that was active when a runtime error occurred
+#[derive(Debug, PartialEq, Clone)]
+pub struct Frame {
+    /// The name the function was bound to with `let`, or the callee at the call site
+    pub function: String,
+    /// The name of the script the call is in
+    pub source: String,
+    /// Where the call expression starts
+    pub position: Position,
+    /// The arguments the function was called with, as Monkey values
+    pub arguments: Vec<String>,
+}
+
+impl Display for Frame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "at {} ({}:{})",
+            self.function, self.source, self.position
+        )
+    }
+}
+
+/// The Monkey call stack at the point a runtime error occurred. It is attached to errors
+/// raised inside functions as context, so the original error stays available with `downcast_ref`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct StackTrace {
+    /// The message of the original error
+    pub message: String,
+    /// The active calls, innermost first
+    pub frames: Vec<Frame>,
+}
+
+/// Adds the frame of a call that failed to the stack trace of the error, attaching one if it has none
+pub(crate) fn add_frame(mut err: anyhow::Error, frame: Frame) -> anyhow::Error {
+    if let Some(trace) = err.downcast_mut::<StackTrace>() {
+        trace.frames.push(frame);
+        return err;
+    }
+    let message = err.to_string();
+    err.context(StackTrace {
+        message,
+        frames: vec![frame],
+    })
+}
+
+/// How many frames at each end of a long stack trace are shown
+const SHOWN_FRAMES: usize = 10;
+
+impl Display for StackTrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        if self.frames.len() <= 2 * SHOWN_FRAMES {
+            for frame in &self.frames {
+                write!(f, "\n    {}", frame)?;
+            }
+            return Ok(());
+        }
+        for frame in &self.frames[..SHOWN_FRAMES] {
+            write!(f, "\n    {}", frame)?;
+        }
+        let hidden = self.frames.len() - 2 * SHOWN_FRAMES;
+        write!(f, "\n    ... {} more frames", hidden)?;
+        for frame in &self.frames[self.frames.len() - SHOWN_FRAMES..] {
+            write!(f, "\n    {}", frame)?;
+        }
+        Ok(())
+    }
+}
@@ -1,140 +1,1090 @@
 use crate::ast::*;
-use crate::object::{Environment, Object};
+use crate::builtin::Builtin;
+use crate::error::{self, Frame, RuntimeError};
+use crate::gc;
+use crate::object::{binding_size, Env, Environment, MemoryMeter, Object, ENVIRONMENT_SIZE};
+use crate::resolver;
+use crate::symbol::Symbol;
+use crate::token::Position;
 use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::ops::ControlFlow;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-pub fn eval_program(program: Program) -> Result<Object> {
-    // Evaluate the given program and return the result.
-    // Create a new environment for the program.
-    let mut env = Environment::new();
+/// The arguments of a call that were passed by parameter name
+pub(crate) type NamedArguments = Vec<(Symbol, Object)>;
 
-    // Evaluate each statement in the program.
-    let mut result = Object::Null;
-    for statement in program.statements {
-        result = eval_statement(statement, &mut env)?;
+/// The number of nested calls allowed by default
+pub const DEFAULT_MAX_DEPTH: usize = 1000;
 
-        // If the result is a ReturnValue, return the value.
-        if let Object::ReturnValue(val) = result {
-            return Ok(*val);
+/// The Rust stack the evaluator may use by default, which fits in the 2 MiB
+/// that spawned threads get by default.
+/// How many calls fit in it depends on the build and on the expressions involved.
+pub const DEFAULT_MAX_STACK_SIZE: usize = 1024 * 1024;
+
+/// The Rust stack a nested call takes at most unless its expressions are deeply nested.
+/// Unoptimized builds take several times as much as optimized ones.
+const STACK_SIZE_PER_CALL: usize = if cfg!(debug_assertions) {
+    64 * 1024
+} else {
+    16 * 1024
+};
+
+/// The stack a thread running the evaluator needs besides what evaluation may use,
+/// for the frames of its caller and the calls in progress when the limit is checked
+const STACK_MARGIN: usize = 2 * 1024 * 1024;
+
+/// How many steps pass between checks for cancellation and timeouts
+pub(crate) const CANCELLATION_CHECK_INTERVAL: u64 = 1024;
+
+/// The name scripts are known by in stack traces unless another is set
+pub(crate) const DEFAULT_SOURCE_NAME: &str = "<input>";
+
+/// Evaluates an expression whose value the enclosing expression uses. A `return`, `break` or
+/// `continue` inside it leaves the enclosing expression too, as on the virtual machine, so its
+/// signal is returned at once from the calling function, wrapped by `$wrap` if given.
+macro_rules! eval_operand {
+    ($evaluator:expr, $expression:expr, $env:expr) => {
+        eval_operand!($evaluator, $expression, $env, std::convert::identity)
+    };
+    ($evaluator:expr, $expression:expr, $env:expr, $wrap:expr) => {
+        match $evaluator.eval_expression($expression, $env)? {
+            signal @ (Object::ReturnValue(_) | Object::Break | Object::Continue) => {
+                return Ok($wrap(signal))
+            }
+            value => value,
         }
+    };
+}
+
+/// An active call, kept for the stack trace of errors raised inside it. The frame owns the
+/// arguments, which parameters bind copies of, and they are only rendered for an error.
+struct CallFrame {
+    function: Symbol,
+    position: Position,
+    arguments: Vec<Object>,
+    named: NamedArguments,
+}
+
+/// Stops an evaluation running on another thread, which then fails with `RuntimeError::Cancelled`
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Evaluates programs under the configured limits
+pub struct Evaluator {
+    max_depth: usize,
+    max_stack_size: usize,
+    max_steps: Option<u64>,
+    max_memory: Option<usize>,
+    timeout: Option<Duration>,
+    cancellation: Option<CancellationToken>,
+    /// When the running program times out
+    deadline: Option<Instant>,
+    /// The number of statements and expressions evaluated by the last call to eval_program
+    steps: u64,
+    /// The memory in use by the environments the evaluator created, as estimated by `binding_size`
+    meter: MemoryMeter,
+    /// The memory the names of the running program added to the symbol table
+    names_size: usize,
+    /// The most memory in use during the last call to eval_program, as checked by `reserve`
+    peak_memory: usize,
+    /// The address of the stack when evaluation started, for measuring how much of it is used
+    stack_base: usize,
+    /// The name of the script in stack traces
+    source_name: String,
+    /// The active calls, outermost first
+    call_stack: Vec<CallFrame>,
+}
+
+impl Default for Evaluator {
+    fn default() -> Self {
+        Self::new()
     }
-    Ok(result)
 }
 
-fn eval_statement(statement: Statement, env: &mut Environment) -> Result<Object> {
-    match statement {
-        // If the statement is an expression, evaluate it and return the result.
-        Statement::ExpressionStatement(expr) => {
-            let val = eval_expression(expr, env)?;
-            Ok(val)
-        }
-
-        // If the statement is a block statement, evaluate each statement in the block.
-        Statement::BlockStatement { statements } => {
-            let mut result = Object::Null;
-            for statement in statements {
-                result = eval_statement(statement, env)?;
-                if let Object::ReturnValue(_) = result {
-                    return Ok(result);
+/// Evaluates the program with the default limits
+pub fn eval_program(program: &Program) -> Result<Object> {
+    Evaluator::new().eval_program(program)
+}
+
+impl Evaluator {
+    pub fn new() -> Self {
+        Self {
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_stack_size: DEFAULT_MAX_STACK_SIZE,
+            max_steps: None,
+            max_memory: None,
+            timeout: None,
+            cancellation: None,
+            deadline: None,
+            steps: 0,
+            meter: MemoryMeter::default(),
+            names_size: 0,
+            peak_memory: 0,
+            stack_base: 0,
+            source_name: DEFAULT_SOURCE_NAME.to_string(),
+            call_stack: Vec::new(),
+        }
+    }
+
+    /// Sets the number of nested calls after which evaluation fails with `RuntimeError::StackOverflow`.
+    /// Deep recursion also needs the stack for it, see `stack_size_for_depth`.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Sets how many bytes of the Rust stack evaluation may use before failing with
+    /// `RuntimeError::StackOverflow`. The calling thread must have `stack_size` bytes of stack left.
+    pub fn with_max_stack_size(mut self, max_stack_size: usize) -> Self {
+        self.max_stack_size = max_stack_size;
+        self
+    }
+
+    /// Sets how many statements and expressions evaluation may go through before
+    /// failing with `RuntimeError::OutOfFuel`. There is no limit by default.
+    pub fn with_max_steps(mut self, max_steps: u64) -> Self {
+        self.max_steps = Some(max_steps);
+        self
+    }
+
+    /// Sets how many bytes the arrays, strings, hashes and environments of a program may take
+    /// at once before evaluation fails with `RuntimeError::OutOfMemory`. The count is an estimate
+    /// of the environments that are alive and of the value being created, so memory that is
+    /// released, e.g. by leaving a scope, can be used again.
+    pub fn with_max_memory(mut self, max_memory: usize) -> Self {
+        self.max_memory = Some(max_memory);
+        self
+    }
+
+    /// Sets how long each program may run before failing with `RuntimeError::Cancelled`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Makes evaluation fail with `RuntimeError::Cancelled` once the token is cancelled
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Sets the name of the script in stack traces, e.g. its path
+    pub fn with_source_name(mut self, source_name: &str) -> Self {
+        self.source_name = source_name.to_string();
+        self
+    }
+
+    /// Returns how much stack the thread running the evaluator needs, which `run_with_stack_size` can provide
+    pub fn stack_size(&self) -> usize {
+        self.max_stack_size.saturating_add(STACK_MARGIN)
+    }
+
+    /// Returns the number of steps taken by the last evaluated program
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Returns the most memory the last evaluated program had in use, as estimated for the memory limit
+    pub fn peak_memory(&self) -> usize {
+        self.peak_memory
+    }
+
+    pub fn eval_program(&mut self, program: &Program) -> Result<Object> {
+        // Evaluate the given program in a new environment and return the result.
+        let env = gc::alloc(Environment::new());
+        self.eval_program_with_env(program, &env)
+    }
+
+    /// Evaluates the program in an existing environment, e.g. one kept between the lines of a REPL.
+    /// When evaluation fails, including by hitting a limit, the bindings made before the failure stay in `env`.
+    pub fn eval_program_with_env(&mut self, program: &Program, env: &Env) -> Result<Object> {
+        // Reject errors that can be found without running the program, and find the slot of every variable.
+        let mut globals = env.borrow().names().clone();
+        resolver::resolve_program(program, &mut globals)?;
+        env.borrow_mut().set_names(globals);
+        self.stack_base = stack_address();
+        self.call_stack.clear();
+        self.steps = 0;
+        env.borrow_mut().set_meter(&self.meter);
+        self.names_size = program.names_size;
+        self.peak_memory = self.in_use();
+        self.reserve(0)?;
+        // A timeout too long to represent never expires.
+        self.deadline = self
+            .timeout
+            .and_then(|timeout| Instant::now().checked_add(timeout));
+        self.check_cancellation()?;
+
+        // Evaluate each statement in the program.
+        let mut result = Object::Null;
+        for statement in &program.statements {
+            result = self.eval_statement(statement, env)?;
+
+            // If the result is a ReturnValue, return the value.
+            match result {
+                Object::ReturnValue(val) => {
+                    return match *val {
+                        Object::FunctionApplication {
+                            function,
+                            arguments,
+                            named,
+                            callee,
+                            position,
+                        } => self.call_function(callee, position, *function, arguments, named),
+                        val => Ok(val),
+                    };
+                }
+                Object::Break => bail!("break outside of loop"),
+                Object::Continue => bail!("continue outside of loop"),
+                _ => {}
+            }
+        }
+        Ok(result)
+    }
+
+    fn eval_statement(&mut self, statement: &Statement, env: &Env) -> Result<Object> {
+        // Blocks nest without expressions in between, so their depth is checked here too.
+        self.check_stack()?;
+        self.use_fuel()?;
+        match statement {
+            // If the statement is an expression, evaluate it and return the result.
+            Statement::ExpressionStatement(expr) => {
+                let val = self.eval_expression(expr, env)?;
+                Ok(val)
+            }
+
+            // If the statement is a block statement, evaluate each statement in the block.
+            Statement::BlockStatement { statements } => {
+                let mut result = Object::Null;
+                for statement in statements {
+                    result = self.eval_statement(statement, env)?;
+                    if let Object::ReturnValue(_) | Object::Break | Object::Continue = result {
+                        return Ok(result);
+                    }
+                }
+                Ok(result)
+            }
+
+            // If the statement is a return statement, evaluate the expression and return the result.
+            // The returned expression is in tail position.
+            Statement::ReturnStatement(expr) => {
+                let val = self.eval_tail_expression(expr, env)?;
+                Ok(Object::ReturnValue(Box::new(val)))
+            }
+
+            // If the statement is a let statement, evaluate the expression and store the result in the environment.
+            Statement::LetStatement {
+                kind,
+                pattern,
+                value,
+                position,
+            } => {
+                let val = eval_operand!(self, value, env);
+                let mut bindings = Vec::new();
+                if !self.match_pattern(pattern, &val, env, &mut bindings)? {
+                    bail!("pattern {} does not match {:?}", pattern, val);
+                }
+                for (identifier, value) in bindings {
+                    self.reserve(binding_size(&value))?;
+                    let index = slot(identifier)?.index;
+                    env.borrow_mut()
+                        .declare_at(index, identifier.name, value, *kind, *position)?;
+                }
+                Ok(Object::Null)
+            }
+
+            // If the statement is a while statement, evaluate the body in a fresh scope until the condition is false.
+            Statement::WhileStatement { condition, body } => {
+                loop {
+                    // The condition is inside the loop, so `break` and `continue` in it apply to this loop.
+                    let condition = match self.eval_expression(condition, env)? {
+                        Object::Break => break,
+                        Object::Continue => continue,
+                        signal @ Object::ReturnValue(_) => return Ok(signal),
+                        condition => condition.cast_to_boolean()?,
+                    };
+                    if condition != Object::Boolean(true) {
+                        break;
+                    }
+                    let loop_env = self.new_scope(env, Vec::new())?;
+                    match self.eval_statement(body, &loop_env)? {
+                        Object::Break => break,
+                        Object::ReturnValue(val) => return Ok(Object::ReturnValue(val)),
+                        _ => {}
+                    }
+                }
+                Ok(Object::Null)
+            }
+
+            // If the statement is a for statement, evaluate the body in a fresh scope for each item.
+            Statement::ForStatement {
+                variable,
+                iterable,
+                body,
+            } => {
+                for item in eval_operand!(self, iterable, env).iterate()? {
+                    // Each iteration gets its own binding, so closures capture the current item.
+                    let loop_env = self.new_scope(env, vec![(variable, item)])?;
+                    match self.eval_statement(body, &loop_env)? {
+                        Object::Break => break,
+                        Object::ReturnValue(val) => return Ok(Object::ReturnValue(val)),
+                        _ => {}
+                    }
                 }
+                Ok(Object::Null)
             }
-            Ok(result)
+
+            // If the statement is a break or continue statement, signal the enclosing loop.
+            Statement::BreakStatement => Ok(Object::Break),
+            Statement::ContinueStatement => Ok(Object::Continue),
+            _ => Ok(Object::Null),
         }
+    }
 
-        // If the statement is a return statement, evaluate the expression and return the result.
-        Statement::ReturnStatement(expr) => {
-            let val = eval_expression(expr, env)?;
-            Ok(Object::ReturnValue(Box::new(val)))
+    fn eval_tail_statement(&mut self, statement: &Statement, env: &Env) -> Result<Object> {
+        // Evaluate a statement whose value is returned from the function, deferring a call in its last expression.
+        // Statements that are not passed on to eval_statement use fuel here.
+        match statement {
+            Statement::BlockStatement { statements } => {
+                self.use_fuel()?;
+                let Some((last, statements)) = statements.split_last() else {
+                    return Ok(Object::Null);
+                };
+                for statement in statements {
+                    let result = self.eval_statement(statement, env)?;
+                    if let Object::ReturnValue(_) | Object::Break | Object::Continue = result {
+                        return Ok(result);
+                    }
+                }
+                self.eval_tail_statement(last, env)
+            }
+            Statement::ExpressionStatement(expr) => {
+                self.use_fuel()?;
+                self.eval_tail_expression(expr, env)
+            }
+            statement => self.eval_statement(statement, env),
         }
+    }
 
-        // If the statement is a let statement, evaluate the expression and store the result in the environment.
-        Statement::LetStatement { name, value } => {
-            let val = eval_expression(value, env)?;
-            env.set(name, val);
-            Ok(Object::Null)
+    fn eval_tail_expression(&mut self, expression: &Expression, env: &Env) -> Result<Object> {
+        // Evaluate an expression in tail position. A call is not applied here but returned
+        // as a FunctionApplication for the trampoline in apply_function.
+        // Expressions that are not passed on to eval_expression use fuel here.
+        match expression {
+            Expression::CallExpression {
+                function,
+                arguments,
+                position,
+            } => {
+                self.use_fuel()?;
+                let callee = callee_name(function);
+                let function = eval_operand!(self, function, env);
+                check_callable(&function)?;
+                let (arguments, named) = match self.eval_arguments(arguments, env)? {
+                    ControlFlow::Continue(arguments) => arguments,
+                    ControlFlow::Break(signal) => return Ok(signal),
+                };
+                Ok(Object::FunctionApplication {
+                    function: Box::new(function),
+                    arguments,
+                    named,
+                    callee,
+                    position: *position,
+                })
+            }
+            Expression::IfExpression {
+                condition,
+                consequence,
+                alternative,
+            } => {
+                self.use_fuel()?;
+                self.eval_if_expression(condition, consequence, alternative.as_deref(), env, true)
+            }
+            Expression::MatchExpression { subject, arms } => {
+                self.use_fuel()?;
+                self.eval_match_expression(subject, arms, env, true)
+            }
+            expression => self.eval_expression(expression, env),
         }
-        _ => Ok(Object::Null),
     }
-}
 
-fn eval_expression(expression: Expression, env: &mut Environment) -> Result<Object> {
-    match expression {
-        // If the expression is an integer literal, return the integer value.
-        Expression::IntegerLiteral(i) => Ok(Object::Integer(i)),
+    fn eval_expression(&mut self, expression: &Expression, env: &Env) -> Result<Object> {
+        // Every nested expression and call goes through here, so this is where deep nesting is caught.
+        self.check_stack()?;
+        self.use_fuel()?;
+        match expression {
+            // If the expression is an integer literal, return the integer value.
+            Expression::IntegerLiteral(i) => Ok(Object::Integer(*i)),
 
-        // If the expression is a boolean literal, return the boolean value.
-        Expression::Boolean(b) => Ok(Object::Boolean(b)),
+            // If the expression is a boolean literal, return the boolean value.
+            Expression::Boolean(b) => Ok(Object::Boolean(*b)),
 
-        // If the expression is an identifier, look up the value in the environment and return it.
-        Expression::Identifier(name) => match env.get(&name) {
-            Some(val) => Ok(val.clone()),
-            None => bail!("identifier not found: {}", name),
-        },
+            // If the expression is a string literal, return the string value.
+            Expression::StringLiteral(s) => Ok(Object::String(s.clone())),
+
+            // If the expression is an array literal, evaluate each element.
+            Expression::ArrayLiteral(elements) => self.eval_array_literal(elements, env),
+
+            // If the expression is a hash literal, evaluate each key and value.
+            Expression::HashLiteral(pairs) => self.eval_hash_literal(pairs, env),
+
+            // If the expression is an index expression, evaluate the collection and the index and look up the element.
+            Expression::IndexExpression { left, index } => {
+                let left = eval_operand!(self, left, env);
+                let index = eval_operand!(self, index, env);
+                eval_index_expression(left, index)
+            }
+
+            // If the expression is an identifier, look up the value in the environment and return it.
+            Expression::Identifier(identifier) => match identifier.slot.get() {
+                Some(slot) => match env.borrow().get_at(slot) {
+                    Some(val) => Ok(val),
+                    None => bail!("identifier not found: {}", identifier),
+                },
+                // The resolver leaves the names of builtins unresolved.
+                None => match Builtin::lookup(identifier.name) {
+                    Some(builtin) => Ok(Object::Builtin(builtin)),
+                    None => bail!("unresolved variable: {}", identifier),
+                },
+            },
+
+            // If the expression is a prefix expression, evaluate the right expression and apply the operator.
+            Expression::PrefixExpression { operator, right } => {
+                let right = eval_operand!(self, right, env);
+                match operator.as_str() {
+                    "!" => eval_bang_prefix_expression(right),
+                    "-" => eval_minus_prefix_operator_expression(right),
+                    _ => Ok(Object::Null),
+                }
+            }
+
+            // If the expression is an infix expression, evaluate the left and right expressions and apply the operator.
+            Expression::InfixExpression {
+                left,
+                operator,
+                right,
+            } => {
+                let left = eval_operand!(self, left, env);
+                let right = eval_operand!(self, right, env);
+                self.reserve(result_size(operator, &left, &right))?;
+                eval_infix_expression(operator, left, right)
+            }
+
+            // If the expression is a block expression, evaluate each statement in the block.
+            Expression::IfExpression {
+                condition,
+                consequence,
+                alternative,
+            } => {
+                self.eval_if_expression(condition, consequence, alternative.as_deref(), env, false)
+            }
+
+            // If the expression is a match expression, evaluate the body of the first arm whose pattern and guard match.
+            Expression::MatchExpression { subject, arms } => {
+                self.eval_match_expression(subject, arms, env, false)
+            }
+
+            // If the expression is a function literal, return the function object, which shares the literal.
+            Expression::FunctionLiteral(function) => Ok(Object::FunctionObject {
+                function: Rc::clone(function),
+                env: Rc::clone(env),
+            }),
+
+            // If the expression is a function application, evaluate the function and the arguments and apply the function.
+            Expression::CallExpression {
+                function,
+                arguments,
+                position,
+            } => self.eval_call_expression(function, arguments, *position, env),
+
+            // If the expression is an assignment, evaluate the value and update the nearest existing binding.
+            Expression::AssignExpression {
+                name,
+                operator,
+                value,
+            } => self.eval_assign_expression(name, operator, value, env),
+            _ => Ok(Object::Null),
+        }
+    }
+
+    // The larger cases of eval_expression live in their own functions so that its stack frame,
+    // which every level of nesting and every call pays for, stays small.
+
+    fn eval_array_literal(&mut self, elements: &[Expression], env: &Env) -> Result<Object> {
+        let mut values = Vec::with_capacity(elements.len());
+        for element in elements {
+            values.push(eval_operand!(self, element, env));
+        }
+        let array = Object::Array(values);
+        self.reserve(array.size())?;
+        Ok(array)
+    }
 
-        // If the expression is a prefix expression, evaluate the right expression and apply the operator.
-        Expression::PrefixExpression { operator, right } => {
-            let right = eval_expression(*right, env)?;
-            match operator.as_str() {
-                "!" => eval_bang_prefix_expression(right),
-                "-" => eval_minus_prefix_operator_expression(right),
-                _ => Ok(Object::Null),
-            }
-        }
-
-        // If the expression is an infix expression, evaluate the left and right expressions and apply the operator.
-        Expression::InfixExpression {
-            left,
-            operator,
-            right,
-        } => {
-            let left = eval_expression(*left, env)?;
-            let right = eval_expression(*right, env)?;
-            eval_infix_expression(operator, left, right)
-        }
-
-        // If the expression is a block expression, evaluate each statement in the block.
-        Expression::IfExpression {
-            condition,
-            consequence,
-            alternative,
-        } => {
-            let condition = eval_expression(*condition, env)?.cast_to_boolean()?;
-            debug_assert!(matches!(condition, Object::Boolean(_)));
-            debug_assert!(matches!(*consequence, Statement::BlockStatement { .. }));
-            if condition == Object::Boolean(true) {
-                eval_statement(*consequence, env)
-            } else if let Some(alt) = alternative {
-                debug_assert!(matches!(*alt, Statement::BlockStatement { .. }));
-                eval_statement(*alt, env)
+    fn eval_hash_literal(
+        &mut self,
+        pairs: &[(Expression, Expression)],
+        env: &Env,
+    ) -> Result<Object> {
+        let mut hash = BTreeMap::new();
+        for (key, value) in pairs {
+            let key = eval_operand!(self, key, env).hash_key()?;
+            let value = eval_operand!(self, value, env);
+            hash.insert(key, value);
+        }
+        let hash = Object::Hash(hash);
+        self.reserve(hash.size())?;
+        Ok(hash)
+    }
+
+    fn eval_call_expression(
+        &mut self,
+        function: &Expression,
+        arguments: &[Argument],
+        position: Position,
+        env: &Env,
+    ) -> Result<Object> {
+        let callee = callee_name(function);
+        let function = eval_operand!(self, function, env);
+        check_callable(&function)?;
+        let (arguments, named) = match self.eval_arguments(arguments, env)? {
+            ControlFlow::Continue(arguments) => arguments,
+            ControlFlow::Break(signal) => return Ok(signal),
+        };
+        self.call_function(callee, position, function, arguments, named)
+    }
+
+    fn eval_assign_expression(
+        &mut self,
+        name: &Identifier,
+        operator: &str,
+        value: &Expression,
+        env: &Env,
+    ) -> Result<Object> {
+        let value = eval_operand!(self, value, env);
+        let slot = slot(name)?;
+        let value = match operator {
+            "=" => value,
+            _ => {
+                let current = match env.borrow().get_at(slot) {
+                    Some(val) => val,
+                    None => bail!("cannot assign to undefined variable: {}", name),
+                };
+                // Compound assignment applies the operator without the trailing '='.
+                let operator = operator.trim_end_matches('=');
+                self.reserve(result_size(operator, &current, &value))?;
+                eval_infix_expression(operator, current, value)?
+            }
+        };
+        env.borrow_mut().assign_at(slot, name.name, value.clone())?;
+        Ok(value)
+    }
+
+    fn eval_if_expression(
+        &mut self,
+        condition: &Expression,
+        consequence: &Statement,
+        alternative: Option<&Statement>,
+        env: &Env,
+        tail: bool,
+    ) -> Result<Object> {
+        // Evaluate the branch selected by the condition, in tail position if the if expression is.
+        let condition = eval_operand!(self, condition, env).cast_to_boolean()?;
+        debug_assert!(matches!(condition, Object::Boolean(_)));
+        debug_assert!(matches!(consequence, Statement::BlockStatement { .. }));
+        let branch = if condition == Object::Boolean(true) {
+            consequence
+        } else if let Some(alt) = alternative {
+            debug_assert!(matches!(
+                alt,
+                Statement::BlockStatement { .. }
+                    | Statement::ExpressionStatement(Expression::IfExpression { .. })
+            ));
+            alt
+        } else {
+            return Ok(Object::Null);
+        };
+        if tail {
+            self.eval_tail_statement(branch, env)
+        } else {
+            self.eval_statement(branch, env)
+        }
+    }
+
+    fn eval_match_expression(
+        &mut self,
+        subject: &Expression,
+        arms: &[MatchArm],
+        env: &Env,
+        tail: bool,
+    ) -> Result<Object> {
+        // Evaluate the body of the first arm whose pattern and guard match, in tail position if the match expression is.
+        let subject = eval_operand!(self, subject, env);
+        for arm in arms {
+            let mut bindings = Vec::new();
+            if !self.match_pattern(&arm.pattern, &subject, env, &mut bindings)? {
+                continue;
+            }
+            // Each arm binds its pattern variables in a fresh scope.
+            let arm_env = self.new_scope(env, bindings)?;
+            if let Some(guard) = &arm.guard {
+                let guard = eval_operand!(self, guard, &arm_env).cast_to_boolean()?;
+                if guard != Object::Boolean(true) {
+                    continue;
+                }
+            }
+            return if tail {
+                self.eval_tail_statement(&arm.body, &arm_env)
             } else {
-                Ok(Object::Null)
+                self.eval_statement(&arm.body, &arm_env)
+            };
+        }
+        bail!("no match arm matched {:?}", subject)
+    }
+
+    fn eval_arguments(
+        &mut self,
+        arguments: &[Argument],
+        env: &Env,
+    ) -> Result<ControlFlow<Object, (Vec<Object>, NamedArguments)>> {
+        // Evaluate the arguments of a call into the positional values, with spread arguments expanded, and the named values.
+        // An argument that returns or leaves a loop breaks off the call.
+        let mut positional = Vec::new();
+        let mut named = Vec::new();
+        for argument in arguments {
+            match argument {
+                Argument::Positional(arg) => {
+                    positional.push(eval_operand!(self, arg, env, ControlFlow::Break))
+                }
+                Argument::Spread(arg) => {
+                    // Ranges are spread lazily into any number of elements, so each element is
+                    // a step, which also checks for cancellation, and is counted before it is stored.
+                    let mut spread = 0;
+                    for item in eval_operand!(self, arg, env, ControlFlow::Break).iterate()? {
+                        self.use_fuel()?;
+                        spread += item.size();
+                        self.reserve(spread)?;
+                        positional.push(item);
+                    }
+                }
+                Argument::Named(name, arg) => {
+                    named.push((*name, eval_operand!(self, arg, env, ControlFlow::Break)))
+                }
+            }
+        }
+        Ok(ControlFlow::Continue((positional, named)))
+    }
+
+    fn match_pattern<'a>(
+        &mut self,
+        pattern: &'a Pattern,
+        value: &Object,
+        env: &Env,
+        bindings: &mut Vec<(&'a Identifier, Object)>,
+    ) -> Result<bool> {
+        // Check whether the value matches the pattern, collecting the values of the pattern variables into `bindings`.
+        match (pattern, value) {
+            (Pattern::Wildcard, _) => Ok(true),
+            (Pattern::Binding(identifier), value) => {
+                bindings.push((identifier, value.clone()));
+                Ok(true)
+            }
+            (Pattern::Literal(literal), value) => Ok(self.eval_expression(literal, env)? == *value),
+
+            // An array pattern matches arrays of the same length, or at least as long if it has a rest binding.
+            (Pattern::Array { elements, rest }, Object::Array(values)) => {
+                let length_matches = match rest {
+                    Some(_) => values.len() >= elements.len(),
+                    None => values.len() == elements.len(),
+                };
+                if !length_matches {
+                    return Ok(false);
+                }
+                for (element, value) in elements.iter().zip(values) {
+                    if !self.match_pattern(element, value, env, bindings)? {
+                        return Ok(false);
+                    }
+                }
+                if let Some(rest) = rest.as_ref().filter(|rest| rest.name.as_str() != "_") {
+                    let rest_values = Object::Array(values[elements.len()..].to_vec());
+                    bindings.push((rest, rest_values));
+                }
+                Ok(true)
+            }
+
+            // A hash pattern matches hashes that have all of its keys, ignoring any other keys.
+            (Pattern::Hash(pairs), Object::Hash(hash)) => {
+                for (key, pattern) in pairs {
+                    let key = self.eval_expression(key, env)?.hash_key()?;
+                    match hash.get(&key) {
+                        Some(value) if self.match_pattern(pattern, value, env, bindings)? => {}
+                        _ => return Ok(false),
+                    }
+                }
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn bind_arguments<'a>(
+        &mut self,
+        parameters: &'a [Parameter],
+        rest: &'a Option<Identifier>,
+        arguments: &[Object],
+        named: &[(Symbol, Object)],
+        env: &Env,
+    ) -> Result<ControlFlow<Object, Vec<(&'a Identifier, Object)>>> {
+        // Match the arguments against the parameters and return the variables the function body starts with.
+        // A default value that returns or leaves a loop breaks off the call with its signal.
+        let required = parameters.iter().filter(|p| p.default.is_none()).count();
+        let expected = match rest {
+            Some(_) => format!("at least {}", required),
+            None if required == parameters.len() => required.to_string(),
+            None => format!("{}..={}", required, parameters.len()),
+        };
+        let got = arguments.len() + named.len();
+        if arguments.len() > parameters.len() && rest.is_none() {
+            bail!(
+                "wrong number of arguments: expected={}, got={}",
+                expected,
+                got
+            );
+        }
+
+        let (positional, rest_values) = arguments.split_at(arguments.len().min(parameters.len()));
+        let mut values = positional
+            .iter()
+            .map(Some)
+            .collect::<Vec<Option<&Object>>>();
+        values.resize(parameters.len(), None);
+
+        // Named arguments fill the parameters that are plain identifiers.
+        for (name, value) in named {
+            let index = parameters
+                .iter()
+                .position(|p| matches!(&p.pattern, Pattern::Binding(param) if param.name == *name));
+            match index {
+                Some(index) if values[index].is_none() => values[index] = Some(value),
+                Some(_) => bail!("parameter {} is given more than once", name),
+                None => bail!("unknown parameter: {}", name),
             }
         }
 
-        // If the expression is a function literal, return the function object.
-        Expression::FunctionLiteral { parameters, body } => Ok(Object::FunctionObject {
-            parameters,
-            body: *body,
-            env: env.clone(),
-        }),
+        let mut bindings = Vec::new();
+        for (param, value) in parameters.iter().zip(values) {
+            // Defaults are evaluated in the environment the function was defined in.
+            let default_value;
+            let value = match (value, &param.default) {
+                (Some(value), _) => value,
+                (None, Some(default)) => {
+                    default_value = eval_operand!(self, default, env, ControlFlow::Break);
+                    &default_value
+                }
+                (None, None) => {
+                    bail!(
+                        "wrong number of arguments: expected={}, got={}",
+                        expected,
+                        got
+                    )
+                }
+            };
+            if !self.match_pattern(&param.pattern, value, env, &mut bindings)? {
+                bail!(
+                    "argument {:?} does not match parameter {}",
+                    value,
+                    param.pattern
+                );
+            }
+        }
+        if let Some(rest) = rest {
+            bindings.push((rest, Object::Array(rest_values.to_vec())));
+        }
+        Ok(ControlFlow::Continue(bindings))
+    }
 
-        // If the expression is a function application, evaluate the function and the arguments and apply the function.
-        Expression::CallExpression {
-            function,
+    fn call_function(
+        &mut self,
+        callee: Symbol,
+        position: Position,
+        function: Object,
+        arguments: Vec<Object>,
+        named: NamedArguments,
+    ) -> Result<Object> {
+        // Apply the function in a new frame of the call stack, failing once the stack is too deep.
+        // Errors leaving the call get the frame added to their stack trace.
+        if self.call_stack.len() >= self.max_depth {
+            return Err(self.stack_overflow());
+        }
+        self.call_stack.push(CallFrame {
+            function: function_name(callee, &function),
+            position,
             arguments,
-        } => {
-            let function = eval_expression(*function, env)?;
-            assert!(matches!(function, Object::FunctionObject { .. }));
-            let arguments = arguments
-                .into_iter()
-                .map(|arg| eval_expression(arg, env))
-                .collect::<Result<Vec<Object>>>()?;
-            apply_function(function, arguments)
+            named,
+        });
+        let result = self.apply_function(function);
+        let frame = self.call_stack.pop().expect("the frame of this call");
+        result.map_err(|err| self.add_frame(err, frame))
+    }
+
+    /// Adds the frame of a call that failed to the stack trace of the error
+    fn add_frame(&self, err: anyhow::Error, frame: CallFrame) -> anyhow::Error {
+        let frame = Frame {
+            function: frame.function.to_string(),
+            source: self.source_name.clone(),
+            position: frame.position,
+            arguments: render_arguments(&frame.arguments, &frame.named),
+        };
+        error::add_frame(err, frame)
+    }
+
+    /// Creates a scope enclosed by `outer` holding the given immutable bindings
+    fn new_scope(&mut self, outer: &Env, bindings: Vec<(&Identifier, Object)>) -> Result<Env> {
+        self.reserve(ENVIRONMENT_SIZE)?;
+        let mut env = Environment::new_enclosed(Rc::clone(outer));
+        for (identifier, value) in bindings {
+            self.reserve(binding_size(&value))?;
+            env.set_at(slot(identifier)?.index, value);
+        }
+        Ok(gc::alloc(env))
+    }
+
+    /// Returns the memory in use by the environments and the names of the running program
+    fn in_use(&self) -> usize {
+        self.meter.get().saturating_add(self.names_size)
+    }
+
+    /// Fails unless `bytes` more than the memory in use fit in the limit. Environments kept
+    /// alive only by cycles count until they are collected, which is done before failing.
+    fn reserve(&mut self, bytes: usize) -> Result<()> {
+        let mut in_use = self.in_use().saturating_add(bytes);
+        if self
+            .max_memory
+            .is_some_and(|max_memory| in_use > max_memory)
+        {
+            gc::collect();
+            in_use = self.in_use().saturating_add(bytes);
+        }
+        self.peak_memory = self.peak_memory.max(in_use);
+        match self.max_memory {
+            Some(max_memory) if in_use > max_memory => {
+                Err(RuntimeError::OutOfMemory { limit: max_memory }.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts one evaluation step against the budget, checking for cancellation every so often
+    fn use_fuel(&mut self) -> Result<()> {
+        self.steps += 1;
+        match self.max_steps {
+            Some(max_steps) if self.steps > max_steps => {
+                Err(RuntimeError::OutOfFuel { steps: max_steps }.into())
+            }
+            _ if self.steps.is_multiple_of(CANCELLATION_CHECK_INTERVAL) => {
+                self.check_cancellation()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_cancellation(&self) -> Result<()> {
+        let cancelled = self.cancellation.as_ref().is_some_and(|t| t.is_cancelled());
+        let timed_out = self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline);
+        if cancelled || timed_out {
+            return Err(RuntimeError::Cancelled.into());
+        }
+        Ok(())
+    }
+
+    /// Fails once evaluation has used more of the Rust stack than allowed
+    fn check_stack(&self) -> Result<()> {
+        if stack_address().abs_diff(self.stack_base) > self.max_stack_size {
+            return Err(self.stack_overflow());
+        }
+        Ok(())
+    }
+
+    fn stack_overflow(&self) -> anyhow::Error {
+        let call_stack = self
+            .call_stack
+            .iter()
+            .map(|frame| frame.function.to_string())
+            .collect();
+        RuntimeError::StackOverflow { call_stack }.into()
+    }
+
+    fn apply_function(&mut self, mut function: Object) -> Result<Object> {
+        // Apply the given function to the arguments in the top frame of the call stack and return the result.
+        // A call in tail position of the body comes back as a function application, which is
+        // applied by this loop instead of a nested call so that tail recursion runs in constant stack.
+        // The tail call takes over the frame of the call it replaces.
+        loop {
+            match function {
+                // If the function is a function object, create a new environment for the function and evaluate the body.
+                Object::FunctionObject {
+                    function: literal,
+                    env,
+                } => {
+                    // The arguments are taken out of the frame while defaults are evaluated, which may call functions.
+                    let frame = self.call_stack.last_mut().expect("the frame of this call");
+                    let arguments = std::mem::take(&mut frame.arguments);
+                    let named = std::mem::take(&mut frame.named);
+                    let bound = self.bind_arguments(
+                        &literal.parameters,
+                        &literal.rest,
+                        &arguments,
+                        &named,
+                        &env,
+                    );
+                    let frame = self.call_stack.last_mut().expect("the frame of this call");
+                    frame.arguments = arguments;
+                    frame.named = named;
+                    let result = match bound? {
+                        ControlFlow::Continue(bindings) => {
+                            let extended_env = self.new_scope(&env, bindings)?;
+                            self.eval_tail_statement(&literal.body, &extended_env)?
+                        }
+                        ControlFlow::Break(signal) => signal,
+                    };
+                    let result = match result {
+                        Object::ReturnValue(val) => *val,
+                        Object::Break => bail!("break outside of loop"),
+                        Object::Continue => bail!("continue outside of loop"),
+                        val => val,
+                    };
+                    match result {
+                        Object::FunctionApplication {
+                            function: next,
+                            arguments,
+                            named,
+                            callee,
+                            position,
+                        } => {
+                            let frame = self.call_stack.last_mut().expect("the frame of this call");
+                            *frame = CallFrame {
+                                function: function_name(callee, &next),
+                                position,
+                                arguments,
+                                named,
+                            };
+                            function = *next;
+                        }
+                        // Returns inside operands reach this loop too, so the value holds no call left to apply.
+                        val => return Ok(val),
+                    }
+                }
+
+                Object::Builtin(builtin) => {
+                    let frame = self.call_stack.last().expect("the frame of this call");
+                    return builtin.apply(&frame.arguments, &frame.named);
+                }
+
+                // If the function is not a function object or a function application, return an error.
+                function => bail!("not a function: {:?}", function),
+            }
         }
-        _ => Ok(Object::Null),
     }
 }
 
-fn eval_bang_prefix_expression(right: Object) -> Result<Object> {
+/// Returns the slot the resolver stored in the identifier
+fn slot(identifier: &Identifier) -> Result<Slot> {
+    match identifier.slot.get() {
+        Some(slot) => Ok(slot),
+        None => bail!("unresolved variable: {}", identifier),
+    }
+}
+
+/// Estimates the memory newly allocated by an infix operation, which only string concatenation does
+pub(crate) fn result_size(operator: &str, left: &Object, right: &Object) -> usize {
+    match (operator, left, right) {
+        ("+", Object::String(l), Object::String(r)) => l.len() + r.len(),
+        _ => 0,
+    }
+}
+
+/// Returns the Rust stack that `max_depth` nested calls may take, for `with_max_stack_size`
+/// when programs should recurse that deep on a thread that has the stack for it
+pub fn stack_size_for_depth(max_depth: usize) -> usize {
+    max_depth.saturating_mul(STACK_SIZE_PER_CALL)
+}
+
+/// Runs `f` on a new thread with `stack_size` bytes of stack and returns its result,
+/// e.g. to give an evaluator the stack its limits need
+pub fn run_with_stack_size<T: Send>(
+    stack_size: usize,
+    f: impl FnOnce() -> T + Send,
+) -> std::io::Result<T> {
+    std::thread::scope(|scope| {
+        let thread = std::thread::Builder::new()
+            .stack_size(stack_size)
+            .spawn_scoped(scope, f)?;
+        Ok(thread
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+    })
+}
+
+/// Returns an address in the current stack frame
+fn stack_address() -> usize {
+    let marker = 0u8;
+    std::hint::black_box(&marker) as *const u8 as usize
+}
+
+/// Renders the arguments of a call for its frame in a stack trace
+pub(crate) fn render_arguments(arguments: &[Object], named: &[(Symbol, Object)]) -> Vec<String> {
+    let mut rendered = arguments
+        .iter()
+        .map(|a| a.to_string())
+        .collect::<Vec<String>>();
+    rendered.extend(named.iter().map(|(name, a)| format!("{}: {}", name, a)));
+    rendered
+}
+
+/// Describes the callee of a call expression for the call stack
+pub(crate) fn callee_name(function: &Expression) -> Symbol {
+    match function {
+        Expression::Identifier(identifier) => identifier.name,
+        _ => Symbol::ANONYMOUS,
+    }
+}
+
+/// Fails unless the object can be called, before a frame is added to the call stack for it
+fn check_callable(function: &Object) -> Result<()> {
+    match function {
+        Object::FunctionObject { .. } | Object::Builtin(_) => Ok(()),
+        _ => bail!("not a function: {:?}", function),
+    }
+}
+
+/// Names a called function by the `let` binding it was defined in, or else by its callee
+fn function_name(callee: Symbol, function: &Object) -> Symbol {
+    match function {
+        Object::FunctionObject { function, .. } => function.name.unwrap_or(callee),
+        _ => callee,
+    }
+}
+
+pub(crate) fn eval_bang_prefix_expression(right: Object) -> Result<Object> {
     // Evaluate the given prefix expression with the '!' operator and return the result.
     match right {
         Object::Boolean(b) => Ok(Object::Boolean(!b)),
@@ -142,27 +1092,41 @@ fn eval_bang_prefix_expression(right: Object) -> Result<Object> {
     }
 }
 
-fn eval_minus_prefix_operator_expression(right: Object) -> Result<Object> {
+pub(crate) fn eval_minus_prefix_operator_expression(right: Object) -> Result<Object> {
     // Evaluate the given prefix expression with the '-' operator and return the result.
     match right {
-        Object::Integer(i) => Ok(Object::Integer(-i)),
+        Object::Integer(i) => match i.checked_neg() {
+            Some(i) => Ok(Object::Integer(i)),
+            None => bail!("integer overflow: -{}", i),
+        },
         _ => bail!("cannot use '-' operator on {:?}", right),
     }
 }
 
-fn eval_infix_expression(operator: String, left: Object, right: Object) -> Result<Object> {
+pub(crate) fn eval_infix_expression(operator: &str, left: Object, right: Object) -> Result<Object> {
     // Evaluate the given infix expression and return the result.
     match (left, right) {
         // If both operands are integers, apply the operator and return the result.
-        (Object::Integer(left), Object::Integer(right)) => match operator.as_str() {
-            "+" => Ok(Object::Integer(left + right)),
-            "-" => Ok(Object::Integer(left - right)),
-            "*" => Ok(Object::Integer(left * right)),
-            "/" => Ok(Object::Integer(left / right)),
+        (Object::Integer(left), Object::Integer(right)) => match operator {
+            "+" => eval_integer_arithmetic(left, "+", right, left.checked_add(right)),
+            "-" => eval_integer_arithmetic(left, "-", right, left.checked_sub(right)),
+            "*" => eval_integer_arithmetic(left, "*", right, left.checked_mul(right)),
+            "/" if right == 0 => bail!("division by zero: {} / {}", left, right),
+            "/" => eval_integer_arithmetic(left, "/", right, left.checked_div(right)),
             "<" => Ok(Object::Boolean(left < right)),
             ">" => Ok(Object::Boolean(left > right)),
             "==" => Ok(Object::Boolean(left == right)),
             "!=" => Ok(Object::Boolean(left != right)),
+            ".." => Ok(Object::Range {
+                start: left,
+                end: right,
+                inclusive: false,
+            }),
+            "..=" => Ok(Object::Range {
+                start: left,
+                end: right,
+                inclusive: true,
+            }),
             _ => bail!(
                 "unknown operator: {:?} {} {:?}",
                 Object::Integer(left),
@@ -172,7 +1136,7 @@ fn eval_infix_expression(operator: String, left: Object, right: Object) -> Resul
         },
 
         // If both operands are booleans, apply the operator and return the result.
-        (Object::Boolean(left), Object::Boolean(right)) => match operator.as_str() {
+        (Object::Boolean(left), Object::Boolean(right)) => match operator {
             "==" => Ok(Object::Boolean(left == right)),
             "!=" => Ok(Object::Boolean(left != right)),
             _ => bail!(
@@ -183,8 +1147,21 @@ fn eval_infix_expression(operator: String, left: Object, right: Object) -> Resul
             ),
         },
 
+        // If both operands are strings, apply the operator and return the result.
+        (Object::String(left), Object::String(right)) => match operator {
+            "+" => Ok(Object::String(left + &right)),
+            "==" => Ok(Object::Boolean(left == right)),
+            "!=" => Ok(Object::Boolean(left != right)),
+            _ => bail!(
+                "unknown operator: {:?} {} {:?}",
+                Object::String(left),
+                operator,
+                Object::String(right)
+            ),
+        },
+
         // If both operands are null, apply the operator and return the result.
-        (Object::Null, Object::Null) => match operator.as_str() {
+        (Object::Null, Object::Null) => match operator {
             "==" => Ok(Object::Boolean(true)),
             "!=" => Ok(Object::Boolean(false)),
             _ => bail!("unknown operator: Null {} Null", operator),
@@ -195,40 +1172,89 @@ fn eval_infix_expression(operator: String, left: Object, right: Object) -> Resul
     }
 }
 
-fn apply_function(function: Object, arguments: Vec<Object>) -> Result<Object> {
-    // Apply the given function to the given arguments and return the result.
-    match function {
-        // If the function is a function object, create a new environment for the function and evaluate the body.
-        Object::FunctionObject {
-            parameters,
-            body,
-            env: _,
-        } => {
-            let mut extended_env = Environment::new();
-            if parameters.len() != arguments.len() {
-                bail!(
-                    "wrong number of arguments: expected={}, got={}",
-                    parameters.len(),
-                    arguments.len()
-                );
-            }
-            for (param, arg) in parameters.iter().zip(arguments) {
-                extended_env.set(param.to_string(), arg);
-            }
-            eval_statement(body, &mut extended_env)
-        }
+/// Returns the result of integer arithmetic, which is None when it overflows
+fn eval_integer_arithmetic(
+    left: i64,
+    operator: &str,
+    right: i64,
+    result: Option<i64>,
+) -> Result<Object> {
+    match result {
+        Some(result) => Ok(Object::Integer(result)),
+        None => bail!("integer overflow: {} {} {}", left, operator, right),
+    }
+}
 
-        // If the function is not a function object or a function application, return an error.
-        function => bail!("not a function: {:?}", function),
+pub(crate) fn eval_index_expression(left: Object, index: Object) -> Result<Object> {
+    // Evaluate the given index expression and return the element, or null if there is none.
+    match (left, index) {
+        (Object::Array(elements), Object::Integer(i)) => match usize::try_from(i) {
+            Ok(i) if i < elements.len() => Ok(elements[i].clone()),
+            _ => Ok(Object::Null),
+        },
+        (Object::String(s), Object::Integer(i)) => match usize::try_from(i) {
+            Ok(i) => Ok(s
+                .chars()
+                .nth(i)
+                .map_or(Object::Null, |c| Object::String(c.to_string()))),
+            _ => Ok(Object::Null),
+        },
+        (Object::Hash(pairs), key) => {
+            let key = key.hash_key()?;
+            Ok(pairs.get(&key).cloned().unwrap_or(Object::Null))
+        }
+        (left, index) => bail!("index operator not supported: {:?}[{:?}]", left, index),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::compiler;
+    use crate::error::StackTrace;
     use crate::lexer::Lexer;
     use crate::object::Object;
+    use crate::optimizer;
     use crate::parser::Parser;
+    use crate::vm;
+
+    /// Evaluates the program and checks that the virtual machine gives the same result or error
+    fn eval_program(program: Program) -> Result<Object> {
+        let evaluated = super::eval_program(&program);
+        assert_same_result(&evaluated, &vm::run_program(&program));
+        evaluated
+    }
+
+    fn assert_same_result(evaluated: &Result<Object>, ran: &Result<Object>) {
+        // The engines show functions differently, so messages are compared up to the first function.
+        let message = |err: &anyhow::Error| {
+            let message = err.to_string();
+            let end = ["FunctionObject", "Closure"]
+                .iter()
+                .filter_map(|function| message.find(function))
+                .min()
+                .unwrap_or(message.len());
+            message[..end].to_string()
+        };
+        match (evaluated, ran) {
+            (Ok(evaluated), Ok(ran)) => assert_eq!(evaluated.to_string(), ran.to_string()),
+            (Err(evaluated), Err(ran)) => match (
+                evaluated.downcast_ref::<RuntimeError>(),
+                ran.downcast_ref::<RuntimeError>(),
+            ) {
+                // The engines count steps differently, so only the limit that was hit must agree.
+                (Some(evaluated), Some(ran)) => assert_eq!(
+                    std::mem::discriminant(evaluated),
+                    std::mem::discriminant(ran),
+                    "the engines hit different limits: {} and {}",
+                    evaluated,
+                    ran
+                ),
+                _ => assert_eq!(message(evaluated), message(ran)),
+            },
+            (evaluated, ran) => panic!("the engines disagree: {:?} and {:?}", evaluated, ran),
+        }
+    }
 
     #[test]
     fn test_eval_integer_expression() {
@@ -386,14 +1412,46 @@ mod tests {
                 "unknown operator: Boolean(true) + Boolean(false)",
             ),
             ("foobar", "identifier not found: foobar"),
-        ];
-        for (input, expected) in tests {
-            let l = Lexer::new(input);
-            let mut p = Parser::new(l);
-            let program = p.parse_program().unwrap();
-            let evaluated = eval_program(program);
-            assert!(evaluated.is_err());
-            assert_eq!(evaluated.err().unwrap().to_string(), expected);
+            ("if (false) { foobar }", "identifier not found: foobar"),
+            (
+                "let f = fn() { x }; f(); let x = 1;",
+                "identifier not found: x\n    at f (<input>:1:21)",
+            ),
+            ("5(1)", "not a function: Integer(5)"),
+            (
+                r#"let f = fn() { "f"(1) }; f()"#,
+                "not a function: String(\"f\")\n    at f (<input>:1:26)",
+            ),
+            ("1 / 0", "division by zero: 1 / 0"),
+            ("let mut x = 1; x /= 0", "division by zero: 1 / 0"),
+            (
+                "9223372036854775807 + 1",
+                "integer overflow: 9223372036854775807 + 1",
+            ),
+            (
+                "-9223372036854775807 - 2",
+                "integer overflow: -9223372036854775807 - 2",
+            ),
+            (
+                "4611686018427387904 * 2",
+                "integer overflow: 4611686018427387904 * 2",
+            ),
+            (
+                "let min = -9223372036854775807 - 1; min / -1",
+                "integer overflow: -9223372036854775808 / -1",
+            ),
+            (
+                "let min = -9223372036854775807 - 1; -min",
+                "integer overflow: --9223372036854775808",
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let evaluated = eval_program(program);
+            assert!(evaluated.is_err());
+            assert_eq!(evaluated.err().unwrap().to_string(), expected);
         }
     }
 
@@ -429,6 +1487,7 @@ mod tests {
     }
 
     #[test]
+    #[rustfmt::skip]
     fn test_function_application() {
         let tests = vec![
             ("let identity = fn(x) { x; }; identity(5);", Object::Integer(5)),
@@ -446,4 +1505,1312 @@ mod tests {
             assert_eq!(evaluated, expected);
         }
     }
+
+    #[test]
+    fn test_closures() {
+        let tests = vec![
+            (
+                "let newAdder = fn(x) { fn(y) { x + y }; }; let addTwo = newAdder(2); addTwo(2);",
+                Object::Integer(4),
+            ),
+            (
+                "let fact = fn(n) { if (n < 2) { 1 } else { n * fact(n - 1) } }; fact(5);",
+                Object::Integer(120),
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let evaluated = eval_program(program).unwrap();
+            assert_eq!(evaluated, expected);
+        }
+    }
+
+    #[test]
+    fn test_assign_expression() {
+        let tests = vec![
+            ("let mut a = 5; a = 10; a;", Object::Integer(10)),
+            ("let mut a = 5; a = a + 1;", Object::Integer(6)),
+            ("let mut a = 1; let mut b = 2; a = b = 3; a + b;", Object::Integer(6)),
+            ("let mut a = 5; a += 2; a;", Object::Integer(7)),
+            ("let mut a = 5; a -= 2; a;", Object::Integer(3)),
+            ("let mut a = 5; a *= 2; a;", Object::Integer(10)),
+            ("let mut a = 5; a /= 2; a;", Object::Integer(2)),
+            ("let mut a = true; a = false; a;", Object::Boolean(false)),
+            (
+                "let mut total = 0; let add = fn(x) { total += x; }; add(1); add(2); total;",
+                Object::Integer(3),
+            ),
+            (
+                "let counter = fn() { let mut n = 0; fn() { n += 1; }; }; let c = counter(); c(); c(); c();",
+                Object::Integer(3),
+            ),
+            (
+                "let x = 1; let f = fn() { let mut x = 2; x = 3; x; }; f() + x;",
+                Object::Integer(4),
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let evaluated = eval_program(program).unwrap();
+            assert_eq!(evaluated, expected);
+        }
+    }
+
+    #[test]
+    fn test_assign_expression_error() {
+        let tests = vec![
+            ("a = 5;", "cannot assign to undefined variable: a"),
+            ("a += 5;", "cannot assign to undefined variable: a"),
+            (
+                "let f = fn() { n = 1; }; f(); let mut n = 0;",
+                "cannot assign to undefined variable: n\n    at f (<input>:1:26)",
+            ),
+            (
+                "let f = fn() { n += 1; }; f(); let mut n = 0;",
+                "cannot assign to undefined variable: n\n    at f (<input>:1:27)",
+            ),
+            (
+                "let mut a = true; a += 1;",
+                "type mismatch: Boolean(true) + Integer(1)",
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let evaluated = eval_program(program);
+            assert!(evaluated.is_err());
+            assert_eq!(evaluated.err().unwrap().to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_immutable_bindings() {
+        let tests = [
+            (
+                "let a = 5; a = 10;",
+                "cannot assign to immutable binding: a (declared at 1:5)",
+            ),
+            (
+                "const a = 5;\na += 1;",
+                "cannot assign to immutable binding: a (declared at 1:7)",
+            ),
+            (
+                "let mut a = 5; let a = 6; a = 7;",
+                "cannot assign to immutable binding: a (declared at 1:20)",
+            ),
+            (
+                "let f = fn(x) { x = 1; }; f(5);",
+                "cannot assign to immutable binding: x",
+            ),
+            (
+                "const a = 5; let a = 6;",
+                "cannot redeclare constant: a (declared at 1:7)",
+            ),
+            (
+                "const a = 5; const a = 6;",
+                "cannot redeclare constant: a (declared at 1:7)",
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let evaluated = eval_program(program);
+            assert!(evaluated.is_err());
+            assert_eq!(evaluated.err().unwrap().to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_const_statement() {
+        let tests = [
+            ("const a = 5; a;", Object::Integer(5)),
+            (
+                "const a = 5; let f = fn() { let a = 6; a }; f() + a;",
+                Object::Integer(11),
+            ),
+            ("let a = 5; const a = 6; a;", Object::Integer(6)),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let evaluated = eval_program(program).unwrap();
+            assert_eq!(evaluated, expected);
+        }
+    }
+
+    #[test]
+    fn test_while_statement() {
+        let tests = [
+            (
+                "let mut i = 0; while (i < 10) { i += 1; } i;",
+                Object::Integer(10),
+            ),
+            (
+                "let mut i = 0; let mut sum = 0; while (i < 100000) { i += 1; sum += i; } sum;",
+                Object::Integer(5000050000),
+            ),
+            ("while (false) { 1; }", Object::Null),
+            (
+                "let mut i = 0; while (true) { i += 1; if (i == 5) { break; } } i;",
+                Object::Integer(5),
+            ),
+            (
+                "let mut i = 0; let mut sum = 0; while (i < 10) { i += 1; if (i > 3) { continue; } sum += i; } sum;",
+                Object::Integer(6),
+            ),
+            (
+                "let f = fn() { let mut i = 0; while (true) { i += 1; if (i == 3) { return i * 10; } } }; f() + 1;",
+                Object::Integer(31),
+            ),
+            (
+                "let mut i = 0; let mut j = 0; while (i < 3) { i += 1; let mut k = 0; while (true) { k += 1; j += 1; if (k == 2) { break; } } } j;",
+                Object::Integer(6),
+            ),
+            (
+                "let mut i = 0; while (i < 3) { const x = i; i = x + 1; } i;",
+                Object::Integer(3),
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let evaluated = eval_program(program).unwrap();
+            assert_eq!(evaluated, expected);
+        }
+    }
+
+    #[test]
+    fn test_loop_control_error() {
+        let tests = [
+            ("break;", "break outside of loop"),
+            ("if (true) { continue; }", "continue outside of loop"),
+            (
+                "while (true) { let f = fn() { break; }; f(); }",
+                "break outside of loop",
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let evaluated = eval_program(program);
+            assert!(evaluated.is_err());
+            assert_eq!(evaluated.err().unwrap().to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_return_value_in_expression() {
+        let tests = [
+            ("let f = fn() { return 1; }; f() + 1;", Object::Integer(2)),
+            (
+                "let f = fn(x) { if (x > 0) { return x; } 0 }; f(3) * f(-1) + f(4);",
+                Object::Integer(4),
+            ),
+            // A return inside an operand returns from the function at once.
+            (
+                "let f = fn() { let x = [if (true) { return 1; }]; 99 }; f()",
+                Object::Integer(1),
+            ),
+            (
+                "let f = fn(c) { let x = if (c) { return 5; } else { 2 }; x + 100 }; f(true) + f(false)",
+                Object::Integer(107),
+            ),
+            (
+                "let f = fn() { 1 + match 2 { n => { return n; } } }; f()",
+                Object::Integer(2),
+            ),
+            (
+                "let g = fn(a, b) { a + b }; let f = fn() { g(1, if (true) { return 3; }) }; f()",
+                Object::Integer(3),
+            ),
+            (
+                "let f = fn(x = if (true) { return 4; }) { x + 1 }; f() + f(10)",
+                Object::Integer(15),
+            ),
+            ("let x = -if (true) { return 6; }; x", Object::Integer(6)),
+            // So do break and continue inside a loop.
+            (
+                "let mut n = 0; while (true) { n += 1; let x = {\"a\": if (n == 3) { break; } else { n }}; } n",
+                Object::Integer(3),
+            ),
+            (
+                "let mut s = 0; for (i in 0..5) { s += [i][if (i == 2) { continue; } else { 0 }]; } s",
+                Object::Integer(8),
+            ),
+            (
+                "let mut n = 0; let mut m = 0; for (i in 0..2) { while (if (n == 3) { break; } else { true }) { n += 1; } m += 1; } n * 10 + m",
+                Object::Integer(32),
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let evaluated = eval_program(program).unwrap();
+            assert_eq!(evaluated, expected);
+        }
+    }
+
+    #[test]
+    fn test_string_expression() {
+        let tests = [
+            (
+                r#""Hello World!""#,
+                Object::String("Hello World!".to_string()),
+            ),
+            (
+                r#""Hello" + " " + "World!""#,
+                Object::String("Hello World!".to_string()),
+            ),
+            (r#""a" == "a""#, Object::Boolean(true)),
+            (r#""a" != "a""#, Object::Boolean(false)),
+            (r#""héllo"[1]"#, Object::String("é".to_string())),
+            (r#""abc"[3]"#, Object::Null),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let evaluated = eval_program(program).unwrap();
+            assert_eq!(evaluated, expected);
+        }
+    }
+
+    #[test]
+    fn test_array_and_hash_expression() {
+        let tests = [
+            (
+                "[1, 2 * 2, 3 + 3]",
+                Object::Array(vec![
+                    Object::Integer(1),
+                    Object::Integer(4),
+                    Object::Integer(6),
+                ]),
+            ),
+            ("[1, 2, 3][0]", Object::Integer(1)),
+            ("[1, 2, 3][1 + 1]", Object::Integer(3)),
+            ("let myArray = [1, 2, 3]; myArray[2];", Object::Integer(3)),
+            ("[1, 2, 3][3]", Object::Null),
+            ("[1, 2, 3][-1]", Object::Null),
+            (r#"({"foo": 5})["foo"]"#, Object::Integer(5)),
+            (r#"({"foo": 5})["bar"]"#, Object::Null),
+            (r#"let key = "foo"; ({"foo": 5})[key]"#, Object::Integer(5)),
+            ("({5: 5})[5]", Object::Integer(5)),
+            ("({true: 5})[true]", Object::Integer(5)),
+            ("({})[false]", Object::Null),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let evaluated = eval_program(program).unwrap();
+            assert_eq!(evaluated, expected);
+        }
+    }
+
+    #[test]
+    fn test_range_expression() {
+        let tests = [
+            (
+                "0..10",
+                Object::Range {
+                    start: 0,
+                    end: 10,
+                    inclusive: false,
+                },
+            ),
+            (
+                "let n = 3; 1..=n * 2",
+                Object::Range {
+                    start: 1,
+                    end: 6,
+                    inclusive: true,
+                },
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let evaluated = eval_program(program).unwrap();
+            assert_eq!(evaluated, expected);
+        }
+    }
+
+    #[test]
+    fn test_for_statement() {
+        let tests = [
+            (
+                "let mut sum = 0; for (x in [1, 2, 3]) { sum += x; } sum;",
+                Object::Integer(6),
+            ),
+            (
+                r#"let mut s = ""; for (c in "abc") { s = c + s; } s;"#,
+                Object::String("cba".to_string()),
+            ),
+            (
+                r#"let mut s = ""; for (c in "añ😄") { s = c + s; } s;"#,
+                Object::String("😄ña".to_string()),
+            ),
+            (
+                r#"let mut s = ""; for (k in {"b": 1, "a": 2}) { s = s + k; } s;"#,
+                Object::String("ab".to_string()),
+            ),
+            (
+                "let mut sum = 0; for (i in 0..10) { sum += i; } sum;",
+                Object::Integer(45),
+            ),
+            (
+                "let mut sum = 0; for (i in 0..=10) { sum += i; } sum;",
+                Object::Integer(55),
+            ),
+            ("let mut n = 0; for (i in 5..0) { n += 1; } n;", Object::Integer(0)),
+            (
+                "let mut sum = 0; for (i in 0..1000000000000) { if (i == 5) { break; } if (i == 2) { continue; } sum += i; } sum;",
+                Object::Integer(8),
+            ),
+            (
+                "let f = fn(xs) { for (x in xs) { if (x > 1) { return x; } } -1 }; f([0, 1, 2, 3]) + f([]);",
+                Object::Integer(1),
+            ),
+            (
+                "let mut f = fn() { -1 }; for (i in 0..3) { if (i == 1) { f = fn() { i }; } } f();",
+                Object::Integer(1),
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let evaluated = eval_program(program).unwrap();
+            assert_eq!(evaluated, expected);
+        }
+    }
+
+    #[test]
+    fn test_collection_error() {
+        let tests = [
+            ("for (x in 5) { x; }", "cannot iterate over Integer(5)"),
+            (
+                "for (x in [1]) { x = 2; }",
+                "cannot assign to immutable binding: x",
+            ),
+            ("({[1]: 2})", "unusable as hash key: Array([Integer(1)])"),
+            (
+                "5[0]",
+                "index operator not supported: Integer(5)[Integer(0)]",
+            ),
+            (
+                r#""a" - "b""#,
+                r#"unknown operator: String("a") - String("b")"#,
+            ),
+            (
+                "true..false",
+                "unknown operator: Boolean(true) .. Boolean(false)",
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let evaluated = eval_program(program);
+            assert!(evaluated.is_err());
+            assert_eq!(evaluated.err().unwrap().to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_else_if_expression() {
+        let tests = [
+            (
+                "let sign = fn(x) { if (x < 0) { -1 } else if (x == 0) { 0 } else { 1 } }; [sign(-5), sign(0), sign(5)]",
+                Object::Array(vec![
+                    Object::Integer(-1),
+                    Object::Integer(0),
+                    Object::Integer(1),
+                ]),
+            ),
+            ("if (false) { 1 } else if (false) { 2 }", Object::Null),
+            (
+                "let mut x = 0; if (x > 0) { x = 1; } x -= 1; x",
+                Object::Integer(-1),
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let evaluated = eval_program(program).unwrap();
+            assert_eq!(evaluated, expected);
+        }
+    }
+
+    #[test]
+    fn test_match_expression() {
+        let tests = [
+            (
+                r#"let f = fn(x) { match x { 0 => "zero", -1 => "minus one", _ => "other" } }; [f(0), f(-1), f(7)]"#,
+                Object::Array(vec![
+                    Object::String("zero".to_string()),
+                    Object::String("minus one".to_string()),
+                    Object::String("other".to_string()),
+                ]),
+            ),
+            ("match 5 { n if n > 3 => n * 2, n => n }", Object::Integer(10)),
+            ("match 2 { n if n > 3 => n * 2, n => n }", Object::Integer(2)),
+            (
+                "match [1, 2, 3] { [a] => a, [a, ...rest] => rest }",
+                Object::Array(vec![Object::Integer(2), Object::Integer(3)]),
+            ),
+            ("match [1, [2, 3]] { [1, [_, b]] => b }", Object::Integer(3)),
+            (
+                r#"match {"x": 1, "y": 2} { {"z": z} => z, {"x": 1, "y": y} => { let d = y * 10; d } }"#,
+                Object::Integer(20),
+            ),
+            (
+                "let mut n = 0; for (i in 0..10) { match i { 3 => { break; }, _ => { n += i; } } } n",
+                Object::Integer(3),
+            ),
+            ("let x = 1; match 2 { x => x }; x", Object::Integer(1)),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let evaluated = eval_program(program).unwrap();
+            assert_eq!(evaluated, expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_match_expression_error() {
+        let tests = [
+            (
+                "match 3 { 1 => 1, 2 => 2 }",
+                "no match arm matched Integer(3)",
+            ),
+            (
+                "match 3 { n => { n = 4; } }",
+                "cannot assign to immutable binding: n",
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let evaluated = eval_program(program);
+            assert!(evaluated.is_err());
+            assert_eq!(evaluated.err().unwrap().to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_destructuring() {
+        let tests = [
+            ("let [a, b] = [1, 2]; a * 10 + b", Object::Integer(12)),
+            (
+                r#"let {"x": x, "y": y} = {"x": 3, "y": 4, "z": 5}; x * y"#,
+                Object::Integer(12),
+            ),
+            (
+                "let [first, ...rest] = [1, 2, 3]; rest",
+                Object::Array(vec![Object::Integer(2), Object::Integer(3)]),
+            ),
+            ("let mut [a, _] = [1, 2]; a += 5; a", Object::Integer(6)),
+            (
+                "let swap = fn([a, b]) { [b, a] }; swap([1, 2])",
+                Object::Array(vec![Object::Integer(2), Object::Integer(1)]),
+            ),
+            (
+                r#"let norm = fn({"x": x, "y": y}, scale) { (x * x + y * y) * scale }; norm({"x": 1, "y": 2}, 2)"#,
+                Object::Integer(10),
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let evaluated = eval_program(program).unwrap();
+            assert_eq!(evaluated, expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_destructuring_error() {
+        let tests = [
+            (
+                "let [a, b] = [1];",
+                "pattern [a, b] does not match Array([Integer(1)])",
+            ),
+            (
+                r#"let {"x": x} = 5;"#,
+                r#"pattern {"x": x} does not match Integer(5)"#,
+            ),
+            (
+                "let f = fn([a, b]) { a }; f(1)",
+                "argument Integer(1) does not match parameter [a, b]",
+            ),
+            (
+                "const [a, b] = [1, 2]; let a = 3;",
+                "cannot redeclare constant: a (declared at 1:7)",
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let evaluated = eval_program(program);
+            assert!(evaluated.is_err());
+            assert_eq!(evaluated.err().unwrap().root_cause().to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_parameters_and_arguments() {
+        let tests = [
+            (
+                "let add = fn(x, y = 10) { x + y }; [add(1), add(1, 2)]",
+                Object::Array(vec![Object::Integer(11), Object::Integer(3)]),
+            ),
+            (
+                "let mut n = 1; let f = fn(x = n * 2) { x }; n = 5; f()",
+                Object::Integer(10),
+            ),
+            (
+                "let f = fn(x, ...rest) { rest }; f(1, 2, 3)",
+                Object::Array(vec![Object::Integer(2), Object::Integer(3)]),
+            ),
+            (
+                "let f = fn(x, ...rest) { rest }; f(1)",
+                Object::Array(vec![]),
+            ),
+            (
+                "let add = fn(a, b, c) { a * 100 + b * 10 + c }; let args = [2, 3]; add(1, ...args)",
+                Object::Integer(123),
+            ),
+            (
+                "let sum = fn(...xs) { let mut s = 0; for (x in xs) { s += x; } s }; sum(...1..=4, 10)",
+                Object::Integer(20),
+            ),
+            (
+                "let f = fn(x, y = 2, z = 3) { [x, y, z] }; f(1, z: 30)",
+                Object::Array(vec![
+                    Object::Integer(1),
+                    Object::Integer(2),
+                    Object::Integer(30),
+                ]),
+            ),
+            (
+                "let f = fn(x, y) { x - y }; f(y: 1, x: 10)",
+                Object::Integer(9),
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let evaluated = eval_program(program).unwrap();
+            assert_eq!(evaluated, expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_parameters_and_arguments_error() {
+        let tests = [
+            (
+                "let f = fn(x, y) { x }; f(1)",
+                "wrong number of arguments: expected=2, got=1",
+            ),
+            (
+                "let f = fn(x, y = 1) { x }; f(1, 2, 3)",
+                "wrong number of arguments: expected=1..=2, got=3",
+            ),
+            (
+                "let f = fn(x, ...rest) { x }; f()",
+                "wrong number of arguments: expected=at least 1, got=0",
+            ),
+            ("let f = fn(x) { x }; f(y: 1)", "unknown parameter: y"),
+            (
+                "let f = fn(x) { x }; f(1, x: 2)",
+                "parameter x is given more than once",
+            ),
+            (
+                "let f = fn(x) { x }; f(...5)",
+                "cannot iterate over Integer(5)",
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let evaluated = eval_program(program);
+            assert!(evaluated.is_err());
+            assert_eq!(evaluated.err().unwrap().root_cause().to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_tail_call() {
+        let tests = [
+            (
+                "let count = fn(n, acc) { if (n == 0) { acc } else { count(n - 1, acc + 1) } }; count(100000, 0)",
+                Object::Integer(100000),
+            ),
+            (
+                "let count = fn(n) { if (n == 0) { return 0; } return count(n - 1); }; count(20000)",
+                Object::Integer(0),
+            ),
+            (
+                "let even = fn(n) { match n { 0 => true, _ => odd(n - 1) } }; let odd = fn(n) { match n { 0 => false, _ => even(n - 1) } }; even(20001)",
+                Object::Boolean(false),
+            ),
+            (
+                "let f = fn(n) { while (true) { return if (n > 0) { f(n - 1) } else { n }; } }; f(20000)",
+                Object::Integer(0),
+            ),
+            (
+                "let fact = fn(n) { if (n == 0) { 1 } else { n * fact(n - 1) } }; fact(10)",
+                Object::Integer(3628800),
+            ),
+            (
+                "let id = fn(x) { x }; let f = fn() { id(1) + 1 }; f()",
+                Object::Integer(2),
+            ),
+            ("let f = fn(x) { x * 2 }; return f(21);", Object::Integer(42)),
+            // A returned call inside an operand is applied before the value leaves the function.
+            (
+                "let g = fn(x) { x * 2 }; let f = fn(c) { let y = if (c) { return g(21); } else { 0 }; y + 1 }; f(true) + f(false)",
+                Object::Integer(43),
+            ),
+            (
+                "let g = fn(x) { x }; let f = fn() { 1 + if (true) { if (true) { return g(1); } } else { 2 } }; [f()]",
+                Object::Array(vec![Object::Integer(1)]),
+            ),
+            (
+                "let count = fn(n) { let x = if (n > 0) { return count(n - 1); } else { 0 }; x }; count(100000)",
+                Object::Integer(0),
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let evaluated = eval_program(program).unwrap();
+            assert_eq!(evaluated, expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        // Recursion to the default depth needs a larger stack than the default limits use.
+        let deep = || Evaluator::new().with_max_stack_size(stack_size_for_depth(DEFAULT_MAX_DEPTH));
+        run_with_stack_size(deep().stack_size(), || {
+            let eval = |input: &str, evaluator: Evaluator| {
+                let l = Lexer::new(input);
+                let mut p = Parser::new(l);
+                let program = p.parse_program().unwrap();
+                let mut evaluator = evaluator;
+                evaluator.eval_program(&program)
+            };
+            let runtime_error = |result: Result<Object>| -> RuntimeError {
+                let err = result.unwrap_err();
+                err.downcast_ref::<RuntimeError>().unwrap().clone()
+            };
+
+            // With that stack the default depth is the limit that applies, as on the virtual machine.
+            let input = "let f = fn(n) { if (n == 0) { 0 } else { 1 + f(n - 1) } };";
+            let result = eval(&format!("{} f(999)", input), deep());
+            assert_eq!(result.unwrap(), Object::Integer(999));
+            match runtime_error(eval(&format!("{} f(1000)", input), deep())) {
+                RuntimeError::StackOverflow { call_stack } => {
+                    assert_eq!(call_stack.len(), DEFAULT_MAX_DEPTH)
+                }
+                err => panic!("unexpected error: {}", err),
+            }
+
+            // The default limits stop unbounded recursion before the Rust stack runs out.
+            let result = eval("let f = fn(n) { f(n + 1) + 1 }; f(0)", Evaluator::new());
+            match runtime_error(result) {
+                RuntimeError::StackOverflow { call_stack } => {
+                    assert!(call_stack.iter().all(|name| name == "f"))
+                }
+                err => panic!("unexpected error: {}", err),
+            }
+
+            let result = eval(
+                "let g = fn(n) { h(n) + 1 }; let h = fn(n) { g(n) + 1 }; g(0)",
+                Evaluator::new().with_max_depth(5),
+            );
+            assert_eq!(
+                runtime_error(result),
+                RuntimeError::StackOverflow {
+                    call_stack: ["g", "h", "g", "h", "g"].map(String::from).to_vec(),
+                }
+            );
+            let result = eval(
+                "let f = fn(n) { f(n + 1) + 1 }; f(0)",
+                Evaluator::new().with_max_depth(3),
+            );
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "stack overflow at call depth 3 in f
+    at f (<input>:1:17)
+    at f (<input>:1:17)
+    at f (<input>:1:33)"
+            );
+
+            let result = eval(
+                "let f = fn(n) { f(n + 1) + 1 }; f(0)",
+                Evaluator::new().with_max_stack_size(64 * 1024),
+            );
+            assert!(matches!(
+                runtime_error(result),
+                RuntimeError::StackOverflow { .. }
+            ));
+
+            // Tail calls do not count towards the depth, and the evaluator can be reused after an overflow.
+            let mut evaluator = Evaluator::new().with_max_depth(3);
+            let l = Lexer::new("let f = fn(n) { f(n + 1) + 1 }; f(0)");
+            let program = Parser::new(l).parse_program().unwrap();
+            assert!(evaluator.eval_program(&program).is_err());
+            let l = Lexer::new(
+                "let count = fn(n) { if (n == 0) { 0 } else { count(n - 1) } }; count(1000)",
+            );
+            let program = Parser::new(l).parse_program().unwrap();
+            assert_eq!(
+                evaluator.eval_program(&program).unwrap(),
+                Object::Integer(0)
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_out_of_fuel() {
+        let eval = |input: &str, evaluator: &mut Evaluator| {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            evaluator.eval_program(&program)
+        };
+
+        let mut evaluator = Evaluator::new().with_max_steps(1000);
+        let err = eval("while (true) { }", &mut evaluator).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::OutOfFuel { steps: 1000 })
+        );
+        assert_eq!(err.to_string(), "out of fuel after 1000 steps");
+
+        // Spreading uses a step for each element.
+        let err = eval(
+            "let f = fn(...r) { 1 }; f(...0..100000000000)",
+            &mut evaluator,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::OutOfFuel { steps: 1000 })
+        );
+
+        // Tail recursion runs in constant stack but still uses fuel.
+        let err = eval("let f = fn(n) { f(n + 1) }; f(0)", &mut evaluator).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::OutOfFuel { steps: 1000 })
+        );
+
+        // The budget applies to each program, and small programs fit in it.
+        let result = eval("let x = 1 + 2; x * 3", &mut evaluator).unwrap();
+        assert_eq!(result, Object::Integer(9));
+        assert!(evaluator.steps() > 0 && evaluator.steps() <= 1000);
+
+        let mut evaluator = Evaluator::new().with_max_steps(evaluator.steps());
+        assert!(eval("let x = 1 + 2; x * 3", &mut evaluator).is_ok());
+        let mut evaluator = Evaluator::new().with_max_steps(evaluator.steps() - 1);
+        assert!(eval("let x = 1 + 2; x * 3", &mut evaluator).is_err());
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let parse = |input: &str| {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            p.parse_program().unwrap()
+        };
+
+        // A token cancelled from another thread stops a running loop.
+        let token = CancellationToken::new();
+        let canceller = {
+            let token = token.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(20));
+                token.cancel();
+            })
+        };
+        let mut evaluator = Evaluator::new().with_cancellation(token.clone());
+        let err = evaluator
+            .eval_program(&parse("while (true) { }"))
+            .unwrap_err();
+        canceller.join().unwrap();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::Cancelled)
+        );
+        assert_eq!(err.to_string(), "evaluation cancelled");
+
+        // An already cancelled token stops evaluation before it starts.
+        let err = evaluator.eval_program(&parse("1")).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::Cancelled)
+        );
+
+        // Timeouts also stop the loops inside a single expression, like spreading a long range.
+        let mut evaluator = Evaluator::new().with_timeout(Duration::from_millis(20));
+        let err = evaluator
+            .eval_program(&parse("let f = fn(...r) { 1 }; f(...0..100000000000)"))
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::Cancelled)
+        );
+
+        // A timeout leaves the bindings made so far in the environment, which stays usable.
+        let env = gc::alloc(Environment::new());
+        let mut evaluator = Evaluator::new().with_timeout(Duration::from_millis(20));
+        let err = evaluator
+            .eval_program_with_env(&parse("let mut n = 0; while (true) { n += 1; }"), &env)
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::Cancelled)
+        );
+        let n = match env.borrow().get(Symbol::intern("n")) {
+            Some(Object::Integer(n)) => n,
+            n => panic!("unexpected value of n: {:?}", n),
+        };
+        assert!(n > 0);
+        let result = Evaluator::new()
+            .eval_program_with_env(&parse("n += 1; n"), &env)
+            .unwrap();
+        assert_eq!(result, Object::Integer(n + 1));
+    }
+
+    #[test]
+    fn test_out_of_memory() {
+        let eval = |input: &str, evaluator: &mut Evaluator| {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            evaluator.eval_program(&program)
+        };
+        let limit = 1024 * 1024;
+        let mut evaluator = Evaluator::new().with_max_memory(limit);
+
+        let tests = [
+            r#"let mut s = "ab"; while (true) { s += s; }"#,
+            "let f = fn(...xs) { xs }; f(...0..1000000000)",
+            "let f = fn(xs) { f([xs, xs, xs, xs]) }; f([])",
+            r#"let mut h = {}; while (true) { h = {"n": 1, "m": [h, h]}; }"#,
+        ];
+        for input in tests {
+            let err = eval(input, &mut evaluator).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<RuntimeError>(),
+                Some(&RuntimeError::OutOfMemory { limit }),
+                "{}",
+                input
+            );
+            assert!(evaluator.peak_memory() > limit);
+        }
+        assert_eq!(
+            eval(tests[0], &mut evaluator).unwrap_err().to_string(),
+            "out of memory: more than 1048576 bytes in use"
+        );
+
+        // Memory is counted while it is in use, so loops that release what they allocate run to the end.
+        let input = "let mut i = 0; while (i < 100000) { i += 1; } i";
+        assert_eq!(
+            eval(input, &mut evaluator).unwrap(),
+            Object::Integer(100000)
+        );
+        assert!(evaluator.peak_memory() < limit / 8);
+        // Scopes that hold closures over themselves are collected before the limit is reached.
+        let input = r#"let mut n = 0;
+            for (i in 0..20000) { let h = {"i": 1, "s": [i, "abc"]}; let f = fn() { h }; n += f()["i"]; }
+            n"#;
+        assert_eq!(eval(input, &mut evaluator).unwrap(), Object::Integer(20000));
+
+        let result = eval(r#"let f = fn(x) { [x, x + "!"] }; f("hi")"#, &mut evaluator).unwrap();
+        assert_eq!(
+            result,
+            Object::Array(vec![
+                Object::String("hi".to_string()),
+                Object::String("hi!".to_string()),
+            ])
+        );
+        assert!(evaluator.peak_memory() > 0 && evaluator.peak_memory() < limit);
+
+        // Names the program adds to the symbol table are in use for good, so they count too.
+        let input = (0..30000)
+            .map(|i: u32| {
+                // Identifiers have no digits, so the number is spelled in letters.
+                let name = i.to_string();
+                let name = name.bytes().map(|d| (d - b'0' + b'a') as char);
+                format!(
+                    "let evaluator_memory_test_{} = 0;",
+                    name.collect::<String>()
+                )
+            })
+            .collect::<String>();
+        let input = format!("fn() {{ {} }}; 1", input);
+        let err = eval(&input, &mut evaluator).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::OutOfMemory { limit })
+        );
+        assert_eq!(eval(&input, &mut evaluator).unwrap(), Object::Integer(1));
+    }
+
+    #[test]
+    fn test_stack_trace() {
+        let eval = |input: &str, mut evaluator: Evaluator| {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            evaluator.eval_program(&program).unwrap_err()
+        };
+        let frame = |function: &str, line, column, arguments: &[&str]| Frame {
+            function: function.to_string(),
+            source: "script.mnk".to_string(),
+            position: Position { line, column },
+            arguments: arguments.iter().map(|a| a.to_string()).collect(),
+        };
+
+        let input = r#"let div = fn(a, b) { if (b == 0) { a + "" } else { a } };
+let half = fn(n, name = "n") { div(n, 0) * 2 };
+let apply = fn(f, x) { f(x) + 1 };
+apply(fn(x) { half(x, name: "x") + 0 }, 10);"#;
+        let err = eval(input, Evaluator::new().with_source_name("script.mnk"));
+        let trace = err.downcast_ref::<StackTrace>().unwrap();
+        assert_eq!(trace.message, "type mismatch: Integer(10) + String(\"\")");
+        assert_eq!(
+            trace.frames,
+            vec![
+                frame("div", 2, 32, &["10", "0"]),
+                frame("half", 4, 15, &["10", "name: \"x\""]),
+                frame("f", 3, 24, &["10"]),
+                frame(
+                    "apply",
+                    4,
+                    1,
+                    &["fn(x) {\n (half(x, name: \"x\") + 0)\n}", "10"]
+                ),
+            ]
+        );
+        assert_eq!(
+            err.to_string(),
+            r#"type mismatch: Integer(10) + String("")
+    at div (script.mnk:2:32)
+    at half (script.mnk:4:15)
+    at f (script.mnk:3:24)
+    at apply (script.mnk:4:1)"#
+        );
+        assert_eq!(err.root_cause().to_string(), trace.message);
+
+        // A call in tail position takes over the frame of its caller.
+        let input = "let f = fn(n) { if (n == 0) { [][0] + 1 } else { f(n - 1) } };\nf(3)";
+        let err = eval(input, Evaluator::new().with_source_name("script.mnk"));
+        let trace = err.downcast_ref::<StackTrace>().unwrap();
+        assert_eq!(trace.frames, vec![frame("f", 1, 50, &["0"])]);
+
+        // Errors outside of functions have no stack trace.
+        let err = eval("1 + true", Evaluator::new());
+        assert!(err.downcast_ref::<StackTrace>().is_none());
+
+        // Long stack traces leave out the frames in the middle.
+        let err = eval(
+            "let f = fn(n) { f(n + 1) + 1 }; f(0)",
+            Evaluator::new().with_max_depth(22),
+        );
+        assert!(err.downcast_ref::<RuntimeError>().is_some());
+        let trace = err.downcast_ref::<StackTrace>().unwrap();
+        assert_eq!(trace.frames.len(), 22);
+        assert_eq!(
+            trace.frames[0],
+            Frame {
+                function: "f".to_string(),
+                source: "<input>".to_string(),
+                position: Position {
+                    line: 1,
+                    column: 17
+                },
+                arguments: vec!["21".to_string()],
+            }
+        );
+        let lines = err.to_string().lines().count();
+        assert_eq!(lines, 1 + 20 + 1);
+        assert!(err.to_string().contains("\n    ... 2 more frames\n"));
+    }
+
+    /// Generates pseudo-random numbers with xorshift, so that failures can be reproduced from the seed
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn choose<'a>(&mut self, items: &[&'a str]) -> &'a str {
+            items[self.below(items.len())]
+        }
+
+        /// Generates a random expression that is mostly well formed
+        fn expression(&mut self, depth: usize) -> String {
+            const INTEGERS: &[&str] = &[
+                "0",
+                "1",
+                "-1",
+                "2",
+                "x",
+                "n",
+                "9223372036854775807",
+                "(-9223372036854775807 - 1)",
+            ];
+            const ATOMS: &[&str] = &[
+                "true",
+                "[][0]",
+                "\"a\"",
+                "f",
+                "xs",
+                "[]",
+                "({})",
+                "0..3",
+                "fn(...xs) { xs }",
+            ];
+            const OPERATORS: &[&str] = &["+", "-", "*", "/", "<", ">", "==", "!=", "..", "..="];
+            if depth == 0 || self.below(4) == 0 {
+                return match self.below(4) {
+                    0 => self.choose(ATOMS).to_string(),
+                    _ => self.choose(INTEGERS).to_string(),
+                };
+            }
+            let d = depth - 1;
+            match self.below(11) {
+                0 => format!(
+                    "({} {} {})",
+                    self.expression(d),
+                    self.choose(OPERATORS),
+                    self.expression(d)
+                ),
+                1 => format!(
+                    "{}({}, ...{})",
+                    self.expression(d),
+                    self.expression(d),
+                    self.expression(d)
+                ),
+                2 => format!("{}[{}]", self.expression(d), self.expression(d)),
+                3 => format!("[{}, {}]", self.expression(d), self.expression(d)),
+                4 => format!("({{{}: {}}})", self.expression(d), self.expression(d)),
+                5 => format!(
+                    "if ({}) {{ {} }} else {{ {} }}",
+                    self.expression(d),
+                    self.expression(d),
+                    self.expression(d)
+                ),
+                6 => format!(
+                    "fn(x, y = {}) {{ {} }}",
+                    self.expression(d),
+                    self.expression(d)
+                ),
+                7 => format!(
+                    "match {} {{ [x, ...xs] => {}, _ => {} }}",
+                    self.expression(d),
+                    self.expression(d),
+                    self.expression(d)
+                ),
+                8 => format!("{}{}", self.choose(&["-", "!"]), self.expression(d)),
+                9 => format!(
+                    "(n {} {})",
+                    self.choose(&["=", "+=", "/="]),
+                    self.expression(d)
+                ),
+                _ => format!(
+                    "({} {} {})",
+                    self.expression(d),
+                    self.choose(&["+", "-", "*", "/"]),
+                    self.expression(d)
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn test_default_stack() {
+        // The default limits stop recursion before it overflows the stack of a spawned thread.
+        let inputs = [
+            "let f = fn(n) { f(n + 1) + 1 }; f(0)",
+            "let f = fn(n) { [[[[[[[[f(n + 1)]]]]]]]] }; f(0)",
+        ];
+        for input in inputs {
+            let err = std::thread::spawn(move || {
+                let program = Parser::new(Lexer::new(input)).parse_program().unwrap();
+                let err = super::eval_program(&program).unwrap_err();
+                err.downcast_ref::<RuntimeError>().cloned()
+            })
+            .join()
+            .unwrap();
+            assert!(
+                matches!(err, Some(RuntimeError::StackOverflow { .. })),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_no_panics() {
+        run_with_stack_size(Evaluator::new().stack_size(), || {
+            // Lexes, parses and evaluates random inputs under tight limits. Any of the stages may fail,
+            // but none of them may panic or overflow the stack.
+            const FRAGMENTS: &[&str] = &[
+                "let ",
+                "let mut ",
+                "const ",
+                "x",
+                "f",
+                "_",
+                " = ",
+                "; ",
+                "fn",
+                "(",
+                ")",
+                "{",
+                "}",
+                "[",
+                "]",
+                ", ",
+                ": ",
+                "...",
+                "=>",
+                "+",
+                "-",
+                "/",
+                "+=",
+                "<",
+                "==",
+                "!",
+                "..",
+                "..=",
+                "if ",
+                "else ",
+                "match ",
+                "while ",
+                "for ",
+                "in ",
+                "break",
+                "continue",
+                "return ",
+                "true",
+                "null",
+                "0",
+                "1",
+                "99999999999999999999",
+                "\"a\"",
+                "\"",
+                "\\",
+                "\n",
+                "é",
+                "٣",
+                "#",
+                "f(x)",
+                "fn(x) { x }",
+                "[1, 2]",
+                "{\"a\": 1}",
+                "x[0]",
+            ];
+            let mut random = Random(0x2545_f491_4f6c_dd1d);
+            let mut inputs = Vec::new();
+            for _ in 0..2000 {
+                let input = (0..random.below(40))
+                    .map(|_| random.choose(FRAGMENTS))
+                    .collect::<String>();
+                inputs.push(input);
+            }
+            for _ in 0..2000 {
+                let input = format!(
+                    "let mut n = 1; let x = {}; let f = fn(x, ...xs) {{ {} }}; let xs = [{}]; {}",
+                    random.expression(3),
+                    random.expression(3),
+                    random.expression(2),
+                    random.expression(4)
+                );
+                inputs.push(input);
+            }
+            for input in inputs {
+                let result = std::panic::catch_unwind(|| {
+                    let l = Lexer::new(&input);
+                    let _ = l.collect::<Vec<_>>();
+                    let mut p = Parser::new(Lexer::new(&input));
+                    if let Ok(program) = p.parse_program() {
+                        let mut evaluator = Evaluator::new()
+                            .with_max_steps(10_000)
+                            .with_max_memory(1024 * 1024);
+                        let evaluated = evaluator.eval_program(&program);
+                        // Programs that finish within the limits finish on the virtual machine too.
+                        if evaluated
+                            .as_ref()
+                            .err()
+                            .is_none_or(|err| err.downcast_ref::<RuntimeError>().is_none())
+                        {
+                            let ran = compiler::compile(&program).and_then(|bytecode| {
+                                vm::Vm::new().with_max_steps(1_000_000).run(&bytecode)
+                            });
+                            assert_same_result(&evaluated, &ran);
+                        }
+                    }
+                });
+                assert!(result.is_ok(), "panicked on input {:?}", input);
+            }
+
+            // Deep nesting is rejected by the parser or the evaluator instead of overflowing the stack.
+            for (open, close) in [
+                ("(", ")"),
+                ("[", "]"),
+                ("-", ""),
+                ("{", "}"),
+                ("fn() {", "}"),
+                ("", "+1"),
+                ("", "[0]"),
+            ] {
+                for depth in [100, 250, 100_000] {
+                    let input = format!("{}1{}", open.repeat(depth), close.repeat(depth));
+                    let mut p = Parser::new(Lexer::new(&input));
+                    if let Ok(program) = p.parse_program() {
+                        let _ = super::eval_program(&program);
+                        let _ = compiler::compile(&optimizer::optimize(program))
+                            .and_then(|bytecode| vm::Vm::new().run(&bytecode));
+                    }
+                }
+            }
+        })
+        .unwrap();
+    }
 }
@@ -0,0 +1,705 @@
+//! The binary file format of compiled programs.
+//!
+//! A file starts with the magic bytes and the format version, followed by three sections:
+//! the constant pool, the function table (the top level first) and the debug line table of each function.
+//! Integers are little-endian, and strings and lists are prefixed with their length as a u32.
+
+use crate::ast::BindingKind;
+use crate::code::{
+    Address, ArgumentKind, Bytecode, CallSite, CompiledFunction, CompiledParameter,
+    CompiledPattern, Constant, Instruction, Operator, PatternNode,
+};
+use crate::object::HashKey;
+use crate::symbol::{self, Symbol};
+use crate::token::Position;
+use anyhow::{anyhow, bail, ensure, Result};
+use std::rc::Rc;
+
+/// The bytes every bytecode file starts with
+pub const MAGIC: &[u8; 4] = b"MNKC";
+
+/// The version of the format, which changes whenever old files can no longer be read
+pub const VERSION: u16 = 2;
+
+/// How deeply patterns may nest, which the parser limits already
+const MAX_PATTERN_DEPTH: usize = 256;
+
+/// Encodes the bytecode in the file format
+pub fn encode(bytecode: &Bytecode) -> Vec<u8> {
+    let functions = bytecode.functions();
+    let mut writer = Writer::default();
+    writer.bytes.extend_from_slice(MAGIC);
+    writer.bytes.extend_from_slice(&VERSION.to_le_bytes());
+
+    writer.length(bytecode.constants.len());
+    for constant in &bytecode.constants {
+        match constant {
+            Constant::Integer(i) => {
+                writer.u8(0);
+                writer.bytes.extend_from_slice(&i.to_le_bytes());
+            }
+            Constant::String(s) => {
+                writer.u8(1);
+                writer.string(s);
+            }
+            Constant::Pattern(pattern) => {
+                writer.u8(2);
+                writer.pattern(pattern);
+            }
+            Constant::Name(name) => {
+                writer.u8(5);
+                writer.string(name.as_str());
+            }
+            Constant::Function(function) => {
+                let number = functions
+                    .iter()
+                    .position(|f| Rc::ptr_eq(f, function))
+                    .expect("functions are numbered from the constant pool");
+                writer.u8(3);
+                writer.length(number);
+            }
+            Constant::CallSite(call_site) => {
+                writer.u8(4);
+                writer.string(call_site.callee.as_str());
+                writer.position(call_site.position);
+                writer.length(call_site.arguments.len());
+                for argument in &call_site.arguments {
+                    match argument {
+                        ArgumentKind::Positional => writer.u8(0),
+                        ArgumentKind::Spread => writer.u8(1),
+                        ArgumentKind::Named(name) => {
+                            writer.u8(2);
+                            writer.string(name.as_str());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    writer.length(functions.len());
+    for function in &functions {
+        writer.optional_string(function.name.map(Symbol::as_str));
+        writer.length(function.parameters.len());
+        for parameter in &function.parameters {
+            writer.pattern(&parameter.pattern);
+            writer.u8(parameter.has_default as u8);
+        }
+        writer.optional_string(function.rest.map(Symbol::as_str));
+        writer.string(&function.source);
+        writer.length(function.instructions.len());
+        for instruction in &function.instructions {
+            writer.instruction(instruction);
+        }
+    }
+
+    for function in &functions {
+        writer.length(function.lines.len());
+        for (address, position) in &function.lines {
+            writer.u32(*address);
+            writer.position(*position);
+        }
+    }
+    writer.bytes
+}
+
+/// Decodes bytecode from the file format. Instructions are not checked against the constant pool;
+/// the virtual machine fails on ones that refer to missing or wrong constants.
+pub fn decode(bytes: &[u8]) -> Result<Bytecode> {
+    let start = symbol::added_size();
+    let mut reader = Reader { bytes, offset: 0 };
+    ensure!(
+        reader.take(MAGIC.len()).ok() == Some(MAGIC.as_slice()),
+        "not a Monkey bytecode file"
+    );
+    let version = u16::from_le_bytes(reader.array()?);
+    ensure!(
+        version == VERSION,
+        "unsupported bytecode version: {} (expected {})",
+        version,
+        VERSION
+    );
+
+    // Function constants refer to the function table, which comes after the constant pool.
+    let mut constants = Vec::new();
+    for _ in 0..reader.length()? {
+        let constant = match reader.u8()? {
+            0 => Ok(Constant::Integer(i64::from_le_bytes(reader.array()?))),
+            1 => Ok(Constant::String(reader.string()?)),
+            2 => Ok(Constant::Pattern(reader.pattern()?)),
+            3 => Err(reader.length()?),
+            4 => {
+                let callee = reader.name()?;
+                let position = reader.position()?;
+                let mut arguments = Vec::new();
+                for _ in 0..reader.length()? {
+                    arguments.push(match reader.u8()? {
+                        0 => ArgumentKind::Positional,
+                        1 => ArgumentKind::Spread,
+                        2 => ArgumentKind::Named(reader.name()?),
+                        tag => bail!("invalid argument kind: {}", tag),
+                    });
+                }
+                Ok(Constant::CallSite(CallSite {
+                    callee,
+                    position,
+                    arguments,
+                }))
+            }
+            5 => Ok(Constant::Name(reader.name()?)),
+            tag => bail!("invalid constant tag: {}", tag),
+        };
+        constants.push(constant);
+    }
+
+    let mut functions = Vec::new();
+    for _ in 0..reader.length()? {
+        let name = reader.optional_name()?;
+        let mut parameters = Vec::new();
+        for _ in 0..reader.length()? {
+            parameters.push(CompiledParameter {
+                pattern: reader.pattern()?,
+                has_default: reader.u8()? != 0,
+            });
+        }
+        let rest = reader.optional_name()?;
+        let source = reader.string()?;
+        let mut instructions = Vec::new();
+        for _ in 0..reader.length()? {
+            instructions.push(reader.instruction()?);
+        }
+        functions.push(CompiledFunction {
+            name,
+            parameters,
+            rest,
+            instructions,
+            lines: Vec::new(),
+            source,
+        });
+    }
+    ensure!(!functions.is_empty(), "bytecode file has no top level");
+    for function in &mut functions {
+        for _ in 0..reader.length()? {
+            let address = reader.u32()?;
+            function.lines.push((address, reader.position()?));
+        }
+    }
+    ensure!(
+        reader.offset == bytes.len(),
+        "unexpected data at the end of the bytecode file"
+    );
+
+    let functions = functions.into_iter().map(Rc::new).collect::<Vec<_>>();
+    let constants = constants
+        .into_iter()
+        .map(|constant| match constant {
+            Ok(constant) => Ok(constant),
+            Err(number) => match functions.get(number) {
+                Some(function) if number > 0 => Ok(Constant::Function(Rc::clone(function))),
+                _ => bail!("invalid function number: {}", number),
+            },
+        })
+        .collect::<Result<Vec<Constant>>>()?;
+    Ok(Bytecode {
+        main: Rc::clone(&functions[0]),
+        constants,
+        names_size: symbol::added_size() - start,
+    })
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn length(&mut self, length: usize) {
+        self.u32(u32::try_from(length).expect("bytecode too large for the file format"));
+    }
+
+    fn string(&mut self, s: &str) {
+        self.length(s.len());
+        self.bytes.extend_from_slice(s.as_bytes());
+    }
+
+    fn optional_string(&mut self, s: Option<&str>) {
+        match s {
+            Some(s) => {
+                self.u8(1);
+                self.string(s);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn position(&mut self, position: Position) {
+        self.length(position.line);
+        self.length(position.column);
+    }
+
+    fn hash_key(&mut self, key: &HashKey) {
+        match key {
+            HashKey::Integer(i) => {
+                self.u8(0);
+                self.bytes.extend_from_slice(&i.to_le_bytes());
+            }
+            HashKey::Boolean(b) => {
+                self.u8(1);
+                self.u8(*b as u8);
+            }
+            HashKey::String(s) => {
+                self.u8(2);
+                self.string(s);
+            }
+        }
+    }
+
+    fn pattern(&mut self, pattern: &CompiledPattern) {
+        self.pattern_node(&pattern.pattern);
+        self.string(&pattern.source);
+    }
+
+    fn pattern_node(&mut self, pattern: &PatternNode) {
+        match pattern {
+            PatternNode::Wildcard => self.u8(0),
+            PatternNode::Literal(key) => {
+                self.u8(1);
+                self.hash_key(key);
+            }
+            PatternNode::Binding(name) => {
+                self.u8(2);
+                self.string(name.as_str());
+            }
+            PatternNode::Array { elements, rest } => {
+                self.u8(3);
+                self.length(elements.len());
+                for element in elements {
+                    self.pattern_node(element);
+                }
+                self.optional_string(rest.map(Symbol::as_str));
+            }
+            PatternNode::Hash(pairs) => {
+                self.u8(4);
+                self.length(pairs.len());
+                for (key, pattern) in pairs {
+                    self.hash_key(key);
+                    self.pattern_node(pattern);
+                }
+            }
+        }
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) {
+        self.u8(opcode(instruction));
+        match *instruction {
+            Instruction::Constant(a)
+            | Instruction::GetName(a)
+            | Instruction::Assign(a)
+            | Instruction::Array(a)
+            | Instruction::Hash(a)
+            | Instruction::Jump(a)
+            | Instruction::JumpIfNotTrue(a)
+            | Instruction::Closure(a)
+            | Instruction::Call(a)
+            | Instruction::TailCall(a)
+            | Instruction::BindParameter(a) => self.u32(a),
+            Instruction::Let {
+                pattern,
+                kind,
+                position,
+            } => {
+                self.u32(pattern);
+                self.u8(match kind {
+                    BindingKind::Let => 0,
+                    BindingKind::LetMut => 1,
+                    BindingKind::Const => 2,
+                });
+                self.position(position);
+            }
+            Instruction::CompoundAssign { name, operator } => {
+                self.u32(name);
+                self.u8(operator as u8);
+            }
+            Instruction::Infix(operator) => self.u8(operator as u8),
+            Instruction::ArgumentOrJump {
+                parameter: a,
+                target: b,
+            }
+            | Instruction::EnterLoop { exit: a, next: b }
+            | Instruction::IterateNext {
+                variable: a,
+                exit: b,
+            }
+            | Instruction::MatchArm {
+                pattern: a,
+                next: b,
+            } => {
+                self.u32(a);
+                self.u32(b);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Numbers the instructions in the order they are declared
+fn opcode(instruction: &Instruction) -> u8 {
+    match instruction {
+        Instruction::Constant(_) => 0,
+        Instruction::True => 1,
+        Instruction::False => 2,
+        Instruction::Null => 3,
+        Instruction::Pop => 4,
+        Instruction::GetName(_) => 5,
+        Instruction::Let { .. } => 6,
+        Instruction::Assign(_) => 7,
+        Instruction::CompoundAssign { .. } => 8,
+        Instruction::Bang => 9,
+        Instruction::Minus => 10,
+        Instruction::Infix(_) => 11,
+        Instruction::Index => 12,
+        Instruction::Array(_) => 13,
+        Instruction::CheckHashKey => 14,
+        Instruction::Hash(_) => 15,
+        Instruction::Jump(_) => 16,
+        Instruction::JumpIfNotTrue(_) => 17,
+        Instruction::Closure(_) => 18,
+        Instruction::CheckCallable => 19,
+        Instruction::Spread => 20,
+        Instruction::Call(_) => 21,
+        Instruction::TailCall(_) => 22,
+        Instruction::Return => 23,
+        Instruction::ArgumentOrJump { .. } => 24,
+        Instruction::BindParameter(_) => 25,
+        Instruction::EnterBody => 26,
+        Instruction::PushScope => 27,
+        Instruction::PopScope => 28,
+        Instruction::EnterLoop { .. } => 29,
+        Instruction::ExitLoop => 30,
+        Instruction::Break => 31,
+        Instruction::Continue => 32,
+        Instruction::Iterate => 33,
+        Instruction::IterateNext { .. } => 34,
+        Instruction::EndIteration => 35,
+        Instruction::MatchArm { .. } => 36,
+        Instruction::NoMatch => 37,
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8]> {
+        let bytes = self
+            .offset
+            .checked_add(n)
+            .and_then(|end| self.bytes.get(self.offset..end))
+            .ok_or_else(|| anyhow!("unexpected end of bytecode file"))?;
+        self.offset += n;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().expect("N bytes"))
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn length(&mut self) -> Result<usize> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let length = self.length()?;
+        let bytes = self.take(length)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| anyhow!("invalid string in bytecode file"))
+    }
+
+    fn name(&mut self) -> Result<Symbol> {
+        Ok(Symbol::intern(&self.string()?))
+    }
+
+    fn optional_name(&mut self) -> Result<Option<Symbol>> {
+        Ok(self.optional_string()?.as_deref().map(Symbol::intern))
+    }
+
+    fn optional_string(&mut self) -> Result<Option<String>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.string()?)),
+            tag => bail!("invalid optional string tag: {}", tag),
+        }
+    }
+
+    fn position(&mut self) -> Result<Position> {
+        Ok(Position {
+            line: self.length()?,
+            column: self.length()?,
+        })
+    }
+
+    fn hash_key(&mut self) -> Result<HashKey> {
+        match self.u8()? {
+            0 => Ok(HashKey::Integer(i64::from_le_bytes(self.array()?))),
+            1 => Ok(HashKey::Boolean(self.u8()? != 0)),
+            2 => Ok(HashKey::String(self.string()?)),
+            tag => bail!("invalid hash key tag: {}", tag),
+        }
+    }
+
+    fn pattern(&mut self) -> Result<CompiledPattern> {
+        Ok(CompiledPattern {
+            pattern: self.pattern_node(0)?,
+            source: self.string()?,
+        })
+    }
+
+    fn pattern_node(&mut self, depth: usize) -> Result<PatternNode> {
+        ensure!(depth < MAX_PATTERN_DEPTH, "pattern nested too deeply");
+        match self.u8()? {
+            0 => Ok(PatternNode::Wildcard),
+            1 => Ok(PatternNode::Literal(self.hash_key()?)),
+            2 => Ok(PatternNode::Binding(self.name()?)),
+            3 => {
+                let mut elements = Vec::new();
+                for _ in 0..self.length()? {
+                    elements.push(self.pattern_node(depth + 1)?);
+                }
+                Ok(PatternNode::Array {
+                    elements,
+                    rest: self.optional_name()?,
+                })
+            }
+            4 => {
+                let mut pairs = Vec::new();
+                for _ in 0..self.length()? {
+                    pairs.push((self.hash_key()?, self.pattern_node(depth + 1)?));
+                }
+                Ok(PatternNode::Hash(pairs))
+            }
+            tag => bail!("invalid pattern tag: {}", tag),
+        }
+    }
+
+    fn operator(&mut self) -> Result<Operator> {
+        const OPERATORS: [Operator; 10] = [
+            Operator::Add,
+            Operator::Subtract,
+            Operator::Multiply,
+            Operator::Divide,
+            Operator::LessThan,
+            Operator::GreaterThan,
+            Operator::Equal,
+            Operator::NotEqual,
+            Operator::Range,
+            Operator::RangeInclusive,
+        ];
+        let operator = self.u8()?;
+        OPERATORS
+            .get(operator as usize)
+            .copied()
+            .ok_or_else(|| anyhow!("invalid operator: {}", operator))
+    }
+
+    fn instruction(&mut self) -> Result<Instruction> {
+        let instruction = match self.u8()? {
+            0 => Instruction::Constant(self.u32()?),
+            1 => Instruction::True,
+            2 => Instruction::False,
+            3 => Instruction::Null,
+            4 => Instruction::Pop,
+            5 => Instruction::GetName(self.u32()?),
+            6 => Instruction::Let {
+                pattern: self.u32()?,
+                kind: match self.u8()? {
+                    0 => BindingKind::Let,
+                    1 => BindingKind::LetMut,
+                    2 => BindingKind::Const,
+                    kind => bail!("invalid binding kind: {}", kind),
+                },
+                position: self.position()?,
+            },
+            7 => Instruction::Assign(self.u32()?),
+            8 => Instruction::CompoundAssign {
+                name: self.u32()?,
+                operator: self.operator()?,
+            },
+            9 => Instruction::Bang,
+            10 => Instruction::Minus,
+            11 => Instruction::Infix(self.operator()?),
+            12 => Instruction::Index,
+            13 => Instruction::Array(self.u32()?),
+            14 => Instruction::CheckHashKey,
+            15 => Instruction::Hash(self.u32()?),
+            16 => Instruction::Jump(self.address()?),
+            17 => Instruction::JumpIfNotTrue(self.address()?),
+            18 => Instruction::Closure(self.u32()?),
+            19 => Instruction::CheckCallable,
+            20 => Instruction::Spread,
+            21 => Instruction::Call(self.u32()?),
+            22 => Instruction::TailCall(self.u32()?),
+            23 => Instruction::Return,
+            24 => Instruction::ArgumentOrJump {
+                parameter: self.u32()?,
+                target: self.address()?,
+            },
+            25 => Instruction::BindParameter(self.u32()?),
+            26 => Instruction::EnterBody,
+            27 => Instruction::PushScope,
+            28 => Instruction::PopScope,
+            29 => Instruction::EnterLoop {
+                exit: self.address()?,
+                next: self.address()?,
+            },
+            30 => Instruction::ExitLoop,
+            31 => Instruction::Break,
+            32 => Instruction::Continue,
+            33 => Instruction::Iterate,
+            34 => Instruction::IterateNext {
+                variable: self.u32()?,
+                exit: self.address()?,
+            },
+            35 => Instruction::EndIteration,
+            36 => Instruction::MatchArm {
+                pattern: self.u32()?,
+                next: self.address()?,
+            },
+            37 => Instruction::NoMatch,
+            opcode => bail!("invalid opcode: {}", opcode),
+        };
+        Ok(instruction)
+    }
+
+    fn address(&mut self) -> Result<Address> {
+        self.u32()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler;
+    use crate::error::RuntimeError;
+    use crate::lexer::Lexer;
+    use crate::object::Object;
+    use crate::parser::Parser;
+    use crate::vm::Vm;
+
+    fn compile(input: &str) -> Bytecode {
+        let program = Parser::new(Lexer::new(input)).parse_program().unwrap();
+        compiler::compile(&program).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let input = r#"
+            let add = fn(a, b = 2, ...rest) { a + b };
+            let mut total = 0;
+            for (x in [1, 2, 3]) { total += add(x, b: 10); }
+            let { "k": [first, ..._], "flag": true } = { "k": [1, 2], "flag": true };
+            match total { -1 => "negative", n if n > 30 => "big", _ => "small" };
+            add(...[first, 5]) + total
+        "#;
+        let bytecode = compile(input);
+        let decoded = decode(&encode(&bytecode)).unwrap();
+        assert_eq!(decoded, bytecode);
+        assert_eq!(Vm::new().run(&decoded).unwrap(), Object::Integer(42));
+
+        // Names that decoding adds to the symbol table count against the memory limit.
+        let mut bytes = encode(&compile("let decoded_name = 42; decoded_name"));
+        while let Some(at) = bytes
+            .windows(b"decoded_name".len())
+            .position(|window| window == b"decoded_name")
+        {
+            bytes[at..at + b"decoded_name".len()].copy_from_slice(b"decoded_NAME");
+        }
+        let decoded = decode(&bytes).unwrap();
+        assert!(decoded.names_size > "decoded_NAME".len());
+        let err = Vm::new().with_max_memory(64).run(&decoded).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::OutOfMemory { limit: 64 })
+        );
+        let decoded = decode(&bytes).unwrap();
+        assert_eq!(decoded.names_size, 0);
+        let result = Vm::new().with_max_memory(1024).run(&decoded).unwrap();
+        assert_eq!(result, Object::Integer(42));
+    }
+
+    #[test]
+    fn test_decode_error() {
+        let bytes = encode(&compile("let f = fn(x) { x }; f(1)"));
+        let mut wrong_version = bytes.clone();
+        wrong_version[4] = 9;
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        let tests = [
+            (b"#!/bin/monkey".to_vec(), "not a Monkey bytecode file"),
+            (
+                wrong_version,
+                "unsupported bytecode version: 9 (expected 2)",
+            ),
+            (
+                bytes[..bytes.len() - 1].to_vec(),
+                "unexpected end of bytecode file",
+            ),
+            (trailing, "unexpected data at the end of the bytecode file"),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(decode(&input).unwrap_err().to_string(), expected);
+        }
+
+        // Truncated and corrupted files fail without panicking.
+        for end in 0..bytes.len() {
+            assert!(decode(&bytes[..end]).is_err());
+        }
+        for at in 0..bytes.len() {
+            let mut corrupted = bytes.clone();
+            corrupted[at] ^= 0xff;
+            if let Ok(bytecode) = decode(&corrupted) {
+                let _ = Vm::new().with_max_steps(1000).run(&bytecode);
+            }
+        }
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let bytecode = compile("let f = fn(x) { x + 1 };\nf(2)");
+        let expected = r#"== function 0: <program> ==
+0000          Closure 2                  ; function 1
+0001     1:5  Let 3 let                  ; f
+0002          GetName 4                  ; f
+0003          CheckCallable
+0004          Constant 5                 ; 2
+0005     2:1  Call 6                     ; f/1
+0006          Return
+
+== function 1: f ==
+0000          ArgumentOrJump 0 1
+0001          BindParameter 0
+0002          EnterBody
+0003          GetName 0                  ; x
+0004          Constant 1                 ; 1
+0005          Infix +
+0006          Return
+"#;
+        assert_eq!(bytecode.disassemble(), expected);
+    }
+}
@@ -0,0 +1,267 @@
+//! A tracing collector for environments.
+//!
+//! Environments are shared by reference counting, which never frees a closure stored in the
+//! environment it captures, since each keeps the other alive. Both engines allocate their
+//! environments with `alloc`, which tracks them on a heap of the current thread, and `collect`
+//! frees the tracked environments that are only reachable from each other.
+//!
+//! The collector needs no list of roots. An environment with more references than the tracked
+//! environments hold to it is referenced from elsewhere, e.g. by a running evaluator or by the
+//! embedder, and is a root. Marking follows the environments that roots hold through their
+//! bindings and enclosing scopes, including the arrays, hashes and functions in the bindings.
+//! The unmarked environments are swept by clearing their bindings, which breaks their cycles.
+
+use crate::object::{Env, Environment};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+/// The fewest allocations between two automatic collections
+const MIN_THRESHOLD: usize = 4096;
+
+/// What the collector of the current thread has done so far
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct GcStats {
+    /// How many collections have run, automatically or when asked for
+    pub collections: u64,
+    /// How many environments have been allocated
+    pub allocated: u64,
+    /// How many environments the collector freed, all of which reference counting would have leaked
+    pub freed: u64,
+    /// How many environments were alive after the last collection
+    pub live: usize,
+}
+
+struct Heap {
+    environments: Vec<Weak<RefCell<Environment>>>,
+    // A collection runs once this many environments are tracked
+    threshold: usize,
+    stats: GcStats,
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = const {
+        RefCell::new(Heap {
+            environments: Vec::new(),
+            threshold: MIN_THRESHOLD,
+            stats: GcStats {
+                collections: 0,
+                allocated: 0,
+                freed: 0,
+                live: 0,
+            },
+        })
+    };
+}
+
+/// Moves the environment to the heap, collecting garbage first if enough has been allocated since the last collection
+pub fn alloc(environment: Environment) -> Env {
+    let env = Rc::new(RefCell::new(environment));
+    let due = HEAP.with_borrow_mut(|heap| {
+        heap.environments.push(Rc::downgrade(&env));
+        heap.stats.allocated += 1;
+        heap.environments.len() >= heap.threshold
+    });
+    if due {
+        collect();
+    }
+    env
+}
+
+/// Frees the environments that nothing but other environments can reach, and returns how many it freed
+pub fn collect() -> usize {
+    let environments = HEAP.with_borrow_mut(|heap| {
+        heap.environments.retain(|env| env.strong_count() > 0);
+        heap.environments
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect::<Vec<Env>>()
+    });
+    let index = environments
+        .iter()
+        .enumerate()
+        .map(|(i, env)| (Rc::as_ptr(env), i))
+        .collect::<HashMap<_, _>>();
+    let tracked = |env: &Env| index.get(&Rc::as_ptr(env)).copied();
+
+    // Environments that are borrowed are in use, and their references cannot be followed.
+    let mut marked = vec![false; environments.len()];
+    let mut internal = vec![0; environments.len()];
+    for (i, env) in environments.iter().enumerate() {
+        match env.try_borrow() {
+            Ok(env) => env.for_each_env(&mut |inner| {
+                if let Some(j) = tracked(inner) {
+                    internal[j] += 1;
+                }
+            }),
+            Err(_) => marked[i] = true,
+        }
+    }
+    // One reference to each environment is the one taken above.
+    for (i, env) in environments.iter().enumerate() {
+        if Rc::strong_count(env) - 1 > internal[i] {
+            marked[i] = true;
+        }
+    }
+
+    let mut pending = (0..environments.len())
+        .filter(|&i| marked[i])
+        .collect::<Vec<usize>>();
+    while let Some(i) = pending.pop() {
+        let Ok(env) = environments[i].try_borrow() else {
+            continue;
+        };
+        env.for_each_env(&mut |inner| {
+            if let Some(j) = tracked(inner).filter(|&j| !marked[j]) {
+                marked[j] = true;
+                pending.push(j);
+            }
+        });
+    }
+
+    // The bindings are dropped after the heap is updated, and they free the environments they hold.
+    let garbage = environments
+        .iter()
+        .zip(&marked)
+        .filter(|(_, &marked)| !marked)
+        .filter_map(|(env, _)| {
+            env.try_borrow_mut()
+                .ok()
+                .map(|mut env| std::mem::take(&mut *env))
+        })
+        .collect::<Vec<Environment>>();
+    let freed = garbage.len();
+    HEAP.with_borrow_mut(|heap| {
+        let live = environments.len() - freed;
+        heap.stats.collections += 1;
+        heap.stats.freed += freed as u64;
+        heap.stats.live = live;
+        heap.threshold = MIN_THRESHOLD.max(2 * live);
+    });
+    drop(environments);
+    drop(garbage);
+    freed
+}
+
+/// Returns the statistics of the collector of the current thread
+pub fn stats() -> GcStats {
+    HEAP.with_borrow(|heap| heap.stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builtin::Builtin;
+    use crate::compiler;
+    use crate::evaluator::eval_program;
+    use crate::lexer::Lexer;
+    use crate::object::Object;
+    use crate::parser::Parser;
+    use crate::symbol::Symbol;
+    use crate::vm::Vm;
+
+    fn run(input: &str) -> [Object; 2] {
+        let program = Parser::new(Lexer::new(input)).parse_program().unwrap();
+        let bytecode = compiler::compile(&program).unwrap();
+        // The garbage left by earlier programs on this thread is freed first.
+        collect();
+        let evaluated = eval_program(&program).unwrap();
+        collect();
+        [evaluated, Vm::new().run(&bytecode).unwrap()]
+    }
+
+    #[test]
+    fn test_collect() {
+        // Each call leaves behind a scope that holds a closure over itself.
+        let input = "
+let make = fn() { let f = fn() { f }; };
+make(); make(); make();
+gc()
+";
+        assert_eq!(run(input), [Object::Integer(3), Object::Integer(3)]);
+
+        // Cycles that the running program can still reach stay.
+        let tests = [
+            "let f = fn() { f }; gc()",
+            "let pair = {\"f\": fn() { pair }}; gc()",
+            "let outer = fn() { let inner = fn() { inner }; fn() { inner } }; let g = outer(); gc()",
+        ];
+        for input in tests {
+            assert_eq!(
+                run(input),
+                [Object::Integer(0), Object::Integer(0)],
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_builtin() {
+        let tests = [
+            ("let gc = 1; gc", Object::Integer(1)),
+            ("let f = fn() { gc() }; f()", Object::Integer(0)),
+            ("[gc][0]", Object::Builtin(Builtin::Gc)),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(run(input), [expected.clone(), expected], "{}", input);
+        }
+
+        let tests = [
+            (
+                "gc(1)",
+                "wrong number of arguments: expected=0, got=1\n    at gc (<input>:1:1)",
+            ),
+            (
+                "gc(force: true)",
+                "unknown parameter: force\n    at gc (<input>:1:1)",
+            ),
+            ("gc = 1", "cannot assign to undefined variable: gc"),
+        ];
+        for (input, expected) in tests {
+            let program = Parser::new(Lexer::new(input)).parse_program().unwrap();
+            let evaluated = eval_program(&program).unwrap_err();
+            assert_eq!(evaluated.to_string(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_automatic_collection() {
+        collect();
+        let before = stats();
+        run("let make = fn() { let f = fn() { f }; }; for (i in 0..10000) { make(); }");
+        let after = stats();
+        assert!(after.collections > before.collections + 2);
+        assert!(after.freed >= before.freed + 10000);
+    }
+
+    #[test]
+    fn test_stats() {
+        collect();
+        let before = stats();
+        // The top-level scope of a finished program holds a function that refers to it.
+        let env = alloc(Environment::new());
+        let function = Object::FunctionObject {
+            function: Rc::new(crate::ast::FunctionLiteral {
+                name: None,
+                parameters: Vec::new(),
+                rest: None,
+                body: crate::ast::Statement::EmptyStatement,
+            }),
+            env: Rc::clone(&env),
+        };
+        env.borrow_mut().set(Symbol::intern("f"), function);
+        assert_eq!(collect(), 0);
+        let weak = Rc::downgrade(&env);
+        drop(env);
+        assert!(weak.upgrade().is_some());
+        assert_eq!(collect(), 1);
+        assert!(weak.upgrade().is_none());
+
+        let after = stats();
+        assert_eq!(after.allocated, before.allocated + 1);
+        assert_eq!(after.collections, before.collections + 2);
+        assert_eq!(after.freed, before.freed + 1);
+        assert_eq!(after.live, 0);
+    }
+}
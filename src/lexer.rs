@@ -1,4 +1,6 @@
-use crate::token::{Token, TokenType};
+use crate::token::{Position, Token, TokenType};
+use std::borrow::Cow;
+use std::io::{self, BufRead, BufReader, Read};
 
 #[derive(Default, Debug, Clone, Copy)]
 pub struct Lexer<'a> {
@@ -6,27 +8,55 @@ pub struct Lexer<'a> {
     position: usize,
     read_position: usize,
     symbol: Option<char>, // Changed type to Option<char>
+    line: usize,
+    column: usize,
+    token_position: Position,
 }
 
 /// Returns true if the character can be used as an identifier
 fn can_use_as_ident(c: char) -> bool {
-    !(c.is_ascii_digit() || c.is_whitespace() || c.is_control() || c.is_ascii_punctuation())
+    c == '_'
+        || !(c.is_ascii_digit() || c.is_whitespace() || c.is_control() || c.is_ascii_punctuation())
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(input: &'a str) -> Lexer<'a> {
+        Lexer::resume(input, Position { line: 1, column: 1 })
+    }
+
+    /// Lexes `input` as the part of a larger source that starts at `start`
+    fn resume(input: &'a str, start: Position) -> Lexer<'a> {
         let mut l = Lexer {
             input,
+            line: start.line,
+            column: start.column - 1,
             ..Default::default()
         };
         l.read_symbol();
         l
     }
 
+    /// Returns the byte offset and the position of the first character not yet lexed
+    fn rest(&self) -> (usize, Position) {
+        let position = Position {
+            line: self.line,
+            column: self.column,
+        };
+        (self.position.min(self.input.len()), position)
+    }
+
     /// Reads the next character and updates the symbol
-    fn read_symbol(&mut self) -> () {
+    fn read_symbol(&mut self) {
+        if self.symbol == Some('\n') {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
         self.position = self.read_position;
-        match self.input[self.read_position..].char_indices().next() {
+        // The read position moves past the end of the input once it is exhausted
+        let rest = self.input.get(self.read_position..).unwrap_or_default();
+        match rest.char_indices().next() {
             Some((u, c)) => {
                 self.read_position += u + c.len_utf8();
                 self.symbol = Some(c);
@@ -39,7 +69,7 @@ impl<'a> Lexer<'a> {
     }
 
     /// Reads the next identifier
-    fn read_identifier(&mut self) -> String {
+    fn read_identifier(&mut self) -> &'a str {
         let position = self.position;
         while let Some(symbol) = self.peak_symbol() {
             if can_use_as_ident(symbol) {
@@ -48,24 +78,60 @@ impl<'a> Lexer<'a> {
                 break;
             }
         }
-        self.input[position..self.read_position].to_string()
+        &self.input[position..self.read_position]
     }
 
     /// Reads the next number
-    fn read_number(&mut self) -> String {
+    fn read_number(&mut self) -> &'a str {
         let position = self.position;
         while let Some(symbol) = self.peak_symbol() {
-            if symbol.is_numeric() {
+            if symbol.is_ascii_digit() {
                 self.read_symbol();
             } else {
                 break;
             }
         }
-        self.input[position..self.read_position].to_string()
+        &self.input[position..self.read_position]
+    }
+
+    /// Reads the string literal starting at the current double quote.
+    /// Returns None if the input ends before the closing quote.
+    fn read_string(&mut self) -> Option<Cow<'a, str>> {
+        let start = self.read_position;
+        // The contents are borrowed from the input until an escape sequence makes them differ.
+        let mut unescaped: Option<String> = None;
+        loop {
+            self.read_symbol();
+            match self.symbol {
+                Some('"') => {
+                    return Some(match unescaped {
+                        Some(literal) => Cow::Owned(literal),
+                        None => Cow::Borrowed(&self.input[start..self.position]),
+                    })
+                }
+                Some('\\') => {
+                    let literal = unescaped
+                        .get_or_insert_with(|| self.input[start..self.position].to_string());
+                    self.read_symbol();
+                    match self.symbol {
+                        Some('n') => literal.push('\n'),
+                        Some('t') => literal.push('\t'),
+                        Some(c) => literal.push(c),
+                        None => return None,
+                    }
+                }
+                Some(c) => {
+                    if let Some(literal) = &mut unescaped {
+                        literal.push(c);
+                    }
+                }
+                None => return None,
+            }
+        }
     }
 
     /// Skips the whitespace
-    fn skip_whitespace(&mut self) -> () {
+    fn skip_whitespace(&mut self) {
         while let Some(symbol) = self.symbol {
             if symbol.is_whitespace() {
                 self.read_symbol();
@@ -77,115 +143,215 @@ impl<'a> Lexer<'a> {
 
     /// Returns the next character without updating the symbol
     fn peak_symbol(&self) -> Option<char> {
-        self.input[self.read_position..]
-            .char_indices()
-            .next()
-            .map(|(_, c)| c)
+        self.input
+            .get(self.read_position..)
+            .and_then(|rest| rest.chars().next())
+    }
+
+    /// Returns the position of the token most recently returned by `next_token`
+    pub fn position(&self) -> Position {
+        self.token_position
     }
 
     /// Returns the next token
-    pub fn next_token(&mut self) -> Token {
+    pub fn next_token(&mut self) -> Token<'a> {
         self.skip_whitespace();
-        let token: Token = match self.symbol {
+        self.token_position = Position {
+            line: self.line,
+            column: self.column,
+        };
+        let token = match self.symbol {
             Some('=') => {
                 if self.peak_symbol() == Some('=') {
                     self.read_symbol();
                     Token {
                         token_type: TokenType::EQ,
-                        literal: "==".to_string(),
+                        literal: "==".into(),
+                    }
+                } else if self.peak_symbol() == Some('>') {
+                    self.read_symbol();
+                    Token {
+                        token_type: TokenType::FAT_ARROW,
+                        literal: "=>".into(),
                     }
                 } else {
                     Token {
                         token_type: TokenType::ASSIGN,
-                        literal: "=".to_string(),
+                        literal: "=".into(),
+                    }
+                }
+            }
+            Some('+') => {
+                if self.peak_symbol() == Some('=') {
+                    self.read_symbol();
+                    Token {
+                        token_type: TokenType::PLUS_ASSIGN,
+                        literal: "+=".into(),
+                    }
+                } else {
+                    Token {
+                        token_type: TokenType::PLUS,
+                        literal: "+".into(),
+                    }
+                }
+            }
+            Some('-') => {
+                if self.peak_symbol() == Some('=') {
+                    self.read_symbol();
+                    Token {
+                        token_type: TokenType::MINUS_ASSIGN,
+                        literal: "-=".into(),
+                    }
+                } else {
+                    Token {
+                        token_type: TokenType::MINUS,
+                        literal: "-".into(),
                     }
                 }
             }
-            Some('+') => Token {
-                token_type: TokenType::PLUS,
-                literal: "+".to_string(),
-            },
-            Some('-') => Token {
-                token_type: TokenType::MINUS,
-                literal: "-".to_string(),
-            },
             Some('!') => {
                 if self.peak_symbol() == Some('=') {
                     self.read_symbol();
                     Token {
                         token_type: TokenType::NOT_EQ,
-                        literal: "!=".to_string(),
+                        literal: "!=".into(),
                     }
                 } else {
                     Token {
                         token_type: TokenType::BANG,
-                        literal: "!".to_string(),
+                        literal: "!".into(),
+                    }
+                }
+            }
+            Some('*') => {
+                if self.peak_symbol() == Some('=') {
+                    self.read_symbol();
+                    Token {
+                        token_type: TokenType::ASTERISK_ASSIGN,
+                        literal: "*=".into(),
+                    }
+                } else {
+                    Token {
+                        token_type: TokenType::ASTERISK,
+                        literal: "*".into(),
+                    }
+                }
+            }
+            Some('/') => {
+                if self.peak_symbol() == Some('=') {
+                    self.read_symbol();
+                    Token {
+                        token_type: TokenType::SLASH_ASSIGN,
+                        literal: "/=".into(),
+                    }
+                } else {
+                    Token {
+                        token_type: TokenType::SLASH,
+                        literal: "/".into(),
                     }
                 }
             }
-            Some('*') => Token {
-                token_type: TokenType::ASTERISK,
-                literal: "*".to_string(),
-            },
-            Some('/') => Token {
-                token_type: TokenType::SLASH,
-                literal: "/".to_string(),
-            },
             Some('<') => Token {
                 token_type: TokenType::LT,
-                literal: "<".to_string(),
+                literal: "<".into(),
             },
             Some('>') => Token {
                 token_type: TokenType::GT,
-                literal: ">".to_string(),
+                literal: ">".into(),
             },
             Some(',') => Token {
                 token_type: TokenType::COMMA,
-                literal: ",".to_string(),
+                literal: ",".into(),
             },
+            Some(':') => Token {
+                token_type: TokenType::COLON,
+                literal: ":".into(),
+            },
+            Some('.') if self.peak_symbol() == Some('.') => {
+                self.read_symbol();
+                if self.peak_symbol() == Some('=') {
+                    self.read_symbol();
+                    Token {
+                        token_type: TokenType::DOT_DOT_EQ,
+                        literal: "..=".into(),
+                    }
+                } else if self.peak_symbol() == Some('.') {
+                    self.read_symbol();
+                    Token {
+                        token_type: TokenType::ELLIPSIS,
+                        literal: "...".into(),
+                    }
+                } else {
+                    Token {
+                        token_type: TokenType::DOT_DOT,
+                        literal: "..".into(),
+                    }
+                }
+            }
             Some(';') => Token {
                 token_type: TokenType::SEMICOLON,
-                literal: ";".to_string(),
+                literal: ";".into(),
             },
             Some('(') => Token {
                 token_type: TokenType::LPAREN,
-                literal: "(".to_string(),
+                literal: "(".into(),
             },
             Some(')') => Token {
                 token_type: TokenType::RPAREN,
-                literal: ")".to_string(),
+                literal: ")".into(),
             },
             Some('{') => Token {
                 token_type: TokenType::LBRACE,
-                literal: "{".to_string(),
+                literal: "{".into(),
             },
             Some('}') => Token {
                 token_type: TokenType::RBRACE,
-                literal: "}".to_string(),
-            },
+                literal: "}".into(),
+            },
+            Some('[') => Token {
+                token_type: TokenType::LBRACKET,
+                literal: "[".into(),
+            },
+            Some(']') => Token {
+                token_type: TokenType::RBRACKET,
+                literal: "]".into(),
+            },
+            Some('"') => {
+                let start = self.position;
+                match self.read_string() {
+                    Some(literal) => Token {
+                        token_type: TokenType::STRING,
+                        literal,
+                    },
+                    None => Token {
+                        token_type: TokenType::ILLEGAL,
+                        literal: self.input[start..].into(),
+                    },
+                }
+            }
             Some(c) if c.is_ascii_digit() => {
                 let literal = self.read_number();
                 Token {
                     token_type: TokenType::INT,
-                    literal: literal,
+                    literal: literal.into(),
                 }
             }
             Some(c) if can_use_as_ident(c) => {
                 let literal = self.read_identifier();
                 Token {
-                    token_type: TokenType::lookup_ident(&literal),
-                    literal: literal,
+                    token_type: TokenType::lookup_ident(literal),
+                    literal: literal.into(),
                 }
             }
             None => {
                 return Token {
                     token_type: TokenType::EOF,
-                    literal: "".to_string(),
+                    literal: "".into(),
                 }
             }
             _ => Token {
                 token_type: TokenType::ILLEGAL,
-                literal: self.input[self.position..self.read_position].to_string(), // Updated symbol handling
+                literal: self.input[self.position..self.read_position].into(),
             },
         };
         self.read_symbol();
@@ -193,11 +359,172 @@ impl<'a> Lexer<'a> {
     }
 }
 
+/// Where the parser takes its tokens from
+pub trait TokenSource<'a> {
+    /// Returns the next token, or EOF once the input is exhausted
+    fn next_token(&mut self) -> Token<'a>;
+
+    /// Returns the position of the token most recently returned by `next_token`
+    fn position(&self) -> Position;
+
+    /// Takes the error that ended the input early, if reading it failed
+    fn take_error(&mut self) -> Option<io::Error> {
+        None
+    }
+}
+
+impl<'a> TokenSource<'a> for Lexer<'a> {
+    fn next_token(&mut self) -> Token<'a> {
+        Lexer::next_token(self)
+    }
+
+    fn position(&self) -> Position {
+        Lexer::position(self)
+    }
+}
+
+/// A lexer that reads its input as tokens are asked for, and produces the same tokens
+/// and positions as `Lexer` does for the whole input.
+///
+/// The input is read a line at a time. Only string literals span lines, so a token is complete
+/// once the line it ends on has been read. The lines before the current token are dropped.
+/// Whitespace is dropped as it is read, and a string literal is scanned for its closing quote
+/// one line at a time before it is lexed, so that neither is lexed again for every line.
+/// If reading fails, the input ends there and `take_error` returns the error.
+pub struct StreamLexer<R> {
+    reader: R,
+    buffer: String,
+    // The byte offset in the buffer, and the position in the input, of the first character not yet lexed
+    offset: usize,
+    next_position: Position,
+    token_position: Position,
+    exhausted: bool,
+    error: Option<io::Error>,
+}
+
+impl<R: Read> StreamLexer<BufReader<R>> {
+    pub fn from_reader(reader: R) -> Self {
+        StreamLexer::new(BufReader::new(reader))
+    }
+}
+
+impl<R: BufRead> StreamLexer<R> {
+    pub fn new(reader: R) -> Self {
+        StreamLexer {
+            reader,
+            buffer: String::new(),
+            offset: 0,
+            next_position: Position { line: 1, column: 1 },
+            token_position: Position::default(),
+            exhausted: false,
+            error: None,
+        }
+    }
+
+    /// Returns the next token
+    pub fn next_token(&mut self) -> Token<'static> {
+        self.skip_whitespace();
+        if self.buffer[self.offset..].starts_with('"') {
+            self.read_string();
+        }
+        loop {
+            let mut lexer = Lexer::resume(&self.buffer[self.offset..], self.next_position);
+            let token = lexer.next_token();
+            // A token that reaches the end of what has been read may continue on the next line.
+            if lexer.symbol.is_none() && !self.exhausted {
+                self.read_line();
+                continue;
+            }
+            let (consumed, next_position) = lexer.rest();
+            self.token_position = lexer.position();
+            self.offset += consumed;
+            self.next_position = next_position;
+            return token.into_owned();
+        }
+    }
+
+    /// Returns the position of the token most recently returned by `next_token`
+    pub fn position(&self) -> Position {
+        self.token_position
+    }
+
+    /// Takes the error that ended the input early, if reading it failed
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    /// Drops the whitespace before the next token, reading lines until it is found or the input ends
+    fn skip_whitespace(&mut self) {
+        loop {
+            let mut lexer = Lexer::resume(&self.buffer[self.offset..], self.next_position);
+            lexer.skip_whitespace();
+            let (consumed, next_position) = lexer.rest();
+            self.offset += consumed;
+            self.next_position = next_position;
+            if lexer.symbol.is_some() || self.exhausted {
+                return;
+            }
+            self.read_line();
+        }
+    }
+
+    /// Reads lines until the string literal at the offset is closed or the input ends.
+    /// Each line is scanned once, going on from where the scan of the previous one stopped.
+    fn read_string(&mut self) {
+        // The scanned length counts from the offset, which reading lines does not move.
+        let mut scanned = '"'.len_utf8();
+        let mut escaped = false;
+        loop {
+            let rest = &self.buffer[self.offset + scanned..];
+            for c in rest.chars() {
+                match c {
+                    _ if escaped => escaped = false,
+                    '\\' => escaped = true,
+                    '"' => return,
+                    _ => {}
+                }
+            }
+            scanned += rest.len();
+            if self.exhausted {
+                return;
+            }
+            self.read_line();
+        }
+    }
+
+    fn read_line(&mut self) {
+        self.buffer.drain(..self.offset);
+        self.offset = 0;
+        match self.reader.read_line(&mut self.buffer) {
+            Ok(0) => self.exhausted = true,
+            Ok(_) => {}
+            Err(e) => {
+                self.exhausted = true;
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+impl<R: BufRead> TokenSource<'static> for StreamLexer<R> {
+    fn next_token(&mut self) -> Token<'static> {
+        StreamLexer::next_token(self)
+    }
+
+    fn position(&self) -> Position {
+        StreamLexer::position(self)
+    }
+
+    fn take_error(&mut self) -> Option<io::Error> {
+        StreamLexer::take_error(self)
+    }
+}
+
 impl<'a> Iterator for Lexer<'a> {
-    type Item = Token;
+    type Item = Token<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let token: Token = self.next_token();
+        let token = self.next_token();
         if token.token_type == TokenType::EOF {
             None
         } else {
@@ -207,10 +534,12 @@ impl<'a> Iterator for Lexer<'a> {
 }
 
 #[cfg(test)]
+#[allow(clippy::useless_vec)]
 mod test {
-    use crate::token::{Token, TokenType};
+    use crate::token::{Position, Token, TokenType};
+    use std::borrow::Cow;
 
-    use super::Lexer;
+    use super::{Lexer, StreamLexer};
 
     #[test]
     fn test_next_token1() {
@@ -218,39 +547,39 @@ mod test {
         let tests = vec![
             Token {
                 token_type: TokenType::ASSIGN,
-                literal: "=".to_string(),
+                literal: "=".into(),
             },
             Token {
                 token_type: TokenType::PLUS,
-                literal: "+".to_string(),
+                literal: "+".into(),
             },
             Token {
                 token_type: TokenType::LPAREN,
-                literal: "(".to_string(),
+                literal: "(".into(),
             },
             Token {
                 token_type: TokenType::RPAREN,
-                literal: ")".to_string(),
+                literal: ")".into(),
             },
             Token {
                 token_type: TokenType::LBRACE,
-                literal: "{".to_string(),
+                literal: "{".into(),
             },
             Token {
                 token_type: TokenType::RBRACE,
-                literal: "}".to_string(),
+                literal: "}".into(),
             },
             Token {
                 token_type: TokenType::COMMA,
-                literal: ",".to_string(),
+                literal: ",".into(),
             },
             Token {
                 token_type: TokenType::SEMICOLON,
-                literal: ";".to_string(),
+                literal: ";".into(),
             },
             Token {
                 token_type: TokenType::EOF,
-                literal: "".to_string(),
+                literal: "".into(),
             },
         ];
 
@@ -287,299 +616,299 @@ mod test {
         let tests = vec![
             Token {
                 token_type: TokenType::LET,
-                literal: "let".to_string(),
+                literal: "let".into(),
             },
             Token {
                 token_type: TokenType::IDENT,
-                literal: "five".to_string(),
+                literal: "five".into(),
             },
             Token {
                 token_type: TokenType::ASSIGN,
-                literal: "=".to_string(),
+                literal: "=".into(),
             },
             Token {
                 token_type: TokenType::INT,
-                literal: "5".to_string(),
+                literal: "5".into(),
             },
             Token {
                 token_type: TokenType::SEMICOLON,
-                literal: ";".to_string(),
+                literal: ";".into(),
             },
             Token {
                 token_type: TokenType::LET,
-                literal: "let".to_string(),
+                literal: "let".into(),
             },
             Token {
                 token_type: TokenType::IDENT,
-                literal: "ten".to_string(),
+                literal: "ten".into(),
             },
             Token {
                 token_type: TokenType::ASSIGN,
-                literal: "=".to_string(),
+                literal: "=".into(),
             },
             Token {
                 token_type: TokenType::INT,
-                literal: "10".to_string(),
+                literal: "10".into(),
             },
             Token {
                 token_type: TokenType::SEMICOLON,
-                literal: ";".to_string(),
+                literal: ";".into(),
             },
             Token {
                 token_type: TokenType::LET,
-                literal: "let".to_string(),
+                literal: "let".into(),
             },
             Token {
                 token_type: TokenType::IDENT,
-                literal: "add".to_string(),
+                literal: "add".into(),
             },
             Token {
                 token_type: TokenType::ASSIGN,
-                literal: "=".to_string(),
+                literal: "=".into(),
             },
             Token {
                 token_type: TokenType::FUNCTION,
-                literal: "fn".to_string(),
+                literal: "fn".into(),
             },
             Token {
                 token_type: TokenType::LPAREN,
-                literal: "(".to_string(),
+                literal: "(".into(),
             },
             Token {
                 token_type: TokenType::IDENT,
-                literal: "x".to_string(),
+                literal: "x".into(),
             },
             Token {
                 token_type: TokenType::COMMA,
-                literal: ",".to_string(),
+                literal: ",".into(),
             },
             Token {
                 token_type: TokenType::IDENT,
-                literal: "y".to_string(),
+                literal: "y".into(),
             },
             Token {
                 token_type: TokenType::RPAREN,
-                literal: ")".to_string(),
+                literal: ")".into(),
             },
             Token {
                 token_type: TokenType::LBRACE,
-                literal: "{".to_string(),
+                literal: "{".into(),
             },
             Token {
                 token_type: TokenType::IDENT,
-                literal: "x".to_string(),
+                literal: "x".into(),
             },
             Token {
                 token_type: TokenType::PLUS,
-                literal: "+".to_string(),
+                literal: "+".into(),
             },
             Token {
                 token_type: TokenType::IDENT,
-                literal: "y".to_string(),
+                literal: "y".into(),
             },
             Token {
                 token_type: TokenType::SEMICOLON,
-                literal: ";".to_string(),
+                literal: ";".into(),
             },
             Token {
                 token_type: TokenType::RBRACE,
-                literal: "}".to_string(),
+                literal: "}".into(),
             },
             Token {
                 token_type: TokenType::SEMICOLON,
-                literal: ";".to_string(),
+                literal: ";".into(),
             },
             Token {
                 token_type: TokenType::LET,
-                literal: "let".to_string(),
+                literal: "let".into(),
             },
             Token {
                 token_type: TokenType::IDENT,
-                literal: "result".to_string(),
+                literal: "result".into(),
             },
             Token {
                 token_type: TokenType::ASSIGN,
-                literal: "=".to_string(),
+                literal: "=".into(),
             },
             Token {
                 token_type: TokenType::IDENT,
-                literal: "add".to_string(),
+                literal: "add".into(),
             },
             Token {
                 token_type: TokenType::LPAREN,
-                literal: "(".to_string(),
+                literal: "(".into(),
             },
             Token {
                 token_type: TokenType::IDENT,
-                literal: "five".to_string(),
+                literal: "five".into(),
             },
             Token {
                 token_type: TokenType::COMMA,
-                literal: ",".to_string(),
+                literal: ",".into(),
             },
             Token {
                 token_type: TokenType::IDENT,
-                literal: "ten".to_string(),
+                literal: "ten".into(),
             },
             Token {
                 token_type: TokenType::RPAREN,
-                literal: ")".to_string(),
+                literal: ")".into(),
             },
             Token {
                 token_type: TokenType::SEMICOLON,
-                literal: ";".to_string(),
+                literal: ";".into(),
             },
             Token {
                 token_type: TokenType::BANG,
-                literal: "!".to_string(),
+                literal: "!".into(),
             },
             Token {
                 token_type: TokenType::MINUS,
-                literal: "-".to_string(),
+                literal: "-".into(),
             },
             Token {
                 token_type: TokenType::SLASH,
-                literal: "/".to_string(),
+                literal: "/".into(),
             },
             Token {
                 token_type: TokenType::ASTERISK,
-                literal: "*".to_string(),
+                literal: "*".into(),
             },
             Token {
                 token_type: TokenType::INT,
-                literal: "5".to_string(),
+                literal: "5".into(),
             },
             Token {
                 token_type: TokenType::SEMICOLON,
-                literal: ";".to_string(),
+                literal: ";".into(),
             },
             Token {
                 token_type: TokenType::INT,
-                literal: "5".to_string(),
+                literal: "5".into(),
             },
             Token {
                 token_type: TokenType::LT,
-                literal: "<".to_string(),
+                literal: "<".into(),
             },
             Token {
                 token_type: TokenType::INT,
-                literal: "10".to_string(),
+                literal: "10".into(),
             },
             Token {
                 token_type: TokenType::GT,
-                literal: ">".to_string(),
+                literal: ">".into(),
             },
             Token {
                 token_type: TokenType::INT,
-                literal: "5".to_string(),
+                literal: "5".into(),
             },
             Token {
                 token_type: TokenType::SEMICOLON,
-                literal: ";".to_string(),
+                literal: ";".into(),
             },
             Token {
                 token_type: TokenType::IF,
-                literal: "if".to_string(),
+                literal: "if".into(),
             },
             Token {
                 token_type: TokenType::LPAREN,
-                literal: "(".to_string(),
+                literal: "(".into(),
             },
             Token {
                 token_type: TokenType::INT,
-                literal: "5".to_string(),
+                literal: "5".into(),
             },
             Token {
                 token_type: TokenType::LT,
-                literal: "<".to_string(),
+                literal: "<".into(),
             },
             Token {
                 token_type: TokenType::INT,
-                literal: "10".to_string(),
+                literal: "10".into(),
             },
             Token {
                 token_type: TokenType::RPAREN,
-                literal: ")".to_string(),
+                literal: ")".into(),
             },
             Token {
                 token_type: TokenType::LBRACE,
-                literal: "{".to_string(),
+                literal: "{".into(),
             },
             Token {
                 token_type: TokenType::RETURN,
-                literal: "return".to_string(),
+                literal: "return".into(),
             },
             Token {
                 token_type: TokenType::TRUE,
-                literal: "true".to_string(),
+                literal: "true".into(),
             },
             Token {
                 token_type: TokenType::SEMICOLON,
-                literal: ";".to_string(),
+                literal: ";".into(),
             },
             Token {
                 token_type: TokenType::RBRACE,
-                literal: "}".to_string(),
+                literal: "}".into(),
             },
             Token {
                 token_type: TokenType::ELSE,
-                literal: "else".to_string(),
+                literal: "else".into(),
             },
             Token {
                 token_type: TokenType::LBRACE,
-                literal: "{".to_string(),
+                literal: "{".into(),
             },
             Token {
                 token_type: TokenType::RETURN,
-                literal: "return".to_string(),
+                literal: "return".into(),
             },
             Token {
                 token_type: TokenType::FALSE,
-                literal: "false".to_string(),
+                literal: "false".into(),
             },
             Token {
                 token_type: TokenType::SEMICOLON,
-                literal: ";".to_string(),
+                literal: ";".into(),
             },
             Token {
                 token_type: TokenType::RBRACE,
-                literal: "}".to_string(),
+                literal: "}".into(),
             },
             Token {
                 token_type: TokenType::INT,
-                literal: "10".to_string(),
+                literal: "10".into(),
             },
             Token {
                 token_type: TokenType::EQ,
-                literal: "==".to_string(),
+                literal: "==".into(),
             },
             Token {
                 token_type: TokenType::INT,
-                literal: "10".to_string(),
+                literal: "10".into(),
             },
             Token {
                 token_type: TokenType::SEMICOLON,
-                literal: ";".to_string(),
+                literal: ";".into(),
             },
             Token {
                 token_type: TokenType::INT,
-                literal: "10".to_string(),
+                literal: "10".into(),
             },
             Token {
                 token_type: TokenType::NOT_EQ,
-                literal: "!=".to_string(),
+                literal: "!=".into(),
             },
             Token {
                 token_type: TokenType::INT,
-                literal: "9".to_string(),
+                literal: "9".into(),
             },
             Token {
                 token_type: TokenType::SEMICOLON,
-                literal: ";".to_string(),
+                literal: ";".into(),
             },
             Token {
                 token_type: TokenType::EOF,
-                literal: "".to_string(),
+                literal: "".into(),
             },
         ];
 
@@ -596,23 +925,23 @@ mod test {
         let tests = vec![
             Token {
                 token_type: TokenType::IDENT,
-                literal: "漢字".to_string(),
+                literal: "漢字".into(),
             },
             Token {
                 token_type: TokenType::IDENT,
-                literal: "😄".to_string(),
+                literal: "😄".into(),
             },
             Token {
                 token_type: TokenType::IDENT,
-                literal: "＋".to_string(),
+                literal: "＋".into(),
             },
             Token {
                 token_type: TokenType::IDENT,
-                literal: "🇯🇵".to_string(),
+                literal: "🇯🇵".into(),
             },
             Token {
                 token_type: TokenType::ILLEGAL,
-                literal: "\u{001B}".to_string(),
+                literal: "\u{001B}".into(),
             },
         ];
         let mut l = Lexer::new(input);
@@ -622,69 +951,108 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_next_token4() {
+        let input = "x = 1; x += 2; x -= 3; x *= 4; x /= 5;";
+        let tests = vec![
+            (TokenType::IDENT, "x"),
+            (TokenType::ASSIGN, "="),
+            (TokenType::INT, "1"),
+            (TokenType::SEMICOLON, ";"),
+            (TokenType::IDENT, "x"),
+            (TokenType::PLUS_ASSIGN, "+="),
+            (TokenType::INT, "2"),
+            (TokenType::SEMICOLON, ";"),
+            (TokenType::IDENT, "x"),
+            (TokenType::MINUS_ASSIGN, "-="),
+            (TokenType::INT, "3"),
+            (TokenType::SEMICOLON, ";"),
+            (TokenType::IDENT, "x"),
+            (TokenType::ASTERISK_ASSIGN, "*="),
+            (TokenType::INT, "4"),
+            (TokenType::SEMICOLON, ";"),
+            (TokenType::IDENT, "x"),
+            (TokenType::SLASH_ASSIGN, "/="),
+            (TokenType::INT, "5"),
+            (TokenType::SEMICOLON, ";"),
+            (TokenType::EOF, ""),
+        ];
+        let mut l = Lexer::new(input);
+        for (token_type, literal) in tests {
+            let token: Token = l.next_token();
+            assert_eq!(
+                token,
+                Token {
+                    token_type,
+                    literal: literal.into(),
+                }
+            )
+        }
+    }
+
     #[test]
     fn test_iterator() {
         let input = "let x = 5; let y = 10; let foobar = 838383;";
         let tests = vec![
             Token {
                 token_type: TokenType::LET,
-                literal: "let".to_string(),
+                literal: "let".into(),
             },
             Token {
                 token_type: TokenType::IDENT,
-                literal: "x".to_string(),
+                literal: "x".into(),
             },
             Token {
                 token_type: TokenType::ASSIGN,
-                literal: "=".to_string(),
+                literal: "=".into(),
             },
             Token {
                 token_type: TokenType::INT,
-                literal: "5".to_string(),
+                literal: "5".into(),
             },
             Token {
                 token_type: TokenType::SEMICOLON,
-                literal: ";".to_string(),
+                literal: ";".into(),
             },
             Token {
                 token_type: TokenType::LET,
-                literal: "let".to_string(),
+                literal: "let".into(),
             },
             Token {
                 token_type: TokenType::IDENT,
-                literal: "y".to_string(),
+                literal: "y".into(),
             },
             Token {
                 token_type: TokenType::ASSIGN,
-                literal: "=".to_string(),
+                literal: "=".into(),
             },
             Token {
                 token_type: TokenType::INT,
-                literal: "10".to_string(),
+                literal: "10".into(),
             },
             Token {
                 token_type: TokenType::SEMICOLON,
-                literal: ";".to_string(),
+                literal: ";".into(),
             },
             Token {
                 token_type: TokenType::LET,
-                literal: "let".to_string(),
+                literal: "let".into(),
             },
             Token {
                 token_type: TokenType::IDENT,
-                literal: "foobar".to_string(),
+                literal: "foobar".into(),
             },
             Token {
                 token_type: TokenType::ASSIGN,
-                literal: "=".to_string(),
+                literal: "=".into(),
             },
             Token {
                 token_type: TokenType::INT,
-                literal: "838383".to_string(),
+                literal: "838383".into(),
             },
             Token {
                 token_type: TokenType::SEMICOLON,
-                literal: ";".to_string(),
+                literal: ";".into(),
             },
         ];
         let l = Lexer::new(input);
@@ -692,4 +1060,168 @@ mod test {
             assert_eq!(token, tests[i]);
         }
     }
+
+    #[test]
+    fn test_next_token5() {
+        let input =
+            r#""foobar" "foo bar" "a\"b\\c\n" [1, 2]; {"k": v}; 0..10 0..=n for (x in xs) "open"#;
+        let tests = [
+            (TokenType::STRING, "foobar"),
+            (TokenType::STRING, "foo bar"),
+            (TokenType::STRING, "a\"b\\c\n"),
+            (TokenType::LBRACKET, "["),
+            (TokenType::INT, "1"),
+            (TokenType::COMMA, ","),
+            (TokenType::INT, "2"),
+            (TokenType::RBRACKET, "]"),
+            (TokenType::SEMICOLON, ";"),
+            (TokenType::LBRACE, "{"),
+            (TokenType::STRING, "k"),
+            (TokenType::COLON, ":"),
+            (TokenType::IDENT, "v"),
+            (TokenType::RBRACE, "}"),
+            (TokenType::SEMICOLON, ";"),
+            (TokenType::INT, "0"),
+            (TokenType::DOT_DOT, ".."),
+            (TokenType::INT, "10"),
+            (TokenType::INT, "0"),
+            (TokenType::DOT_DOT_EQ, "..="),
+            (TokenType::IDENT, "n"),
+            (TokenType::FOR, "for"),
+            (TokenType::LPAREN, "("),
+            (TokenType::IDENT, "x"),
+            (TokenType::IN, "in"),
+            (TokenType::IDENT, "xs"),
+            (TokenType::RPAREN, ")"),
+            (TokenType::ILLEGAL, "\"open"),
+            (TokenType::EOF, ""),
+        ];
+        let mut l = Lexer::new(input);
+        for (token_type, literal) in tests {
+            let token: Token = l.next_token();
+            assert_eq!(
+                token,
+                Token {
+                    token_type,
+                    literal: literal.into(),
+                }
+            )
+        }
+    }
+
+    #[test]
+    fn test_stream_lexer() {
+        let inputs = [
+            "",
+            "let x = 5;\n  x += 😄;",
+            "let s = \"two\nlines\\\"\";\r\n\n\nfoo ..= bar\n",
+            "x\n\"open\nstring",
+            "fn(a) { a }",
+            "\n\n   \n",
+            "\"a\\\n\\\"b\\\\\"\nx \"\"",
+        ];
+        for input in inputs {
+            let mut lexer = Lexer::new(input);
+            let mut stream = StreamLexer::new(input.as_bytes());
+            loop {
+                let token = lexer.next_token();
+                assert_eq!(stream.next_token(), token, "{:?}", input);
+                assert_eq!(stream.position(), lexer.position(), "{:?}", input);
+                if token.token_type == TokenType::EOF {
+                    break;
+                }
+            }
+            assert_eq!(stream.next_token().token_type, TokenType::EOF);
+        }
+
+        // The input ends where it cannot be read.
+        let mut stream = StreamLexer::new(&b"let x\n= \xff;"[..]);
+        let tokens = std::iter::from_fn(|| Some(stream.next_token()))
+            .take_while(|token| token.token_type != TokenType::EOF)
+            .count();
+        assert_eq!(tokens, 2);
+        assert_eq!(
+            stream.take_error().unwrap().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+
+        // Blank lines and strings over many lines are read in linear time.
+        let lines = 100_000;
+        let input = format!("{}\"{}\" x", "\n".repeat(lines), "line\n".repeat(lines));
+        let mut stream = StreamLexer::new(input.as_bytes());
+        let token = stream.next_token();
+        assert_eq!(token.token_type, TokenType::STRING);
+        assert_eq!(token.literal.len(), "line\n".len() * lines);
+        assert_eq!(
+            stream.position(),
+            Position {
+                line: lines + 1,
+                column: 1
+            }
+        );
+        assert_eq!(stream.next_token().literal, "x");
+    }
+
+    #[test]
+    fn test_borrowed_literals() {
+        // Only string literals with escape sequences own their text.
+        let input = r#"let name = "plain" + "tab\t" == 10;"#;
+        let owned = Lexer::new(input)
+            .filter(|token| matches!(token.literal, Cow::Owned(_)))
+            .map(|token| token.literal)
+            .collect::<Vec<_>>();
+        assert_eq!(owned, ["tab\t"]);
+    }
+
+    #[test]
+    fn test_position() {
+        let input = "let x = 5;\n  x += 😄;";
+        let tests = [
+            (1, 1),
+            (1, 5),
+            (1, 7),
+            (1, 9),
+            (1, 10),
+            (2, 3),
+            (2, 5),
+            (2, 8),
+            (2, 9),
+        ];
+        let mut l = Lexer::new(input);
+        for (line, column) in tests {
+            l.next_token();
+            assert_eq!(l.position(), Position { line, column });
+        }
+    }
+
+    #[test]
+    fn test_next_token6() {
+        let input = "match xs { [_, ...rest] => my_var }";
+        let tests = [
+            (TokenType::MATCH, "match"),
+            (TokenType::IDENT, "xs"),
+            (TokenType::LBRACE, "{"),
+            (TokenType::LBRACKET, "["),
+            (TokenType::IDENT, "_"),
+            (TokenType::COMMA, ","),
+            (TokenType::ELLIPSIS, "..."),
+            (TokenType::IDENT, "rest"),
+            (TokenType::RBRACKET, "]"),
+            (TokenType::FAT_ARROW, "=>"),
+            (TokenType::IDENT, "my_var"),
+            (TokenType::RBRACE, "}"),
+            (TokenType::EOF, ""),
+        ];
+        let mut l = Lexer::new(input);
+        for (token_type, literal) in tests {
+            let token: Token = l.next_token();
+            assert_eq!(
+                token,
+                Token {
+                    token_type,
+                    literal: literal.into(),
+                }
+            )
+        }
+    }
 }
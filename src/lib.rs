@@ -1,7 +1,20 @@
+// The crate keeps the package name Monkey, which downstream code imports as `Monkey::...`.
+#![allow(non_snake_case)]
+
 pub mod ast;
+pub mod builtin;
+pub mod code;
+pub mod compiler;
+pub mod error;
 pub mod evaluator;
+pub mod format;
+pub mod gc;
 pub mod lexer;
 pub mod object;
+pub mod optimizer;
 pub mod parser;
 pub mod repl;
+pub mod resolver;
+pub mod symbol;
 pub mod token;
+pub mod vm;
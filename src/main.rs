@@ -1,4 +1,19 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::path::Path;
+use std::time::Duration;
+use std::{env, fs, io, process};
+use Monkey::ast::Program;
+use Monkey::code::Bytecode;
+use Monkey::compiler;
+use Monkey::evaluator::{self, Evaluator, DEFAULT_MAX_DEPTH};
+use Monkey::format;
+use Monkey::lexer::{Lexer, StreamLexer, TokenSource};
+use Monkey::object::Object;
+use Monkey::optimizer::{self, MAX_OPT_LEVEL};
+use Monkey::parser::Parser;
 use Monkey::repl;
+use Monkey::resolver;
+use Monkey::vm::Vm;
 
 const MONKEY_FACE: &str = r#"
             __,__
@@ -15,7 +30,248 @@ const MONKEY_FACE: &str = r#"
 
 "#;
 
+const USAGE: &str = "\
+usage: monkey [--engine eval|vm] [--opt-level 0|1] [--max-steps N] [--timeout SECONDS] [script.mnk | script.mnkc]
+       monkey compile [--opt-level 0|1] script.mnk [-o script.mnkc]
+       monkey disasm [--opt-level 0|1] script.mnkc
+A script named - is read from stdin.";
+
+/// The script name that stands for stdin
+const STDIN: &str = "-";
+
+/// What the command line asks for
+#[derive(Debug, Default, PartialEq)]
+enum Command {
+    /// Run a script, or start the REPL without one
+    #[default]
+    Run,
+    /// Compile a script to a bytecode file
+    Compile,
+    /// Print the instructions of a bytecode file or script
+    Disasm,
+}
+
+/// How scripts are run
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+enum Engine {
+    /// Walk the syntax tree
+    #[default]
+    Eval,
+    /// Compile to bytecode and run it on the virtual machine
+    Vm,
+}
+
+/// The options given on the command line
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    command: Command,
+    engine: Engine,
+    opt_level: u8,
+    max_steps: Option<u64>,
+    timeout: Option<Duration>,
+    script: Option<String>,
+    output: Option<String>,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options> {
+    let mut options = Options::default();
+    let mut args = args.into_iter().peekable();
+    // Subcommands come first.
+    options.command = match args.peek().map(String::as_str) {
+        Some("compile") => Command::Compile,
+        Some("disasm") => Command::Disasm,
+        _ => Command::Run,
+    };
+    if options.command != Command::Run {
+        args.next();
+    }
+    while let Some(arg) = args.next() {
+        // Options take their value either as `--name=value` or as the next argument.
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        match name {
+            "--engine" => {
+                let value = option_value(name, value, &mut args)?;
+                options.engine = match value.as_str() {
+                    "eval" => Engine::Eval,
+                    "vm" => Engine::Vm,
+                    _ => bail!("invalid value for --engine: {}", value),
+                };
+            }
+            "--opt-level" => {
+                let value = option_value(name, value, &mut args)?;
+                options.opt_level = value
+                    .parse()
+                    .ok()
+                    .filter(|&level| level <= MAX_OPT_LEVEL)
+                    .ok_or_else(|| anyhow!("invalid value for --opt-level: {}", value))?;
+            }
+            "--max-steps" => {
+                let value = option_value(name, value, &mut args)?;
+                let max_steps = value
+                    .parse()
+                    .with_context(|| format!("invalid value for --max-steps: {}", value))?;
+                options.max_steps = Some(max_steps);
+            }
+            "--timeout" => {
+                let value = option_value(name, value, &mut args)?;
+                let timeout = value
+                    .parse()
+                    .ok()
+                    .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                    .ok_or_else(|| anyhow!("invalid value for --timeout: {}", value))?;
+                options.timeout = Some(timeout);
+            }
+            "-o" | "--output" => options.output = Some(option_value(name, value, &mut args)?),
+            name if name.starts_with('-') && name != STDIN => bail!("unknown option: {}", name),
+            _ if options.script.is_none() => options.script = Some(arg),
+            _ => bail!("unexpected argument: {}", arg),
+        }
+    }
+    if options.command != Command::Run && options.script.is_none() {
+        bail!("missing script");
+    }
+    if options.command != Command::Compile && options.output.is_some() {
+        bail!("--output can only be used with compile");
+    }
+    Ok(options)
+}
+
+fn option_value(
+    name: &str,
+    value: Option<String>,
+    args: &mut impl Iterator<Item = String>,
+) -> Result<String> {
+    value
+        .or_else(|| args.next())
+        .ok_or_else(|| anyhow!("missing value for {}", name))
+}
+
+/// Parses a script, optimizing it when asked to
+fn parse_script<'a>(tokens: impl TokenSource<'a>, opt_level: u8) -> Result<Program> {
+    let program = Parser::new(tokens).parse_program()?;
+    if opt_level == 0 {
+        return Ok(program);
+    }
+    // The optimizer drops unreachable code, which must still be checked.
+    resolver::check_program(&program)?;
+    Ok(optimizer::optimize(program))
+}
+
+/// Parses the script on stdin as it arrives
+fn read_stdin(opt_level: u8) -> Result<Program> {
+    parse_script(StreamLexer::new(io::stdin().lock()), opt_level)
+}
+
+/// Reads a bytecode file, or compiles a script
+fn load_bytecode(path: &str, opt_level: u8) -> Result<Bytecode> {
+    if path == STDIN {
+        return compiler::compile(&read_stdin(opt_level)?);
+    }
+    let bytes = fs::read(path).with_context(|| format!("cannot read {}", path))?;
+    if bytes.starts_with(format::MAGIC) {
+        return format::decode(&bytes).with_context(|| format!("cannot load {}", path));
+    }
+    let input = String::from_utf8(bytes).with_context(|| format!("cannot read {}", path))?;
+    let program = parse_script(Lexer::new(&input), opt_level)?;
+    compiler::compile(&program)
+}
+
+fn compile_script(path: &str, output: Option<&str>, opt_level: u8) -> Result<()> {
+    // The bytecode file goes next to the script unless another path is given.
+    let output = match output {
+        Some(output) => output.into(),
+        None if path == STDIN => bail!("--output is required to compile stdin"),
+        None => Path::new(path).with_extension("mnkc"),
+    };
+    let bytecode = load_bytecode(path, opt_level)?;
+    fs::write(&output, format::encode(&bytecode))
+        .with_context(|| format!("cannot write {}", output.display()))
+}
+
+fn run_script(path: &str, options: &Options) -> Result<Object> {
+    if path == STDIN {
+        return run_program(&read_stdin(options.opt_level)?, "<stdin>", options);
+    }
+    let input = fs::read(path).with_context(|| format!("cannot read {}", path))?;
+    // Bytecode files always run on the virtual machine.
+    if options.engine == Engine::Vm || input.starts_with(format::MAGIC) {
+        let bytecode = load_bytecode(path, options.opt_level)?;
+        return run_bytecode(&bytecode, path, options);
+    }
+    let input = String::from_utf8(input).with_context(|| format!("cannot read {}", path))?;
+    let program = parse_script(Lexer::new(&input), options.opt_level)?;
+    run_program(&program, path, options)
+}
+
+fn run_bytecode(bytecode: &Bytecode, source_name: &str, options: &Options) -> Result<Object> {
+    let mut vm = Vm::new().with_source_name(source_name);
+    if let Some(max_steps) = options.max_steps {
+        vm = vm.with_max_steps(max_steps);
+    }
+    if let Some(timeout) = options.timeout {
+        vm = vm.with_timeout(timeout);
+    }
+    vm.run(bytecode)
+}
+
+/// Returns the evaluator scripts start from, which recurses to the default depth
+/// on the thread `main` starts for scripts
+fn script_evaluator() -> Evaluator {
+    Evaluator::new().with_max_stack_size(evaluator::stack_size_for_depth(DEFAULT_MAX_DEPTH))
+}
+
+fn run_program(program: &Program, source_name: &str, options: &Options) -> Result<Object> {
+    if options.engine == Engine::Vm {
+        return run_bytecode(&compiler::compile(program)?, source_name, options);
+    }
+    let mut evaluator = script_evaluator().with_source_name(source_name);
+    if let Some(max_steps) = options.max_steps {
+        evaluator = evaluator.with_max_steps(max_steps);
+    }
+    if let Some(timeout) = options.timeout {
+        evaluator = evaluator.with_timeout(timeout);
+    }
+    evaluator.eval_program(program)
+}
+
 fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Some(script) = &options.script {
+        let result = match options.command {
+            // Scripts run on a thread with the stack that the evaluator's limits call for.
+            Command::Run => evaluator::run_with_stack_size(script_evaluator().stack_size(), || {
+                run_script(script, &options).map(|result| {
+                    if result != Object::Null {
+                        println!("{}", result);
+                    }
+                })
+            })
+            .context("cannot start a thread for the script")
+            .and_then(|result| result),
+            Command::Compile => {
+                compile_script(script, options.output.as_deref(), options.opt_level)
+            }
+            Command::Disasm => load_bytecode(script, options.opt_level)
+                .map(|bytecode| print!("{}", bytecode.disassemble())),
+        };
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
     println!("{}", MONKEY_FACE);
     println!("Hello! This is the Monkey programming language!");
     println!("Feel free to type in commands");
@@ -23,3 +279,121 @@ fn main() {
     repl::start();
     println!("Goodbye!")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let tests = [
+            (args(&[]), Options::default()),
+            (
+                args(&["script.mnk"]),
+                Options {
+                    script: Some("script.mnk".to_string()),
+                    ..Options::default()
+                },
+            ),
+            (
+                args(&["--max-steps", "100", "script.mnk"]),
+                Options {
+                    max_steps: Some(100),
+                    script: Some("script.mnk".to_string()),
+                    ..Options::default()
+                },
+            ),
+            (
+                args(&["script.mnk", "--max-steps=5"]),
+                Options {
+                    max_steps: Some(5),
+                    script: Some("script.mnk".to_string()),
+                    ..Options::default()
+                },
+            ),
+            (
+                args(&["--timeout", "1.5", "script.mnk"]),
+                Options {
+                    timeout: Some(Duration::from_millis(1500)),
+                    script: Some("script.mnk".to_string()),
+                    ..Options::default()
+                },
+            ),
+            (
+                args(&["--engine=vm", "script.mnk"]),
+                Options {
+                    engine: Engine::Vm,
+                    script: Some("script.mnk".to_string()),
+                    ..Options::default()
+                },
+            ),
+            (
+                args(&["--engine", "vm", "-"]),
+                Options {
+                    engine: Engine::Vm,
+                    script: Some(STDIN.to_string()),
+                    ..Options::default()
+                },
+            ),
+            (
+                args(&["--opt-level=1", "script.mnk"]),
+                Options {
+                    opt_level: 1,
+                    script: Some("script.mnk".to_string()),
+                    ..Options::default()
+                },
+            ),
+            (
+                args(&["compile", "script.mnk", "-o", "out.mnkc"]),
+                Options {
+                    command: Command::Compile,
+                    script: Some("script.mnk".to_string()),
+                    output: Some("out.mnkc".to_string()),
+                    ..Options::default()
+                },
+            ),
+            (
+                args(&["disasm", "script.mnkc"]),
+                Options {
+                    command: Command::Disasm,
+                    script: Some("script.mnkc".to_string()),
+                    ..Options::default()
+                },
+            ),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(parse_args(input).unwrap(), expected);
+        }
+
+        let tests = [
+            (args(&["--max-steps"]), "missing value for --max-steps"),
+            (
+                args(&["--max-steps=ten"]),
+                "invalid value for --max-steps: ten",
+            ),
+            (args(&["--timeout=-1"]), "invalid value for --timeout: -1"),
+            (
+                args(&["--engine", "jit"]),
+                "invalid value for --engine: jit",
+            ),
+            (
+                args(&["--opt-level", "2"]),
+                "invalid value for --opt-level: 2",
+            ),
+            (args(&["--verbose"]), "unknown option: --verbose"),
+            (args(&["a.mnk", "b.mnk"]), "unexpected argument: b.mnk"),
+            (args(&["compile"]), "missing script"),
+            (
+                args(&["-o", "out.mnkc", "a.mnk"]),
+                "--output can only be used with compile",
+            ),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(parse_args(input).unwrap_err().to_string(), expected);
+        }
+    }
+}
@@ -1,22 +1,49 @@
-use crate::ast::{Expression, Statement};
+use crate::ast::{BindingKind, FunctionLiteral, Slot};
+use crate::builtin::Builtin;
+use crate::code::CompiledFunction;
+use crate::symbol::{Symbol, SymbolMap};
+use crate::token::Position;
 use anyhow::{bail, Result};
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::rc::Rc;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Object {
     Integer(i64),
     Boolean(bool),
+    String(String),
     Null,
+    Array(Vec<Object>),
+    Hash(BTreeMap<HashKey, Object>),
+    Range {
+        start: i64,
+        end: i64,
+        inclusive: bool,
+    },
     ReturnValue(Box<Object>),
+    Break,
+    Continue,
     FunctionObject {
-        parameters: Vec<Expression>,
-        body: Statement,
-        env: Environment,
+        function: Rc<FunctionLiteral>,
+        env: Env,
     },
+    /// A call in tail position, which is applied by the caller's trampoline
+    /// so that tail recursion does not grow the Rust stack
     FunctionApplication {
         function: Box<Object>,
         arguments: Vec<Object>,
+        named: Vec<(Symbol, Object)>,
+        /// The name of the callee and the position of the call, for the stack trace
+        callee: Symbol,
+        position: Position,
+    },
+    /// A function compiled for the virtual machine, with the scope it was created in
+    Closure {
+        function: Rc<CompiledFunction>,
+        env: Env,
     },
+    Builtin(Builtin),
 }
 
 impl Object {
@@ -29,33 +56,467 @@ impl Object {
             _ => bail!("cannot cast {:?} to boolean", self),
         }
     }
+
+    /// Estimates the memory taken by the object and everything it owns.
+    /// Functions share their environment, so it is not counted.
+    pub fn size(&self) -> usize {
+        let owned = match self {
+            Object::String(s) => s.len(),
+            Object::Array(elements) => elements.iter().map(Object::size).sum(),
+            Object::Hash(pairs) => pairs
+                .iter()
+                .map(|(k, v)| std::mem::size_of_val(k) + k.size() + v.size())
+                .sum(),
+            Object::ReturnValue(val) => val.size(),
+            _ => 0,
+        };
+        std::mem::size_of::<Object>() + owned
+    }
+
+    /// Calls `f` with each environment the object holds, directly or in its elements
+    pub(crate) fn for_each_env(&self, f: &mut impl FnMut(&Env)) {
+        match self {
+            Object::Array(elements) => elements.iter().for_each(|e| e.for_each_env(f)),
+            Object::Hash(pairs) => pairs.values().for_each(|v| v.for_each_env(f)),
+            Object::ReturnValue(val) => val.for_each_env(f),
+            Object::FunctionObject { env, .. } | Object::Closure { env, .. } => f(env),
+            Object::FunctionApplication {
+                function,
+                arguments,
+                named,
+                ..
+            } => {
+                function.for_each_env(f);
+                arguments.iter().for_each(|a| a.for_each_env(f));
+                named.iter().for_each(|(_, a)| a.for_each_env(f));
+            }
+            _ => {}
+        }
+    }
+
+    pub fn hash_key(&self) -> Result<HashKey> {
+        match self {
+            Object::Integer(i) => Ok(HashKey::Integer(*i)),
+            Object::Boolean(b) => Ok(HashKey::Boolean(*b)),
+            Object::String(s) => Ok(HashKey::String(s.clone())),
+            _ => bail!("unusable as hash key: {:?}", self),
+        }
+    }
+
+    /// Returns the items a for loop visits: array elements, the characters of a string,
+    /// the keys of a hash or the integers of a range. Items are produced one at a time,
+    /// so callers can count each one against their limits before storing it
+    pub fn iterate(self) -> Result<Box<dyn Iterator<Item = Object>>> {
+        match self {
+            Object::Array(elements) => Ok(Box::new(elements.into_iter())),
+            Object::String(s) => {
+                // The iterator owns the string and steps through it a character at a time.
+                let mut offset = 0;
+                Ok(Box::new(std::iter::from_fn(move || {
+                    let c = s[offset..].chars().next()?;
+                    offset += c.len_utf8();
+                    Some(Object::String(c.to_string()))
+                })))
+            }
+            Object::Hash(pairs) => Ok(Box::new(pairs.into_keys().map(Object::from))),
+            Object::Range {
+                start,
+                end,
+                inclusive: false,
+            } => Ok(Box::new((start..end).map(Object::Integer))),
+            Object::Range {
+                start,
+                end,
+                inclusive: true,
+            } => Ok(Box::new((start..=end).map(Object::Integer))),
+            _ => bail!("cannot iterate over {:?}", self),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+impl std::fmt::Display for Object {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Object::Integer(i) => write!(f, "{}", i),
+            Object::Boolean(b) => write!(f, "{}", b),
+            Object::String(s) => write!(f, "{:?}", s),
+            Object::Null => write!(f, "null"),
+            Object::Array(elements) => {
+                let elements = elements
+                    .iter()
+                    .map(|e| format!("{}", e))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                write!(f, "[{}]", elements)
+            }
+            Object::Hash(pairs) => {
+                let pairs = pairs
+                    .iter()
+                    .map(|(k, v)| format!("{}: {}", Object::from(k.clone()), v))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                write!(f, "{{{}}}", pairs)
+            }
+            Object::Range {
+                start,
+                end,
+                inclusive,
+            } => {
+                let operator = if *inclusive { "..=" } else { ".." };
+                write!(f, "{}{}{}", start, operator, end)
+            }
+            Object::ReturnValue(val) => write!(f, "{}", val),
+            Object::Break => write!(f, "break"),
+            Object::Continue => write!(f, "continue"),
+            Object::FunctionObject { function, .. } => write!(f, "{}", function),
+            Object::FunctionApplication {
+                function,
+                arguments,
+                named,
+                ..
+            } => {
+                let mut args = arguments
+                    .iter()
+                    .map(|a| format!("{}", a))
+                    .collect::<Vec<String>>();
+                args.extend(named.iter().map(|(name, a)| format!("{}: {}", name, a)));
+                write!(f, "({})({})", function, args.join(", "))
+            }
+            Object::Closure { function, .. } => write!(f, "{}", function.source),
+            Object::Builtin(builtin) => write!(f, "<builtin {}>", builtin.name()),
+        }
+    }
+}
+
+/// The objects that can be used as keys of a hash
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Hash)]
+pub enum HashKey {
+    Integer(i64),
+    Boolean(bool),
+    String(String),
+}
+
+impl HashKey {
+    /// Estimates the memory owned by the key besides the key itself
+    fn size(&self) -> usize {
+        match self {
+            HashKey::String(s) => s.len(),
+            _ => 0,
+        }
+    }
+}
+
+impl From<HashKey> for Object {
+    fn from(key: HashKey) -> Self {
+        match key {
+            HashKey::Integer(i) => Object::Integer(i),
+            HashKey::Boolean(b) => Object::Boolean(b),
+            HashKey::String(s) => Object::String(s),
+        }
+    }
+}
+
+/// A scope shared between the evaluator and the closures created in it.
+/// Scopes are allocated with `gc::alloc`, so that cycles through closures can be collected.
+pub type Env = Rc<RefCell<Environment>>;
+
+/// Counts the memory that live environments take, as estimated by `binding_size`. Each engine
+/// shares one with the environments it creates, which add their bindings to it and take
+/// them off again when they are updated or dropped.
+pub(crate) type MemoryMeter = Rc<Cell<usize>>;
+
+pub(crate) const ENVIRONMENT_SIZE: usize = std::mem::size_of::<Environment>();
+
+/// Estimates the memory taken by a variable in an environment. Values are copied when
+/// they are bound, so the whole value counts, while the name is interned and only its symbol is stored.
+pub(crate) fn binding_size(value: &Object) -> usize {
+    std::mem::size_of::<Symbol>() + value.size()
+}
+
+#[derive(Debug, Clone)]
+struct Binding {
+    value: Object,
+    kind: BindingKind,
+    position: Option<Position>,
+}
+
+impl Binding {
+    /// Describes where the binding was declared, for use in error messages
+    fn declared_at(&self) -> String {
+        match self.position {
+            Some(position) => format!(" (declared at {})", position),
+            None => "".to_string(),
+        }
+    }
+}
+
+/// A scope of bindings. The evaluator finds bindings by the slots the resolver assigned to them,
+/// while the virtual machine, and programs that share a top-level scope, find them by name.
 pub struct Environment {
-    store: std::collections::HashMap<String, Object>,
-    outer: Option<Box<Environment>>,
+    // The bindings by slot. A slot stays empty until its declaration runs.
+    slots: Vec<Option<Binding>>,
+    // The slots of the names that can be looked up by name
+    names: SymbolMap<usize>,
+    outer: Option<Env>,
+    // The estimated memory the scope and its bindings take, which is counted by `meter` if it has one
+    size: usize,
+    meter: Option<MemoryMeter>,
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Environment {
     pub fn new() -> Self {
         Self {
-            store: HashMap::new(),
+            slots: Vec::new(),
+            names: SymbolMap::default(),
             outer: None,
+            size: ENVIRONMENT_SIZE,
+            meter: None,
+        }
+    }
+
+    /// Creates a new scope whose lookups fall back to `outer`, and whose memory counts where that of `outer` does
+    pub fn new_enclosed(outer: Env) -> Self {
+        let meter = outer.borrow().meter.clone();
+        if let Some(meter) = &meter {
+            meter.set(meter.get().saturating_add(ENVIRONMENT_SIZE));
+        }
+        Self {
+            slots: Vec::new(),
+            names: SymbolMap::default(),
+            outer: Some(outer),
+            size: ENVIRONMENT_SIZE,
+            meter,
         }
     }
 
-    pub fn get(&self, name: &str) -> Option<&Object> {
-        match self.store.get(name) {
-            Some(obj) => Some(obj),
+    /// Counts the memory of this scope, and of the scopes created in it from now on, with `meter`
+    pub(crate) fn set_meter(&mut self, meter: &MemoryMeter) {
+        if self.meter.as_ref().is_some_and(|m| Rc::ptr_eq(m, meter)) {
+            return;
+        }
+        if let Some(old) = self.meter.replace(Rc::clone(meter)) {
+            old.set(old.get().saturating_sub(self.size));
+        }
+        meter.set(meter.get().saturating_add(self.size));
+    }
+
+    pub fn get(&self, name: Symbol) -> Option<Object> {
+        let binding = self
+            .names
+            .get(&name)
+            .and_then(|&index| self.slots[index].as_ref());
+        match binding {
+            Some(binding) => Some(binding.value.clone()),
             None => match &self.outer {
-                Some(outer) => outer.get(name),
+                Some(outer) => outer.borrow().get(name),
                 None => None,
             },
         }
     }
 
-    pub fn set(&mut self, name: String, value: Object) {
-        self.store.insert(name, value);
+    /// Returns the value in the slot, unless its declaration has not run yet
+    pub fn get_at(&self, slot: Slot) -> Option<Object> {
+        if slot.depth > 0 {
+            let outer = self.outer.as_ref()?.borrow();
+            return outer.get_at(Slot {
+                depth: slot.depth - 1,
+                ..slot
+            });
+        }
+        let binding = self.slots.get(slot.index)?.as_ref()?;
+        Some(binding.value.clone())
+    }
+
+    /// Binds `name` immutably in this scope, e.g. for function parameters
+    pub fn set(&mut self, name: Symbol, value: Object) {
+        let index = self.index_of(name);
+        self.set_at(index, value);
+    }
+
+    /// Binds the slot at `index` of this scope immutably
+    pub fn set_at(&mut self, index: usize, value: Object) {
+        let binding = Binding {
+            value,
+            kind: BindingKind::Let,
+            position: None,
+        };
+        self.store(index, binding);
+    }
+
+    /// Binds `name` in this scope as declared by a let or const statement.
+    /// Any previous binding in the same scope is shadowed unless it is a constant.
+    pub fn declare(
+        &mut self,
+        name: Symbol,
+        value: Object,
+        kind: BindingKind,
+        position: Position,
+    ) -> Result<()> {
+        let index = self.index_of(name);
+        self.declare_at(index, name, value, kind, position)
+    }
+
+    /// Binds the slot at `index` of this scope like `declare` binds `name`
+    pub fn declare_at(
+        &mut self,
+        index: usize,
+        name: Symbol,
+        value: Object,
+        kind: BindingKind,
+        position: Position,
+    ) -> Result<()> {
+        if let Some(binding) = self.slot_mut(index) {
+            if binding.kind == BindingKind::Const {
+                bail!(
+                    "cannot redeclare constant: {}{}",
+                    name,
+                    binding.declared_at()
+                );
+            }
+        }
+        let binding = Binding {
+            value,
+            kind,
+            position: Some(position),
+        };
+        self.store(index, binding);
+        Ok(())
+    }
+
+    /// Updates the nearest existing binding of `name`, which must be mutable.
+    pub fn assign(&mut self, name: Symbol, value: Object) -> Result<()> {
+        let index = self
+            .names
+            .get(&name)
+            .copied()
+            .filter(|&index| self.slots[index].is_some());
+        match index {
+            Some(index) => self.assign_at(Slot { depth: 0, index }, name, value),
+            None => match &self.outer {
+                Some(outer) => outer.borrow_mut().assign(name, value),
+                None => bail!("cannot assign to undefined variable: {}", name),
+            },
+        }
+    }
+
+    /// Updates the binding in the slot, which must be mutable. `name` is the variable the slot holds.
+    pub fn assign_at(&mut self, slot: Slot, name: Symbol, value: Object) -> Result<()> {
+        if slot.depth > 0 {
+            let Some(outer) = &self.outer else {
+                bail!("cannot assign to undefined variable: {}", name);
+            };
+            let slot = Slot {
+                depth: slot.depth - 1,
+                ..slot
+            };
+            return outer.borrow_mut().assign_at(slot, name, value);
+        }
+        let Some(binding) = self.slots.get_mut(slot.index).and_then(Option::as_mut) else {
+            bail!("cannot assign to undefined variable: {}", name);
+        };
+        if !binding.kind.is_mutable() {
+            bail!(
+                "cannot assign to immutable binding: {}{}",
+                name,
+                binding.declared_at()
+            );
+        }
+        let old = std::mem::replace(&mut binding.value, value);
+        let new = binding_size(&binding.value);
+        self.resize(new, binding_size(&old));
+        Ok(())
+    }
+
+    /// Returns the slots of the names bound in this scope, which a program run in it is resolved against
+    pub fn names(&self) -> &SymbolMap<usize> {
+        &self.names
+    }
+
+    /// Makes the slots that a program run in this scope declares at the top level accessible by name
+    pub fn set_names(&mut self, names: SymbolMap<usize>) {
+        if let Some(&last) = names.values().max() {
+            self.slot_mut(last);
+        }
+        self.names = names;
+    }
+
+    /// Calls `f` with each environment this scope holds: the enclosing one and those in its bindings
+    pub(crate) fn for_each_env(&self, f: &mut impl FnMut(&Env)) {
+        if let Some(outer) = &self.outer {
+            f(outer);
+        }
+        for binding in self.slots.iter().flatten() {
+            binding.value.for_each_env(f);
+        }
+    }
+
+    /// Returns the slot of `name`, adding an empty one if the name has none
+    fn index_of(&mut self, name: Symbol) -> usize {
+        if let Some(&index) = self.names.get(&name) {
+            return index;
+        }
+        let index = self.slots.len();
+        self.slots.push(None);
+        self.names.insert(name, index);
+        index
+    }
+
+    /// Puts the binding in the slot at `index`, replacing the one it holds
+    fn store(&mut self, index: usize, binding: Binding) {
+        let added = binding_size(&binding.value);
+        let old = self.slot_mut(index).replace(binding);
+        let removed = old.as_ref().map_or(0, |old| binding_size(&old.value));
+        self.resize(added, removed);
+    }
+
+    /// Counts the bindings of the scope growing by `added` bytes and shrinking by `removed` bytes
+    fn resize(&mut self, added: usize, removed: usize) {
+        self.size = self.size.saturating_add(added).saturating_sub(removed);
+        if let Some(meter) = &self.meter {
+            meter.set(meter.get().saturating_add(added).saturating_sub(removed));
+        }
+    }
+
+    fn slot_mut(&mut self, index: usize) -> &mut Option<Binding> {
+        if index >= self.slots.len() {
+            self.slots.resize(index + 1, None);
+        }
+        &mut self.slots[index]
+    }
+}
+
+impl Drop for Environment {
+    fn drop(&mut self) {
+        if let Some(meter) = &self.meter {
+            meter.set(meter.get().saturating_sub(self.size));
+        }
+    }
+}
+
+// Closures hold their defining environment, which in turn may hold the closure itself,
+// so equality and debug output must not recurse into the bindings.
+impl PartialEq for Environment {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl std::fmt::Debug for Environment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut names = self
+            .names
+            .keys()
+            .map(|name| name.as_str())
+            .collect::<Vec<&str>>();
+        names.sort();
+        f.debug_struct("Environment")
+            .field("names", &names)
+            .finish_non_exhaustive()
     }
 }
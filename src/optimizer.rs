@@ -0,0 +1,376 @@
+//! Rewrites of the syntax tree that keep the result of a program while doing less work to get it.
+//!
+//! The optimizer folds operators applied to literals, replaces `if` expressions whose condition is
+//! a literal with the branch they take, and drops statements that follow a `return`, `break` or
+//! `continue`. Operations that would fail, such as `1 / 0`, are left for evaluation to report.
+//!
+//! Dropped statements are no longer checked, so programs should pass `resolver::check_program`
+//! before they are optimized. Optimized programs take fewer evaluation steps than the originals.
+
+use crate::ast::{Argument, Expression, FunctionLiteral, MatchArm, Parameter, Program, Statement};
+use crate::evaluator::{
+    eval_bang_prefix_expression, eval_infix_expression, eval_minus_prefix_operator_expression,
+};
+use crate::object::Object;
+use crate::resolver;
+use std::rc::Rc;
+
+/// The highest level accepted by `--opt-level`. Level 0 runs programs as they are written.
+pub const MAX_OPT_LEVEL: u8 = 1;
+
+pub fn optimize(program: Program) -> Program {
+    Program {
+        statements: optimize_statements(program.statements),
+        names_size: program.names_size,
+    }
+}
+
+fn optimize_statements(statements: Vec<Statement>) -> Vec<Statement> {
+    let mut optimized = Vec::with_capacity(statements.len());
+    let mut statements = statements.into_iter();
+    for statement in statements.by_ref() {
+        let statement = optimize_statement(statement);
+        let leaves = leaves_block(&statement);
+        optimized.push(statement);
+        if leaves {
+            break;
+        }
+    }
+    // The rest never runs, but its declarations stay: they decide which variable each name
+    // in the closures created earlier in the scope refers to.
+    optimized.extend(statements.filter(resolver::declares));
+    optimized
+}
+
+/// Returns whether control never reaches the statement after this one
+fn leaves_block(statement: &Statement) -> bool {
+    match statement {
+        Statement::ReturnStatement(_)
+        | Statement::BreakStatement
+        | Statement::ContinueStatement => true,
+        Statement::BlockStatement { statements } => statements.iter().any(leaves_block),
+        _ => false,
+    }
+}
+
+fn optimize_statement(statement: Statement) -> Statement {
+    match statement {
+        Statement::LetStatement {
+            kind,
+            pattern,
+            value,
+            position,
+        } => Statement::LetStatement {
+            kind,
+            pattern,
+            value: optimize_expression(value),
+            position,
+        },
+        Statement::ReturnStatement(expr) => Statement::ReturnStatement(optimize_expression(expr)),
+        Statement::ExpressionStatement(expr) => match optimize_expression(expr) {
+            // A branch that declares variables cannot become an expression, but it can
+            // run as a block, which shares the scope of the if expression.
+            Expression::IfExpression {
+                condition,
+                consequence,
+                alternative,
+            } => match constant_condition(&condition) {
+                Some(true) => *consequence,
+                Some(false) => match alternative {
+                    Some(alt) => *alt,
+                    None => Statement::BlockStatement {
+                        statements: Vec::new(),
+                    },
+                },
+                None => Statement::ExpressionStatement(Expression::IfExpression {
+                    condition,
+                    consequence,
+                    alternative,
+                }),
+            },
+            expr => Statement::ExpressionStatement(expr),
+        },
+        Statement::BlockStatement { statements } => Statement::BlockStatement {
+            statements: optimize_statements(statements),
+        },
+        Statement::WhileStatement { condition, body } => Statement::WhileStatement {
+            condition: optimize_expression(condition),
+            body: Box::new(optimize_statement(*body)),
+        },
+        Statement::ForStatement {
+            variable,
+            iterable,
+            body,
+        } => Statement::ForStatement {
+            variable,
+            iterable: optimize_expression(iterable),
+            body: Box::new(optimize_statement(*body)),
+        },
+        statement @ (Statement::EmptyStatement
+        | Statement::BreakStatement
+        | Statement::ContinueStatement) => statement,
+    }
+}
+
+/// Optimizes a branch of an if expression, which must stay a block or an else-if expression
+fn optimize_branch(statement: Statement) -> Statement {
+    match optimize_statement(statement) {
+        Statement::ExpressionStatement(expr @ Expression::IfExpression { .. }) => {
+            Statement::ExpressionStatement(expr)
+        }
+        Statement::ExpressionStatement(expr) => Statement::BlockStatement {
+            statements: vec![Statement::ExpressionStatement(expr)],
+        },
+        statement => statement,
+    }
+}
+
+fn optimize_expression(expression: Expression) -> Expression {
+    match expression {
+        Expression::PrefixExpression { operator, right } => {
+            let right = optimize_expression(*right);
+            let folded = literal_value(&right).and_then(|right| match operator.as_str() {
+                "!" => eval_bang_prefix_expression(right).ok(),
+                "-" => eval_minus_prefix_operator_expression(right).ok(),
+                _ => None,
+            });
+            match folded.and_then(literal) {
+                Some(literal) => literal,
+                None => Expression::PrefixExpression {
+                    operator,
+                    right: Box::new(right),
+                },
+            }
+        }
+        Expression::InfixExpression {
+            left,
+            operator,
+            right,
+        } => {
+            let left = optimize_expression(*left);
+            let right = optimize_expression(*right);
+            let folded = literal_value(&left)
+                .zip(literal_value(&right))
+                .and_then(|(l, r)| eval_infix_expression(&operator, l, r).ok());
+            match folded.and_then(literal) {
+                Some(literal) => literal,
+                None => Expression::InfixExpression {
+                    left: Box::new(left),
+                    operator,
+                    right: Box::new(right),
+                },
+            }
+        }
+        Expression::IfExpression {
+            condition,
+            consequence,
+            alternative,
+        } => {
+            let condition = optimize_expression(*condition);
+            let consequence = optimize_branch(*consequence);
+            let alternative = alternative.map(|alt| Box::new(optimize_branch(*alt)));
+            let branch = match constant_condition(&condition) {
+                Some(true) => Some(&consequence),
+                Some(false) => alternative.as_deref(),
+                None => None,
+            };
+            match branch.and_then(branch_value) {
+                Some(value) => value,
+                None => Expression::IfExpression {
+                    condition: Box::new(condition),
+                    consequence: Box::new(consequence),
+                    alternative,
+                },
+            }
+        }
+        Expression::MatchExpression { subject, arms } => Expression::MatchExpression {
+            subject: Box::new(optimize_expression(*subject)),
+            arms: arms
+                .into_iter()
+                .map(|arm| MatchArm {
+                    pattern: arm.pattern,
+                    guard: arm.guard.map(optimize_expression),
+                    body: optimize_statement(arm.body),
+                })
+                .collect(),
+        },
+        Expression::FunctionLiteral(function) => {
+            let function = Rc::unwrap_or_clone(function);
+            Expression::FunctionLiteral(Rc::new(FunctionLiteral {
+                name: function.name,
+                parameters: function
+                    .parameters
+                    .into_iter()
+                    .map(|parameter| Parameter {
+                        pattern: parameter.pattern,
+                        default: parameter.default.map(optimize_expression),
+                    })
+                    .collect(),
+                rest: function.rest,
+                body: optimize_statement(function.body),
+            }))
+        }
+        Expression::CallExpression {
+            function,
+            arguments,
+            position,
+        } => Expression::CallExpression {
+            function: Box::new(optimize_expression(*function)),
+            arguments: arguments
+                .into_iter()
+                .map(|argument| match argument {
+                    Argument::Positional(expr) => Argument::Positional(optimize_expression(expr)),
+                    Argument::Spread(expr) => Argument::Spread(optimize_expression(expr)),
+                    Argument::Named(name, expr) => Argument::Named(name, optimize_expression(expr)),
+                })
+                .collect(),
+            position,
+        },
+        Expression::AssignExpression {
+            name,
+            operator,
+            value,
+        } => Expression::AssignExpression {
+            name,
+            operator,
+            value: Box::new(optimize_expression(*value)),
+        },
+        Expression::ArrayLiteral(elements) => {
+            Expression::ArrayLiteral(elements.into_iter().map(optimize_expression).collect())
+        }
+        Expression::HashLiteral(pairs) => Expression::HashLiteral(
+            pairs
+                .into_iter()
+                .map(|(key, value)| (optimize_expression(key), optimize_expression(value)))
+                .collect(),
+        ),
+        Expression::IndexExpression { left, index } => Expression::IndexExpression {
+            left: Box::new(optimize_expression(*left)),
+            index: Box::new(optimize_expression(*index)),
+        },
+        expression @ (Expression::EmptyExpression
+        | Expression::Identifier(_)
+        | Expression::IntegerLiteral(_)
+        | Expression::StringLiteral(_)
+        | Expression::Boolean(_)) => expression,
+    }
+}
+
+/// Returns the value of a literal expression
+fn literal_value(expression: &Expression) -> Option<Object> {
+    match expression {
+        Expression::IntegerLiteral(i) => Some(Object::Integer(*i)),
+        Expression::StringLiteral(s) => Some(Object::String(s.clone())),
+        Expression::Boolean(b) => Some(Object::Boolean(*b)),
+        _ => None,
+    }
+}
+
+/// Returns the literal expression of a value, if it has one
+fn literal(object: Object) -> Option<Expression> {
+    match object {
+        Object::Integer(i) => Some(Expression::IntegerLiteral(i)),
+        Object::String(s) => Some(Expression::StringLiteral(s)),
+        Object::Boolean(b) => Some(Expression::Boolean(b)),
+        _ => None,
+    }
+}
+
+/// Returns which branch an if expression takes when its condition is a literal
+fn constant_condition(condition: &Expression) -> Option<bool> {
+    match literal_value(condition)?.cast_to_boolean().ok()? {
+        Object::Boolean(b) => Some(b),
+        _ => None,
+    }
+}
+
+/// Returns the expression a branch consists of, if it does nothing else
+fn branch_value(branch: &Statement) -> Option<Expression> {
+    match branch {
+        Statement::BlockStatement { statements } => match statements.as_slice() {
+            [statement] => branch_value(statement),
+            _ => None,
+        },
+        Statement::ExpressionStatement(expr) => Some(expr.clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler;
+    use crate::evaluator::eval_program;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::vm::Vm;
+
+    fn parse(input: &str) -> Program {
+        Parser::new(Lexer::new(input)).parse_program().unwrap()
+    }
+
+    #[test]
+    fn test_optimize() {
+        let tests = [
+            ("2 * (5 + 10)", "30"),
+            ("-5 + 2", "-3"),
+            ("!(1 < 2)", "false"),
+            (r#""a" + "b" == "ab""#, "true"),
+            ("x + 2 * 3", "(x + 6)"),
+            // Failing operations are left to evaluation.
+            ("1 / 0", "(1 / 0)"),
+            ("9223372036854775807 + 1", "(9223372036854775807 + 1)"),
+            ("-true", "(-true)"),
+            ("1..3", "(1 .. 3)"),
+            ("let x = if (1 < 2) { 10 } else { 20 };", "let x = 10;"),
+            ("if (0) { 10 } else if (true) { 20 }", "20"),
+            ("if (false) { 10 }", "{\n \n}"),
+            ("if (true) { let x = 1; x }", "{\n let x = 1;\nx\n}"),
+            ("if (x) { 1 + 1 }", "if x {\n 2\n}"),
+            ("return 1; 2; let y = 3; y", "return 1;\nlet y = 3;"),
+            (
+                "fn() { if (true) { return 1; } 2 }",
+                "fn() {\n {\n return 1;\n}\n}",
+            ),
+            ("while (x) { break; x = 1; }", "while x {\n break;\n}"),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(optimize(parse(input)).to_string(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_optimized_programs_evaluate_identically() {
+        fn run(program: &Program) -> [String; 2] {
+            let show = |result: anyhow::Result<Object>| match result {
+                Ok(result) => result.to_string(),
+                Err(e) => format!("error: {}", e),
+            };
+            let vm = compiler::compile(program).and_then(|bytecode| Vm::new().run(&bytecode));
+            [show(eval_program(program)), show(vm)]
+        }
+
+        let tests = [
+            "2 * (5 + 10) - -3",
+            r#"let s = "a" + "b"; s + "c""#,
+            "let x = if (10 > 5 * 3) { 1 } else { 2 }; x",
+            "if (true) { let y = 5; } y",
+            "if (false) { 1 }",
+            "let f = fn(n) { if (true) { return n * 2; } n }; f(21)",
+            "let f = fn() { return g(); let g = fn() { 1 }; }; f()",
+            "let f = fn() { x }; return f; let x = 1;",
+            "let mut i = 0; while (true) { i += 1; if (i == 3) { break; } continue; i = 100; } i",
+            "let f = fn(a = 2 * 3) { a }; f()",
+            "match 4 { n if n > 1 + 2 => n * (1 + 1), _ => 0 }",
+            "1 / (2 - 2)",
+            "let f = fn() { 9223372036854775807 + 1 }; f()",
+            "if (\"a\") { 1 }",
+            "-\"a\"",
+        ];
+        for input in tests {
+            let program = parse(input);
+            let optimized = optimize(program.clone());
+            assert_eq!(run(&optimized), run(&program), "{}", input);
+        }
+    }
+}
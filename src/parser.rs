@@ -1,54 +1,87 @@
-use crate::ast::{Expression, Program, Statement};
-use crate::lexer::Lexer;
-use crate::token::{Token, TokenType};
-use anyhow::{ensure, Result};
+use crate::ast::{
+    Argument, BindingKind, Expression, FunctionLiteral, Identifier, MatchArm, Parameter, Pattern,
+    Program, Statement,
+};
+use crate::lexer::{Lexer, TokenSource};
+use crate::symbol::{self, Symbol};
+use crate::token::{Position, Token, TokenType};
+use anyhow::{anyhow, bail, ensure, Result};
+use std::mem;
+use std::rc::Rc;
 
 #[derive(Debug, PartialEq, PartialOrd)]
+#[allow(clippy::upper_case_acronyms)]
 enum Precedence {
     LOWEST,
+    ASSIGN,      // = or +=
     EQUALS,      // ==
     LESSGREATER, // > or <
+    RANGE,       // .. or ..=
     SUM,         // +
     PRODUCT,     // *
     PREFIX,      // -X or !X
     CALL,        // myFunction(X)
+    INDEX,       // array[index]
 }
 
 impl Precedence {
     fn from_token_type(t: TokenType) -> Precedence {
         match t {
+            TokenType::ASSIGN => Precedence::ASSIGN,
+            TokenType::PLUS_ASSIGN => Precedence::ASSIGN,
+            TokenType::MINUS_ASSIGN => Precedence::ASSIGN,
+            TokenType::ASTERISK_ASSIGN => Precedence::ASSIGN,
+            TokenType::SLASH_ASSIGN => Precedence::ASSIGN,
             TokenType::EQ => Precedence::EQUALS,
             TokenType::NOT_EQ => Precedence::EQUALS,
             TokenType::LT => Precedence::LESSGREATER,
             TokenType::GT => Precedence::LESSGREATER,
+            TokenType::DOT_DOT => Precedence::RANGE,
+            TokenType::DOT_DOT_EQ => Precedence::RANGE,
             TokenType::PLUS => Precedence::SUM,
             TokenType::MINUS => Precedence::SUM,
             TokenType::SLASH => Precedence::PRODUCT,
             TokenType::ASTERISK => Precedence::PRODUCT,
             TokenType::LPAREN => Precedence::CALL,
+            TokenType::LBRACKET => Precedence::INDEX,
             _ => Precedence::LOWEST,
         }
     }
 }
 
-pub struct Parser<'a> {
-    lexer: Lexer<'a>,
-    cur_token: Token,
-    peek_token: Token,
+/// How deeply statements, expressions and patterns may nest. This bounds the recursion of the
+/// parser and of everything that walks the AST, so that deeply nested input cannot overflow the stack.
+const MAX_NESTING_DEPTH: usize = 256;
+
+/// Parses the tokens of `lexer`, which is a `Lexer` over a string unless another token source is given
+pub struct Parser<'a, L = Lexer<'a>> {
+    lexer: L,
+    cur_token: Token<'a>,
+    peek_token: Token<'a>,
+    cur_position: Position,
+    peek_position: Position,
+    // The position of the expression whose infix part is being parsed
+    left_position: Position,
+    // How many statements, expressions and patterns enclose the one being parsed
+    depth: usize,
 }
 
-impl<'a> Parser<'a> {
-    pub fn new(lexer: Lexer<'a>) -> Parser<'a> {
+impl<'a, L: TokenSource<'a>> Parser<'a, L> {
+    pub fn new(lexer: L) -> Self {
         let mut p = Parser {
-            lexer: lexer,
+            lexer,
             cur_token: Token {
                 token_type: TokenType::EOF,
-                literal: String::new(),
+                literal: "".into(),
             },
             peek_token: Token {
                 token_type: TokenType::EOF,
-                literal: String::new(),
+                literal: "".into(),
             },
+            cur_position: Position::default(),
+            peek_position: Position::default(),
+            left_position: Position::default(),
+            depth: 0,
         };
 
         p.next_token();
@@ -57,8 +90,9 @@ impl<'a> Parser<'a> {
     }
 
     pub fn next_token(&mut self) {
-        self.cur_token = self.peek_token.clone();
-        self.peek_token = self.lexer.next_token();
+        self.cur_token = mem::replace(&mut self.peek_token, self.lexer.next_token());
+        self.cur_position = self.peek_position;
+        self.peek_position = self.lexer.position();
     }
 
     fn expect_peek(&mut self, t: TokenType) -> bool {
@@ -74,20 +108,21 @@ impl<'a> Parser<'a> {
         Precedence::from_token_type(self.cur_token.token_type)
     }
 
-    fn get_prefix_parse_fn(
-        &self,
-        t: TokenType,
-    ) -> Option<fn(&mut Parser<'a>) -> Result<Expression>> {
+    fn get_prefix_parse_fn(&self, t: TokenType) -> Option<fn(&mut Self) -> Result<Expression>> {
         match t {
             TokenType::IDENT => Some(Parser::parse_identifier),
             TokenType::INT => Some(Parser::parse_integer_literal),
+            TokenType::STRING => Some(Parser::parse_string_literal),
             TokenType::TRUE => Some(Parser::parse_boolean),
             TokenType::FALSE => Some(Parser::parse_boolean),
             TokenType::BANG => Some(Parser::parse_prefix_expression),
             TokenType::MINUS => Some(Parser::parse_prefix_expression),
             TokenType::LPAREN => Some(Parser::parse_grouped_expression),
             TokenType::IF => Some(Parser::parse_if_expression),
+            TokenType::MATCH => Some(Parser::parse_match_expression),
             TokenType::FUNCTION => Some(Parser::parse_function_literal),
+            TokenType::LBRACKET => Some(Parser::parse_array_literal),
+            TokenType::LBRACE => Some(Parser::parse_hash_literal),
             _ => None,
         }
     }
@@ -95,7 +130,7 @@ impl<'a> Parser<'a> {
     fn get_infix_parse_fn(
         &self,
         t: TokenType,
-    ) -> Option<fn(&mut Parser<'a>, Expression) -> Result<Expression>> {
+    ) -> Option<fn(&mut Self, Expression) -> Result<Expression>> {
         match t {
             TokenType::PLUS => Some(Parser::parse_infix_expression),
             TokenType::MINUS => Some(Parser::parse_infix_expression),
@@ -105,14 +140,35 @@ impl<'a> Parser<'a> {
             TokenType::NOT_EQ => Some(Parser::parse_infix_expression),
             TokenType::LT => Some(Parser::parse_infix_expression),
             TokenType::GT => Some(Parser::parse_infix_expression),
+            TokenType::DOT_DOT => Some(Parser::parse_infix_expression),
+            TokenType::DOT_DOT_EQ => Some(Parser::parse_infix_expression),
             TokenType::LPAREN => Some(Parser::parse_call_expression),
+            TokenType::LBRACKET => Some(Parser::parse_index_expression),
+            TokenType::ASSIGN => Some(Parser::parse_assign_expression),
+            TokenType::PLUS_ASSIGN => Some(Parser::parse_assign_expression),
+            TokenType::MINUS_ASSIGN => Some(Parser::parse_assign_expression),
+            TokenType::ASTERISK_ASSIGN => Some(Parser::parse_assign_expression),
+            TokenType::SLASH_ASSIGN => Some(Parser::parse_assign_expression),
             _ => None,
         }
     }
 
     pub fn parse_program(&mut self) -> Result<Program> {
+        let start = symbol::added_size();
+        let program = self.parse_statements();
+        // Input that could not be read is reported instead of the syntax errors it leads to.
+        if let Some(e) = self.lexer.take_error() {
+            bail!("cannot read input: {}", e);
+        }
+        let mut program = program?;
+        program.names_size = symbol::added_size() - start;
+        Ok(program)
+    }
+
+    fn parse_statements(&mut self) -> Result<Program> {
         let mut program = Program {
             statements: Vec::new(),
+            names_size: 0,
         };
 
         while self.cur_token.token_type != TokenType::EOF {
@@ -126,22 +182,51 @@ impl<'a> Parser<'a> {
         Ok(program)
     }
 
+    /// Enters a nested statement, expression or pattern, which must be left with `self.depth -= 1`
+    fn enter(&mut self) -> Result<()> {
+        ensure!(
+            self.depth < MAX_NESTING_DEPTH,
+            "nesting too deep: more than {} levels",
+            MAX_NESTING_DEPTH
+        );
+        self.depth += 1;
+        Ok(())
+    }
+
     fn parse_statement(&mut self) -> Result<Statement> {
+        self.enter()?;
+        let statement = self.parse_unnested_statement();
+        self.depth -= 1;
+        statement
+    }
+
+    fn parse_unnested_statement(&mut self) -> Result<Statement> {
         match self.cur_token.token_type {
             TokenType::LET => self.parse_let_statement(),
+            TokenType::CONST => self.parse_let_statement(),
             TokenType::RETURN => self.parse_return_statement(),
             TokenType::LBRACE => self.parse_block_statement(),
+            TokenType::IF => self.parse_block_expression_statement(),
+            TokenType::MATCH => self.parse_block_expression_statement(),
+            TokenType::WHILE => self.parse_while_statement(),
+            TokenType::FOR => self.parse_for_statement(),
+            TokenType::BREAK => self.parse_loop_control_statement(Statement::BreakStatement),
+            TokenType::CONTINUE => self.parse_loop_control_statement(Statement::ContinueStatement),
             _ => self.parse_expression_statement(),
         }
     }
 
     fn parse_let_statement(&mut self) -> Result<Statement> {
-        ensure!(
-            self.expect_peek(TokenType::IDENT),
-            "expected next token to be IDENT, got {:?} instead",
-            self.peek_token.token_type
-        );
-        let name = self.cur_token.literal.to_string();
+        let kind = if self.cur_token.token_type == TokenType::CONST {
+            BindingKind::Const
+        } else if self.expect_peek(TokenType::MUT) {
+            BindingKind::LetMut
+        } else {
+            BindingKind::Let
+        };
+        self.next_token();
+        let position = self.cur_position;
+        let pattern = self.parse_pattern()?;
 
         ensure!(
             self.expect_peek(TokenType::ASSIGN),
@@ -151,13 +236,28 @@ impl<'a> Parser<'a> {
 
         self.next_token();
 
-        let value = self.parse_expression(Precedence::LOWEST)?;
+        let mut value = self.parse_expression(Precedence::LOWEST)?;
         ensure!(
             self.expect_peek(TokenType::SEMICOLON),
             "expected next token to be SEMICOLON, got {:?} instead",
             self.peek_token.token_type
         );
-        Ok(Statement::LetStatement { name, value })
+
+        // A function bound to a name is known by that name, e.g. in stack traces.
+        if let (Pattern::Binding(binding), Expression::FunctionLiteral(function)) =
+            (&pattern, &mut value)
+        {
+            // The literal was just parsed, so nothing else shares it yet.
+            if let Some(function) = Rc::get_mut(function).filter(|f| f.name.is_none()) {
+                function.name = Some(binding.name);
+            }
+        }
+        Ok(Statement::LetStatement {
+            kind,
+            pattern,
+            value,
+            position,
+        })
     }
 
     fn parse_return_statement(&mut self) -> Result<Statement> {
@@ -186,6 +286,91 @@ impl<'a> Parser<'a> {
         Ok(Statement::BlockStatement { statements })
     }
 
+    /// Parses an if or match expression at the start of a statement.
+    /// Like other block statements it ends at its closing brace, so no semicolon is needed after it.
+    fn parse_block_expression_statement(&mut self) -> Result<Statement> {
+        let expression = self.parse_prefix()?;
+        if self.peek_token.token_type == TokenType::SEMICOLON {
+            self.next_token();
+        }
+        Ok(Statement::ExpressionStatement(expression))
+    }
+
+    fn parse_while_statement(&mut self) -> Result<Statement> {
+        ensure!(
+            self.expect_peek(TokenType::LPAREN),
+            "expected next token to be LPAREN, got {:?} instead",
+            self.peek_token.token_type
+        );
+        self.next_token();
+        let condition = self.parse_expression(Precedence::LOWEST)?;
+        ensure!(
+            self.expect_peek(TokenType::RPAREN),
+            "expected next token to be RPAREN, got {:?} instead",
+            self.peek_token.token_type
+        );
+        ensure!(
+            self.expect_peek(TokenType::LBRACE),
+            "expected next token to be LBRACE, got {:?} instead",
+            self.peek_token.token_type
+        );
+        let body = self.parse_block_statement()?;
+        if self.peek_token.token_type == TokenType::SEMICOLON {
+            self.next_token();
+        }
+        Ok(Statement::WhileStatement {
+            condition,
+            body: Box::new(body),
+        })
+    }
+
+    fn parse_for_statement(&mut self) -> Result<Statement> {
+        ensure!(
+            self.expect_peek(TokenType::LPAREN),
+            "expected next token to be LPAREN, got {:?} instead",
+            self.peek_token.token_type
+        );
+        ensure!(
+            self.expect_peek(TokenType::IDENT),
+            "expected next token to be IDENT, got {:?} instead",
+            self.peek_token.token_type
+        );
+        let variable = Identifier::from(self.cur_token.literal.as_ref());
+        ensure!(
+            self.expect_peek(TokenType::IN),
+            "expected next token to be IN, got {:?} instead",
+            self.peek_token.token_type
+        );
+        self.next_token();
+        let iterable = self.parse_expression(Precedence::LOWEST)?;
+        ensure!(
+            self.expect_peek(TokenType::RPAREN),
+            "expected next token to be RPAREN, got {:?} instead",
+            self.peek_token.token_type
+        );
+        ensure!(
+            self.expect_peek(TokenType::LBRACE),
+            "expected next token to be LBRACE, got {:?} instead",
+            self.peek_token.token_type
+        );
+        let body = self.parse_block_statement()?;
+        if self.peek_token.token_type == TokenType::SEMICOLON {
+            self.next_token();
+        }
+        Ok(Statement::ForStatement {
+            variable,
+            iterable,
+            body: Box::new(body),
+        })
+    }
+
+    fn parse_loop_control_statement(&mut self, statement: Statement) -> Result<Statement> {
+        if self.peek_token.token_type == TokenType::SEMICOLON {
+            self.next_token();
+        }
+        Ok(statement)
+    }
+
     fn parse_expression_statement(&mut self) -> Result<Statement> {
         let expression = self.parse_expression(Precedence::LOWEST)?;
         if self.peek_token.token_type == TokenType::SEMICOLON {
@@ -195,13 +380,32 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_expression(&mut self, precedence: Precedence) -> Result<Expression> {
+        self.enter()?;
+        let expression = self.parse_unnested_expression(precedence);
+        self.depth -= 1;
+        expression
+    }
+
+    fn parse_unnested_expression(&mut self, precedence: Precedence) -> Result<Expression> {
+        let depth = self.depth;
+        let expression = self.parse_operations(precedence);
+        self.depth = depth;
+        expression
+    }
+
+    /// Parses an operand and the operators applied to it, each of which nests the expression
+    /// parsed so far one level deeper and counts towards the depth until the caller restores it
+    fn parse_operations(&mut self, precedence: Precedence) -> Result<Expression> {
+        let position = self.cur_position;
         let prefix = self.parse_prefix()?;
         let mut left = prefix;
 
         while self.peek_token.token_type != TokenType::SEMICOLON
             && precedence < Precedence::from_token_type(self.peek_token.token_type)
         {
+            self.enter()?;
             self.next_token();
+            self.left_position = position;
             left = self.parse_infix(left)?;
         }
 
@@ -210,34 +414,73 @@ impl<'a> Parser<'a> {
 
     fn parse_prefix(&mut self) -> Result<Expression> {
         let prefix_fn = self.get_prefix_parse_fn(self.cur_token.token_type);
-        ensure!(
-            prefix_fn.is_some(),
-            "no prefix parse function for {:?} found",
-            self.cur_token.token_type
-        );
-        let prefix_fn = prefix_fn.unwrap();
+        let Some(prefix_fn) = prefix_fn else {
+            bail!(
+                "no prefix parse function for {:?} found",
+                self.cur_token.token_type
+            );
+        };
         prefix_fn(self)
     }
 
     fn parse_infix(&mut self, left: Expression) -> Result<Expression> {
         let token_type = self.cur_token.token_type;
-        let infix_fn = self.get_infix_parse_fn(token_type);
-        if infix_fn.is_none() {
-            return Ok(left);
+        match self.get_infix_parse_fn(token_type) {
+            Some(infix_fn) => infix_fn(self, left),
+            None => Ok(left),
         }
-        let infix_fn = infix_fn.unwrap().clone();
-        infix_fn(self, left)
     }
 
     fn parse_identifier(&mut self) -> Result<Expression> {
-        Ok(Expression::Identifier(self.cur_token.literal.to_string()))
+        Ok(Expression::Identifier(Identifier::from(
+            self.cur_token.literal.as_ref(),
+        )))
     }
 
     fn parse_integer_literal(&mut self) -> Result<Expression> {
-        let value = self.cur_token.literal.parse::<i64>()?;
+        let literal = &self.cur_token.literal;
+        let value = literal
+            .parse::<i64>()
+            .map_err(|_| anyhow!("integer literal out of range: {}", literal))?;
         Ok(Expression::IntegerLiteral(value))
     }
 
+    fn parse_string_literal(&mut self) -> Result<Expression> {
+        Ok(Expression::StringLiteral(
+            self.cur_token.literal.to_string(),
+        ))
+    }
+
+    fn parse_array_literal(&mut self) -> Result<Expression> {
+        let elements = self.parse_expression_list(TokenType::RBRACKET)?;
+        Ok(Expression::ArrayLiteral(elements))
+    }
+
+    fn parse_hash_literal(&mut self) -> Result<Expression> {
+        let mut pairs = Vec::new();
+        while self.peek_token.token_type != TokenType::RBRACE {
+            self.next_token();
+            let key = self.parse_expression(Precedence::LOWEST)?;
+            ensure!(
+                self.expect_peek(TokenType::COLON),
+                "expected next token to be COLON, got {:?} instead",
+                self.peek_token.token_type
+            );
+            self.next_token();
+            let value = self.parse_expression(Precedence::LOWEST)?;
+            pairs.push((key, value));
+            if self.peek_token.token_type != TokenType::RBRACE {
+                ensure!(
+                    self.expect_peek(TokenType::COMMA),
+                    "expected next token to be COMMA, got {:?} instead",
+                    self.peek_token.token_type
+                );
+            }
+        }
+        self.next_token();
+        Ok(Expression::HashLiteral(pairs))
+    }
+
     fn parse_boolean(&mut self) -> Result<Expression> {
         Ok(Expression::Boolean(
             self.cur_token.token_type == TokenType::TRUE,
@@ -292,41 +535,213 @@ impl<'a> Parser<'a> {
         let consequence = self.parse_block_statement()?;
         let alternative = if self.peek_token.token_type == TokenType::ELSE {
             self.next_token();
-            ensure!(
-                self.expect_peek(TokenType::LBRACE),
-                "expected next token to be LBRACE, got {:?} instead",
-                self.peek_token.token_type
-            );
-            Some(Box::new(self.parse_block_statement()?))
+            if self.expect_peek(TokenType::IF) {
+                // An else-if chain nests the following if expression as the alternative
+                let expression = self.parse_if_expression()?;
+                Some(Box::new(Statement::ExpressionStatement(expression)))
+            } else {
+                ensure!(
+                    self.expect_peek(TokenType::LBRACE),
+                    "expected next token to be LBRACE, got {:?} instead",
+                    self.peek_token.token_type
+                );
+                Some(Box::new(self.parse_block_statement()?))
+            }
         } else {
             None
         };
         Ok(Expression::IfExpression {
             condition: Box::new(condition),
             consequence: Box::new(consequence),
-            alternative: alternative,
+            alternative,
         })
     }
 
-    fn parse_function_parameters(&mut self) -> Result<Vec<Expression>> {
-        let mut identifiers = Vec::new();
-        if self.peek_token.token_type == TokenType::RPAREN {
+    fn parse_match_expression(&mut self) -> Result<Expression> {
+        self.next_token();
+        let subject = self.parse_expression(Precedence::LOWEST)?;
+        ensure!(
+            self.expect_peek(TokenType::LBRACE),
+            "expected next token to be LBRACE, got {:?} instead",
+            self.peek_token.token_type
+        );
+        let mut arms = Vec::new();
+        while self.peek_token.token_type != TokenType::RBRACE {
             self.next_token();
-            return Ok(identifiers);
+            let pattern = self.parse_pattern()?;
+            let guard = if self.expect_peek(TokenType::IF) {
+                self.next_token();
+                Some(self.parse_expression(Precedence::LOWEST)?)
+            } else {
+                None
+            };
+            ensure!(
+                self.expect_peek(TokenType::FAT_ARROW),
+                "expected next token to be FAT_ARROW, got {:?} instead",
+                self.peek_token.token_type
+            );
+            self.next_token();
+            // A block body ends at its closing brace, so the comma after it is optional
+            let is_block = self.cur_token.token_type == TokenType::LBRACE;
+            let body = if is_block {
+                self.parse_block_statement()?
+            } else {
+                Statement::ExpressionStatement(self.parse_expression(Precedence::LOWEST)?)
+            };
+            arms.push(MatchArm {
+                pattern,
+                guard,
+                body,
+            });
+            if !self.expect_peek(TokenType::COMMA) {
+                ensure!(
+                    is_block || self.peek_token.token_type == TokenType::RBRACE,
+                    "expected next token to be COMMA, got {:?} instead",
+                    self.peek_token.token_type
+                );
+            }
         }
         self.next_token();
-        identifiers.push(Expression::Identifier(self.cur_token.literal.to_string()));
-        while self.peek_token.token_type == TokenType::COMMA {
+        Ok(Expression::MatchExpression {
+            subject: Box::new(subject),
+            arms,
+        })
+    }
+
+    fn parse_pattern(&mut self) -> Result<Pattern> {
+        self.enter()?;
+        let pattern = self.parse_unnested_pattern();
+        self.depth -= 1;
+        pattern
+    }
+
+    fn parse_unnested_pattern(&mut self) -> Result<Pattern> {
+        match self.cur_token.token_type {
+            TokenType::IDENT if self.cur_token.literal == "_" => Ok(Pattern::Wildcard),
+            TokenType::IDENT => Ok(Pattern::Binding(Identifier::from(
+                self.cur_token.literal.as_ref(),
+            ))),
+            TokenType::INT | TokenType::STRING | TokenType::TRUE | TokenType::FALSE => {
+                Ok(Pattern::Literal(self.parse_prefix()?))
+            }
+            TokenType::MINUS => {
+                ensure!(
+                    self.peek_token.token_type == TokenType::INT,
+                    "expected next token to be INT, got {:?} instead",
+                    self.peek_token.token_type
+                );
+                Ok(Pattern::Literal(self.parse_prefix_expression()?))
+            }
+            TokenType::LBRACKET => self.parse_array_pattern(),
+            TokenType::LBRACE => self.parse_hash_pattern(),
+            t => bail!("unexpected {:?} in pattern", t),
+        }
+    }
+
+    fn parse_array_pattern(&mut self) -> Result<Pattern> {
+        let mut elements = Vec::new();
+        let mut rest = None;
+        while self.peek_token.token_type != TokenType::RBRACKET {
             self.next_token();
+            if self.cur_token.token_type == TokenType::ELLIPSIS {
+                ensure!(
+                    self.expect_peek(TokenType::IDENT),
+                    "expected next token to be IDENT, got {:?} instead",
+                    self.peek_token.token_type
+                );
+                rest = Some(Identifier::from(self.cur_token.literal.as_ref()));
+                ensure!(
+                    self.peek_token.token_type == TokenType::RBRACKET,
+                    "rest pattern must be the last element, got {:?} after it",
+                    self.peek_token.token_type
+                );
+                break;
+            }
+            elements.push(self.parse_pattern()?);
+            if self.peek_token.token_type != TokenType::RBRACKET {
+                ensure!(
+                    self.expect_peek(TokenType::COMMA),
+                    "expected next token to be COMMA, got {:?} instead",
+                    self.peek_token.token_type
+                );
+            }
+        }
+        self.next_token();
+        Ok(Pattern::Array { elements, rest })
+    }
+
+    fn parse_hash_pattern(&mut self) -> Result<Pattern> {
+        let mut pairs = Vec::new();
+        while self.peek_token.token_type != TokenType::RBRACE {
             self.next_token();
-            identifiers.push(Expression::Identifier(self.cur_token.literal.to_string()));
+            let key = match self.parse_pattern()? {
+                Pattern::Literal(key) => key,
+                pattern => bail!("hash pattern keys must be literals, got {}", pattern),
+            };
+            ensure!(
+                self.expect_peek(TokenType::COLON),
+                "expected next token to be COLON, got {:?} instead",
+                self.peek_token.token_type
+            );
+            self.next_token();
+            let value = self.parse_pattern()?;
+            pairs.push((key, value));
+            if self.peek_token.token_type != TokenType::RBRACE {
+                ensure!(
+                    self.expect_peek(TokenType::COMMA),
+                    "expected next token to be COMMA, got {:?} instead",
+                    self.peek_token.token_type
+                );
+            }
         }
-        ensure!(
-            self.expect_peek(TokenType::RPAREN),
-            "expected next token to be RPAREN, got {:?} instead",
-            self.peek_token.token_type
-        );
-        Ok(identifiers)
+        self.next_token();
+        Ok(Pattern::Hash(pairs))
+    }
+
+    /// Parses the parameter list and the name of the rest parameter, if any
+    fn parse_function_parameters(&mut self) -> Result<(Vec<Parameter>, Option<Identifier>)> {
+        let mut parameters: Vec<Parameter> = Vec::new();
+        let mut rest = None;
+        while self.peek_token.token_type != TokenType::RPAREN {
+            self.next_token();
+            if self.cur_token.token_type == TokenType::ELLIPSIS {
+                ensure!(
+                    self.expect_peek(TokenType::IDENT),
+                    "expected next token to be IDENT, got {:?} instead",
+                    self.peek_token.token_type
+                );
+                rest = Some(Identifier::from(self.cur_token.literal.as_ref()));
+                ensure!(
+                    self.peek_token.token_type == TokenType::RPAREN,
+                    "rest parameter must be the last parameter, got {:?} after it",
+                    self.peek_token.token_type
+                );
+                break;
+            }
+            let pattern = self.parse_pattern()?;
+            let default = if self.peek_token.token_type == TokenType::ASSIGN {
+                self.next_token();
+                self.next_token();
+                Some(self.parse_expression(Precedence::LOWEST)?)
+            } else {
+                ensure!(
+                    parameters.iter().all(|p| p.default.is_none()),
+                    "parameter {} without a default follows a parameter with a default",
+                    pattern
+                );
+                None
+            };
+            parameters.push(Parameter { pattern, default });
+            if self.peek_token.token_type != TokenType::RPAREN {
+                ensure!(
+                    self.expect_peek(TokenType::COMMA),
+                    "expected next token to be COMMA, got {:?} instead",
+                    self.peek_token.token_type
+                );
+            }
+        }
+        self.next_token();
+        Ok((parameters, rest))
     }
 
     fn parse_function_literal(&mut self) -> Result<Expression> {
@@ -335,17 +750,19 @@ impl<'a> Parser<'a> {
             "expected next token to be LPAREN, got {:?} instead",
             self.peek_token.token_type
         );
-        let parameters = self.parse_function_parameters()?;
+        let (parameters, rest) = self.parse_function_parameters()?;
         ensure!(
             self.expect_peek(TokenType::LBRACE),
             "expected next token to be LBRACE, got {:?} instead",
             self.peek_token.token_type
         );
         let body = self.parse_block_statement()?;
-        Ok(Expression::FunctionLiteral {
+        Ok(Expression::FunctionLiteral(Rc::new(FunctionLiteral {
+            name: None,
             parameters,
-            body: Box::new(body),
-        })
+            rest,
+            body,
+        })))
     }
 
     fn parse_infix_expression(&mut self, left: Expression) -> Result<Expression> {
@@ -360,40 +777,111 @@ impl<'a> Parser<'a> {
         })
     }
 
-    fn parse_call_arguments(&mut self) -> Result<Vec<Expression>> {
-        let mut args = Vec::new();
-        if self.peek_token.token_type == TokenType::RPAREN {
+    fn parse_index_expression(&mut self, left: Expression) -> Result<Expression> {
+        self.next_token();
+        let index = self.parse_expression(Precedence::LOWEST)?;
+        ensure!(
+            self.expect_peek(TokenType::RBRACKET),
+            "expected next token to be RBRACKET, got {:?} instead",
+            self.peek_token.token_type
+        );
+        Ok(Expression::IndexExpression {
+            left: Box::new(left),
+            index: Box::new(index),
+        })
+    }
+
+    fn parse_assign_expression(&mut self, left: Expression) -> Result<Expression> {
+        let name = match left {
+            Expression::Identifier(name) => name,
+            _ => bail!("invalid assignment target: {}", left),
+        };
+        let operator = self.cur_token.literal.to_string();
+        self.next_token();
+        // Assignment is right-associative, so the value is parsed at the lowest precedence.
+        let value = self.parse_expression(Precedence::LOWEST)?;
+        Ok(Expression::AssignExpression {
+            name,
+            operator,
+            value: Box::new(value),
+        })
+    }
+
+    /// Parses comma-separated expressions up to the `end` token
+    fn parse_expression_list(&mut self, end: TokenType) -> Result<Vec<Expression>> {
+        let mut list = Vec::new();
+        if self.peek_token.token_type == end {
             self.next_token();
-            return Ok(args);
+            return Ok(list);
         }
         self.next_token();
-        args.push(self.parse_expression(Precedence::LOWEST)?);
+        list.push(self.parse_expression(Precedence::LOWEST)?);
         while self.peek_token.token_type == TokenType::COMMA {
             self.next_token();
             self.next_token();
-            args.push(self.parse_expression(Precedence::LOWEST)?);
+            list.push(self.parse_expression(Precedence::LOWEST)?);
         }
         ensure!(
-            self.expect_peek(TokenType::RPAREN),
-            "expected next token to be RPAREN, got {:?} instead",
+            self.expect_peek(end),
+            "expected next token to be {:?}, got {:?} instead",
+            end,
             self.peek_token.token_type
         );
-        Ok(args)
+        Ok(list)
+    }
+
+    fn parse_call_arguments(&mut self) -> Result<Vec<Argument>> {
+        let mut arguments: Vec<Argument> = Vec::new();
+        while self.peek_token.token_type != TokenType::RPAREN {
+            self.next_token();
+            let argument = match (&self.cur_token.token_type, &self.peek_token.token_type) {
+                (TokenType::ELLIPSIS, _) => {
+                    self.next_token();
+                    Argument::Spread(self.parse_expression(Precedence::LOWEST)?)
+                }
+                (TokenType::IDENT, TokenType::COLON) => {
+                    let name = Symbol::intern(&self.cur_token.literal);
+                    self.next_token();
+                    self.next_token();
+                    Argument::Named(name, self.parse_expression(Precedence::LOWEST)?)
+                }
+                _ => Argument::Positional(self.parse_expression(Precedence::LOWEST)?),
+            };
+            ensure!(
+                matches!(argument, Argument::Named(..))
+                    || !arguments.iter().any(|a| matches!(a, Argument::Named(..))),
+                "positional argument {} follows a named argument",
+                argument
+            );
+            arguments.push(argument);
+            if self.peek_token.token_type != TokenType::RPAREN {
+                ensure!(
+                    self.expect_peek(TokenType::COMMA),
+                    "expected next token to be COMMA, got {:?} instead",
+                    self.peek_token.token_type
+                );
+            }
+        }
+        self.next_token();
+        Ok(arguments)
     }
 
     fn parse_call_expression(&mut self, function: Expression) -> Result<Expression> {
+        let position = self.left_position;
         let arguments = self.parse_call_arguments()?;
         Ok(Expression::CallExpression {
             function: Box::new(function),
             arguments,
+            position,
         })
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::useless_vec)]
 mod tests {
     use super::*;
-    use crate::lexer::Lexer;
+    use crate::lexer::{Lexer, StreamLexer};
 
     #[test]
     fn test_let_statements() {
@@ -411,29 +899,82 @@ let f = false;
 
         let tests = vec![
             Statement::LetStatement {
-                name: "x".to_string(),
+                kind: BindingKind::Let,
+                pattern: Pattern::Binding("x".into()),
                 value: Expression::IntegerLiteral(5),
+                position: Position { line: 2, column: 5 },
             },
             Statement::LetStatement {
-                name: "y".to_string(),
+                kind: BindingKind::Let,
+                pattern: Pattern::Binding("y".into()),
                 value: Expression::IntegerLiteral(10),
+                position: Position { line: 3, column: 5 },
             },
             Statement::LetStatement {
-                name: "foobar".to_string(),
+                kind: BindingKind::Let,
+                pattern: Pattern::Binding("foobar".into()),
                 value: Expression::IntegerLiteral(838383),
+                position: Position { line: 4, column: 5 },
             },
             Statement::LetStatement {
-                name: "t".to_string(),
+                kind: BindingKind::Let,
+                pattern: Pattern::Binding("t".into()),
                 value: Expression::Boolean(true),
+                position: Position { line: 5, column: 5 },
             },
             Statement::LetStatement {
-                name: "f".to_string(),
+                kind: BindingKind::Let,
+                pattern: Pattern::Binding("f".into()),
                 value: Expression::Boolean(false),
+                position: Position { line: 6, column: 5 },
+            },
+        ];
+        for (i, tt) in tests.iter().enumerate() {
+            assert_eq!(&program.statements[i], tt);
+        }
+    }
+
+    #[test]
+    fn test_let_statement_kinds() {
+        let input = r#"
+let x = 5;
+let mut y = 10;
+  const z = 15;
+"#;
+        let l = Lexer::new(input);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().unwrap();
+        assert_eq!(program.statements.len(), 3);
+
+        let tests = vec![
+            Statement::LetStatement {
+                kind: BindingKind::Let,
+                pattern: Pattern::Binding("x".into()),
+                value: Expression::IntegerLiteral(5),
+                position: Position { line: 2, column: 5 },
+            },
+            Statement::LetStatement {
+                kind: BindingKind::LetMut,
+                pattern: Pattern::Binding("y".into()),
+                value: Expression::IntegerLiteral(10),
+                position: Position { line: 3, column: 9 },
+            },
+            Statement::LetStatement {
+                kind: BindingKind::Const,
+                pattern: Pattern::Binding("z".into()),
+                value: Expression::IntegerLiteral(15),
+                position: Position { line: 4, column: 9 },
             },
         ];
         for (i, tt) in tests.iter().enumerate() {
             assert_eq!(&program.statements[i], tt);
         }
+
+        for input in ["let mut = 5;", "const mut x = 5;", "let mut mut x = 5;"] {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            assert!(p.parse_program().is_err());
+        }
     }
 
     #[test]
@@ -549,7 +1090,7 @@ return 838383;
         let program = p.parse_program().unwrap();
         assert_eq!(program.statements.len(), 2);
 
-        let tests = vec![
+        let tests = [
             Expression::PrefixExpression {
                 operator: "!".to_string(),
                 right: Box::new(Expression::IntegerLiteral(5)),
@@ -583,6 +1124,20 @@ return 838383;
                 "3 + 4 * 5 == 3 * 1 + 4 * 5",
                 "((3 + (4 * 5)) == ((3 * 1) + (4 * 5)))",
             ),
+            ("x = y = 5", "(x = (y = 5))"),
+            ("x += 1 + 2 * 3", "(x += (1 + (2 * 3)))"),
+            ("x = a == b", "(x = (a == b))"),
+            (
+                "a * [1, 2, 3, 4][b * c] * d",
+                "((a * ([1, 2, 3, 4][(b * c)])) * d)",
+            ),
+            (
+                "add(a * b[2], b[1], 2 * [1, 2][1])",
+                "add((a * (b[2])), (b[1]), (2 * ([1, 2][1])))",
+            ),
+            ("0..n + 1", "(0 .. (n + 1))"),
+            ("a..=b == c", "((a ..= b) == c)"),
+            ("a < b..c", "(a < (b .. c))"),
         ];
         for (input, expected) in tests {
             let l = Lexer::new(input);
@@ -604,13 +1159,13 @@ if (x < y) { x }
 
         let tests = vec![Statement::ExpressionStatement(Expression::IfExpression {
             condition: Box::new(Expression::InfixExpression {
-                left: Box::new(Expression::Identifier("x".to_string())),
+                left: Box::new(Expression::Identifier("x".into())),
                 operator: "<".to_string(),
-                right: Box::new(Expression::Identifier("y".to_string())),
+                right: Box::new(Expression::Identifier("y".into())),
             }),
             consequence: Box::new(Statement::BlockStatement {
                 statements: vec![Statement::ExpressionStatement(Expression::Identifier(
-                    "x".to_string(),
+                    "x".into(),
                 ))],
             }),
             alternative: None,
@@ -632,18 +1187,18 @@ if (x < y) { x } else { y }
 
         let tests = vec![Statement::ExpressionStatement(Expression::IfExpression {
             condition: Box::new(Expression::InfixExpression {
-                left: Box::new(Expression::Identifier("x".to_string())),
+                left: Box::new(Expression::Identifier("x".into())),
                 operator: "<".to_string(),
-                right: Box::new(Expression::Identifier("y".to_string())),
+                right: Box::new(Expression::Identifier("y".into())),
             }),
             consequence: Box::new(Statement::BlockStatement {
                 statements: vec![Statement::ExpressionStatement(Expression::Identifier(
-                    "x".to_string(),
+                    "x".into(),
                 ))],
             }),
             alternative: Some(Box::new(Statement::BlockStatement {
                 statements: vec![Statement::ExpressionStatement(Expression::Identifier(
-                    "y".to_string(),
+                    "y".into(),
                 ))],
             })),
         })];
@@ -662,23 +1217,31 @@ fn(x, y) { x + y; }
         let program = p.parse_program().unwrap();
         assert_eq!(program.statements.len(), 1);
 
-        let tests = vec![Statement::ExpressionStatement(
-            Expression::FunctionLiteral {
+        let tests = vec![Statement::ExpressionStatement(Expression::FunctionLiteral(
+            Rc::new(FunctionLiteral {
+                name: None,
                 parameters: vec![
-                    Expression::Identifier("x".to_string()),
-                    Expression::Identifier("y".to_string()),
+                    Parameter {
+                        pattern: Pattern::Binding("x".into()),
+                        default: None,
+                    },
+                    Parameter {
+                        pattern: Pattern::Binding("y".into()),
+                        default: None,
+                    },
                 ],
-                body: Box::new(Statement::BlockStatement {
+                rest: None,
+                body: Statement::BlockStatement {
                     statements: vec![Statement::ExpressionStatement(
                         Expression::InfixExpression {
-                            left: Box::new(Expression::Identifier("x".to_string())),
+                            left: Box::new(Expression::Identifier("x".into())),
                             operator: "+".to_string(),
-                            right: Box::new(Expression::Identifier("y".to_string())),
+                            right: Box::new(Expression::Identifier("y".into())),
                         },
                     )],
-                }),
-            },
-        )];
+                },
+            }),
+        ))];
         for (i, tt) in tests.iter().enumerate() {
             assert_eq!(&program.statements[i], tt);
         }
@@ -698,13 +1261,20 @@ fn(x, y) { x + y; }
             let stmt = program.statements.first().unwrap();
             let expected = expected
                 .iter()
-                .map(|s| Expression::Identifier(s.to_string()))
-                .collect::<Vec<Expression>>();
+                .map(|s| Parameter {
+                    pattern: Pattern::Binding((*s).into()),
+                    default: None,
+                })
+                .collect::<Vec<Parameter>>();
             assert_eq!(
-                &Statement::ExpressionStatement(Expression::FunctionLiteral {
-                    parameters: expected,
-                    body: Box::new(Statement::BlockStatement { statements: vec![] })
-                }),
+                &Statement::ExpressionStatement(Expression::FunctionLiteral(Rc::new(
+                    FunctionLiteral {
+                        name: None,
+                        parameters: expected,
+                        rest: None,
+                        body: Statement::BlockStatement { statements: vec![] }
+                    }
+                ))),
                 stmt
             );
         }
@@ -721,23 +1291,501 @@ add(1, 2 * 3, 4 + 5);
         assert_eq!(program.statements.len(), 1);
 
         let tests = vec![Statement::ExpressionStatement(Expression::CallExpression {
-            function: Box::new(Expression::Identifier("add".to_string())),
+            function: Box::new(Expression::Identifier("add".into())),
             arguments: vec![
-                Expression::IntegerLiteral(1),
-                Expression::InfixExpression {
+                Argument::Positional(Expression::IntegerLiteral(1)),
+                Argument::Positional(Expression::InfixExpression {
                     left: Box::new(Expression::IntegerLiteral(2)),
                     operator: "*".to_string(),
                     right: Box::new(Expression::IntegerLiteral(3)),
-                },
-                Expression::InfixExpression {
+                }),
+                Argument::Positional(Expression::InfixExpression {
                     left: Box::new(Expression::IntegerLiteral(4)),
                     operator: "+".to_string(),
                     right: Box::new(Expression::IntegerLiteral(5)),
-                },
+                }),
             ],
+            position: Position { line: 2, column: 1 },
+        })];
+        for (i, tt) in tests.iter().enumerate() {
+            assert_eq!(&program.statements[i], tt);
+        }
+    }
+
+    #[test]
+    fn test_assign_expression_parsing() {
+        let tests = vec![
+            ("x = 5;", "x", "=", Expression::IntegerLiteral(5)),
+            ("x += y;", "x", "+=", Expression::Identifier("y".into())),
+            ("x -= 1;", "x", "-=", Expression::IntegerLiteral(1)),
+            ("x *= 2;", "x", "*=", Expression::IntegerLiteral(2)),
+            ("x /= 3;", "x", "/=", Expression::IntegerLiteral(3)),
+        ];
+        for (input, name, operator, value) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            assert_eq!(program.statements.len(), 1);
+            assert_eq!(
+                program.statements[0],
+                Statement::ExpressionStatement(Expression::AssignExpression {
+                    name: name.into(),
+                    operator: operator.to_string(),
+                    value: Box::new(value),
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_assign_expression_error() {
+        let tests = vec!["5 = 1;", "f(x) = 1;", "(a + b) += 2;"];
+        for input in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            assert!(p.parse_program().is_err());
+        }
+    }
+
+    #[test]
+    fn test_while_statement_parsing() {
+        let input = r#"
+while (x < 10) { x += 1; if (x == 5) { continue; } break; };
+"#;
+        let l = Lexer::new(input);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().unwrap();
+        assert_eq!(program.statements.len(), 1);
+
+        let tests = [Statement::WhileStatement {
+            condition: Expression::InfixExpression {
+                left: Box::new(Expression::Identifier("x".into())),
+                operator: "<".to_string(),
+                right: Box::new(Expression::IntegerLiteral(10)),
+            },
+            body: Box::new(Statement::BlockStatement {
+                statements: vec![
+                    Statement::ExpressionStatement(Expression::AssignExpression {
+                        name: "x".into(),
+                        operator: "+=".to_string(),
+                        value: Box::new(Expression::IntegerLiteral(1)),
+                    }),
+                    Statement::ExpressionStatement(Expression::IfExpression {
+                        condition: Box::new(Expression::InfixExpression {
+                            left: Box::new(Expression::Identifier("x".into())),
+                            operator: "==".to_string(),
+                            right: Box::new(Expression::IntegerLiteral(5)),
+                        }),
+                        consequence: Box::new(Statement::BlockStatement {
+                            statements: vec![Statement::ContinueStatement],
+                        }),
+                        alternative: None,
+                    }),
+                    Statement::BreakStatement,
+                ],
+            }),
+        }];
+        for (i, tt) in tests.iter().enumerate() {
+            assert_eq!(&program.statements[i], tt);
+        }
+    }
+
+    #[test]
+    fn test_collection_literal_parsing() {
+        let tests = [
+            (r#""hello world";"#, r#""hello world""#),
+            ("[]", "[]"),
+            ("[1, 2 * 2, 3 + 3]", "[1, (2 * 2), (3 + 3)]"),
+            ("let h = {};", "let h = {};"),
+            (
+                r#"let h = {"one": 1, "two": 2,};"#,
+                r#"let h = {"one": 1, "two": 2};"#,
+            ),
+            (
+                r#"x = {"a": 0 + 1, true: 10 - 8}"#,
+                r#"(x = {"a": (0 + 1), true: (10 - 8)})"#,
+            ),
+            ("myArray[1 + 1]", "(myArray[(1 + 1)])"),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            assert_eq!(format!("{}", program), expected);
+        }
+
+        let l = Lexer::new(r#"x = {"one": 1, 2: "two"};"#);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().unwrap();
+        assert_eq!(
+            program.statements[0],
+            Statement::ExpressionStatement(Expression::AssignExpression {
+                name: "x".into(),
+                operator: "=".to_string(),
+                value: Box::new(Expression::HashLiteral(vec![
+                    (
+                        Expression::StringLiteral("one".to_string()),
+                        Expression::IntegerLiteral(1)
+                    ),
+                    (
+                        Expression::IntegerLiteral(2),
+                        Expression::StringLiteral("two".to_string())
+                    ),
+                ])),
+            })
+        );
+    }
+
+    #[test]
+    fn test_collection_literal_error() {
+        let tests = [
+            r#"x = {"a" 1}"#,
+            r#"x = {"a": 1 "b": 2}"#,
+            "[1, 2",
+            "a[1",
+            "[1 2]",
+        ];
+        for input in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            assert!(p.parse_program().is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_for_statement_parsing() {
+        let input = r#"
+for (x in 0..10) { total += x; }
+"#;
+        let l = Lexer::new(input);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().unwrap();
+        assert_eq!(program.statements.len(), 1);
+
+        let tests = [Statement::ForStatement {
+            variable: "x".into(),
+            iterable: Expression::InfixExpression {
+                left: Box::new(Expression::IntegerLiteral(0)),
+                operator: "..".to_string(),
+                right: Box::new(Expression::IntegerLiteral(10)),
+            },
+            body: Box::new(Statement::BlockStatement {
+                statements: vec![Statement::ExpressionStatement(
+                    Expression::AssignExpression {
+                        name: "total".into(),
+                        operator: "+=".to_string(),
+                        value: Box::new(Expression::Identifier("x".into())),
+                    },
+                )],
+            }),
+        }];
+        for (i, tt) in tests.iter().enumerate() {
+            assert_eq!(&program.statements[i], tt);
+        }
+
+        for input in [
+            "for x in xs {}",
+            "for (1 in xs) {}",
+            "for (x of xs) {}",
+            "for (x in xs) x",
+        ] {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            assert!(p.parse_program().is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_else_if_expression() {
+        let input = r#"
+if (x < y) { x } else if (x > y) { y } else { 0 }
+"#;
+        let l = Lexer::new(input);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().unwrap();
+        assert_eq!(program.statements.len(), 1);
+
+        let block = |name: &str| {
+            Box::new(Statement::BlockStatement {
+                statements: vec![Statement::ExpressionStatement(Expression::Identifier(
+                    name.into(),
+                ))],
+            })
+        };
+        let tests = [Statement::ExpressionStatement(Expression::IfExpression {
+            condition: Box::new(Expression::InfixExpression {
+                left: Box::new(Expression::Identifier("x".into())),
+                operator: "<".to_string(),
+                right: Box::new(Expression::Identifier("y".into())),
+            }),
+            consequence: block("x"),
+            alternative: Some(Box::new(Statement::ExpressionStatement(
+                Expression::IfExpression {
+                    condition: Box::new(Expression::InfixExpression {
+                        left: Box::new(Expression::Identifier("x".into())),
+                        operator: ">".to_string(),
+                        right: Box::new(Expression::Identifier("y".into())),
+                    }),
+                    consequence: block("y"),
+                    alternative: Some(Box::new(Statement::BlockStatement {
+                        statements: vec![Statement::ExpressionStatement(
+                            Expression::IntegerLiteral(0),
+                        )],
+                    })),
+                },
+            ))),
         })];
         for (i, tt) in tests.iter().enumerate() {
             assert_eq!(&program.statements[i], tt);
         }
+        assert_eq!(
+            format!("{}", program),
+            "if (x < y) {\n x\n} else if (x > y) {\n y\n} else {\n 0\n}"
+        );
+    }
+
+    #[test]
+    fn test_if_statement() {
+        let tests = [
+            ("if (a) { b } -1", "if a {\n b\n}\n(-1)"),
+            (
+                "if (a) { b } else { c } [1, 2]",
+                "if a {\n b\n} else {\n c\n}\n[1, 2]",
+            ),
+            ("if (a) { b }; (c)", "if a {\n b\n}\nc"),
+            (
+                "let x = if (a) { 1 } else { 2 } + 3;",
+                "let x = (if a {\n 1\n} else {\n 2\n} + 3);",
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            assert_eq!(format!("{}", program), expected);
+        }
+
+        for input in ["if (a) { b } else if { c }", "if (a) { b } else if (c) d"] {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            assert!(p.parse_program().is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_match_expression_parsing() {
+        let tests = [
+            (
+                "match x { 1 => a, -1 => b, _ => c }",
+                "match x { 1 => a, (-1) => b, _ => c }",
+            ),
+            (
+                r#"let y = match p { [a, ...rest] if a > 0 => rest, {"k": v} => { v } };"#,
+                "let y = match p { [a, ...rest] if (a > 0) => rest, {\"k\": v} => {\n v\n} };",
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            assert_eq!(format!("{}", program), expected);
+        }
+    }
+
+    #[test]
+    fn test_match_expression_error() {
+        let tests = [
+            "match x { 1 2 }",
+            "match x { [...rest, a] => a }",
+            "match x { {k: v} => v }",
+            "match x { a + b => c }",
+            "match x { _ => a",
+        ];
+        for input in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            assert!(p.parse_program().is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_destructuring_parsing() {
+        let tests = [
+            ("let [a, b] = pair;", "let [a, b] = pair;"),
+            (
+                r#"let mut {"x": x, "y": [_, y]} = point;"#,
+                r#"let mut {"x": x, "y": [_, y]} = point;"#,
+            ),
+            (
+                "let f = fn([a, ...rest], b) { a };",
+                "let f = fn([a, ...rest], b) {\n a\n};",
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            assert_eq!(format!("{}", program), expected);
+        }
+
+        for input in ["let [a b] = pair;", "let (a) = 1;", "fn(a + b) {}"] {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            assert!(p.parse_program().is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_parameter_and_argument_parsing() {
+        let tests = [
+            (
+                "let f = fn(x, y = 10, ...rest) { x };",
+                "let f = fn(x, y = 10, ...rest) {\n x\n};",
+            ),
+            ("fn(...args) {}", "fn(...args) {\n \n}"),
+            ("f(1, ...xs, y: 2 + 3)", "f(1, ...xs, y: (2 + 3))"),
+            ("f(...[1, 2])", "f(...[1, 2])"),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            assert_eq!(format!("{}", program), expected);
+        }
+
+        let tests = [
+            "fn(...rest, x) {}",
+            "fn(x = 1, y) {}",
+            "fn(...) {}",
+            "f(x: 1, 2)",
+            "f(1 2)",
+        ];
+        for input in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            assert!(p.parse_program().is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_function_name_parsing() {
+        let tests = [
+            ("let add = fn(x, y) { x + y; };", Some("add")),
+            ("const add = fn(x, y) { x + y; };", Some("add")),
+            ("let [add] = [fn(x, y) { x + y; }];", None),
+            ("fn(x, y) { x + y; };", None),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let function = match &program.statements[0] {
+                Statement::LetStatement { value, .. } => value,
+                Statement::ExpressionStatement(expr) => expr,
+                stmt => panic!("unexpected statement: {:?}", stmt),
+            };
+            let name = match function {
+                Expression::FunctionLiteral(function) => function.name,
+                Expression::ArrayLiteral(elements) => match &elements[0] {
+                    Expression::FunctionLiteral(function) => function.name,
+                    expr => panic!("unexpected expression: {:?}", expr),
+                },
+                expr => panic!("unexpected expression: {:?}", expr),
+            };
+            assert_eq!(name.map(Symbol::as_str), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_call_expression_position() {
+        let input = "let x = 1 +\n  f(g(1))(2);";
+        let l = Lexer::new(input);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().unwrap();
+        let Statement::LetStatement { value, .. } = &program.statements[0] else {
+            panic!("expected a let statement");
+        };
+        let Expression::InfixExpression { right, .. } = value else {
+            panic!("expected an infix expression");
+        };
+        let Expression::CallExpression {
+            function, position, ..
+        } = right.as_ref()
+        else {
+            panic!("expected a call expression");
+        };
+        assert_eq!(*position, Position { line: 2, column: 3 });
+        let Expression::CallExpression {
+            arguments,
+            position,
+            ..
+        } = function.as_ref()
+        else {
+            panic!("expected a call expression");
+        };
+        assert_eq!(*position, Position { line: 2, column: 3 });
+        let Argument::Positional(Expression::CallExpression { position, .. }) = &arguments[0]
+        else {
+            panic!("expected a call expression");
+        };
+        assert_eq!(*position, Position { line: 2, column: 5 });
+    }
+
+    #[test]
+    fn test_parser_limits() {
+        let tests = [
+            (
+                "99999999999999999999".to_string(),
+                "integer literal out of range: 99999999999999999999",
+            ),
+            (
+                format!("{}1{}", "(".repeat(1000), ")".repeat(1000)),
+                "nesting too deep: more than 256 levels",
+            ),
+            (
+                format!("{}1{}", "{".repeat(1000), "}".repeat(1000)),
+                "nesting too deep: more than 256 levels",
+            ),
+            (
+                format!("let {}x{} = 1;", "[".repeat(1000), "]".repeat(1000)),
+                "nesting too deep: more than 256 levels",
+            ),
+            (
+                format!("let x = 1{};", "+1".repeat(20000)),
+                "nesting too deep: more than 256 levels",
+            ),
+            (
+                format!("f{}", "()".repeat(1000)),
+                "nesting too deep: more than 256 levels",
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(&input);
+            let mut p = Parser::new(l);
+            assert_eq!(p.parse_program().unwrap_err().to_string(), expected);
+        }
+
+        for input in [
+            format!("{}1{}", "(".repeat(200), ")".repeat(200)),
+            format!("let x = 1{};", "+1".repeat(200)),
+        ] {
+            let l = Lexer::new(&input);
+            let mut p = Parser::new(l);
+            assert!(p.parse_program().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_parse_stream() {
+        let input = "let greet = fn(name) {\n  \"hello,\n\" + name\n};\ngreet(\"you\")";
+        let expected = Parser::new(Lexer::new(input)).parse_program().unwrap();
+        let mut p = Parser::new(StreamLexer::new(input.as_bytes()));
+        assert_eq!(p.parse_program().unwrap(), expected);
+
+        let mut p = Parser::new(StreamLexer::new(&b"let x = 1;\nlet y = \xff;"[..]));
+        assert_eq!(
+            p.parse_program().unwrap_err().to_string(),
+            "cannot read input: stream did not contain valid UTF-8"
+        );
     }
 }
@@ -0,0 +1,663 @@
+use crate::ast::{
+    Argument, BindingKind, Expression, FunctionLiteral, Identifier, MatchArm, Pattern, Program,
+    Slot, Statement,
+};
+use crate::builtin::Builtin;
+use crate::symbol::{Symbol, SymbolMap};
+use crate::token::Position;
+use anyhow::{bail, Result};
+
+#[derive(Debug, Clone, Copy)]
+struct Declaration {
+    kind: BindingKind,
+    position: Option<Position>,
+}
+
+/// Function parameters and pattern bindings are immutable and have no declaration site of their own
+const IMMUTABLE: Declaration = Declaration {
+    kind: BindingKind::Let,
+    position: None,
+};
+
+/// Bindings made by earlier programs in the same environment, whose kinds are only known at run time
+const EARLIER: Declaration = Declaration {
+    kind: BindingKind::LetMut,
+    position: None,
+};
+
+type Declarations = SymbolMap<Vec<Declaration>>;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum ScopeKind {
+    Program,
+    Function,
+    Loop,
+    MatchArm,
+}
+
+/// The names declared by the top-level program, a function body, a loop body or a match arm.
+/// Other blocks do not introduce scopes, so declarations inside `if` branches belong to the enclosing one.
+#[derive(Debug)]
+struct Scope {
+    kind: ScopeKind,
+    // Every declaration in the scope, including the ones after the statement being checked
+    all: Declarations,
+    // The declarations that precede the statement being checked
+    seen: Declarations,
+    // The slot of each name, in the order the names are first declared
+    slots: SymbolMap<usize>,
+}
+
+impl Scope {
+    fn new(kind: ScopeKind) -> Self {
+        Self {
+            kind,
+            all: Declarations::default(),
+            seen: Declarations::default(),
+            slots: SymbolMap::default(),
+        }
+    }
+
+    fn slot(&mut self, name: Symbol) -> usize {
+        let next = self.slots.len();
+        *self.slots.entry(name).or_insert(next)
+    }
+
+    /// Records a declaration anywhere in the scope
+    fn collect(&mut self, name: Symbol, declaration: Declaration) {
+        self.slot(name);
+        declare(&mut self.all, name, declaration);
+    }
+
+    /// Records every name bound by the pattern anywhere in the scope
+    fn collect_pattern(&mut self, pattern: &Pattern, declaration: Declaration) {
+        for_each_binding(pattern, &mut |identifier| {
+            self.collect(identifier.name, declaration)
+        });
+    }
+
+    /// Records a declaration that precedes the statement being checked, and stores its slot
+    fn bind(&mut self, identifier: &Identifier, declaration: Declaration) {
+        let index = self.slot(identifier.name);
+        identifier.slot.set(Some(Slot { depth: 0, index }));
+        declare(&mut self.seen, identifier.name, declaration);
+    }
+
+    fn bind_pattern(&mut self, pattern: &Pattern, declaration: Declaration) {
+        for_each_binding(pattern, &mut |identifier| {
+            self.bind(identifier, declaration)
+        });
+    }
+}
+
+/// Checks the program for errors that can be detected before evaluation,
+/// such as assignments to bindings that are never mutable.
+pub fn check_program(program: &Program) -> Result<()> {
+    resolve_program(program, &mut SymbolMap::default())
+}
+
+/// Checks the program and stores in each identifier the slot its variable occupies at run time.
+/// `globals` holds the slots of the names that earlier programs bound at the top level,
+/// and gains the names this program declares there.
+pub fn resolve_program(program: &Program, globals: &mut SymbolMap<usize>) -> Result<()> {
+    let mut scope = Scope::new(ScopeKind::Program);
+    for &name in globals.keys() {
+        declare(&mut scope.seen, name, EARLIER);
+    }
+    scope.slots = globals.clone();
+    scope.all = scope.seen.clone();
+    for statement in &program.statements {
+        collect_statement(statement, &mut scope);
+    }
+    let mut resolver = Resolver {
+        scopes: vec![scope],
+        deferred: 0,
+    };
+    for statement in &program.statements {
+        resolver.check_statement(statement)?;
+    }
+    let scope = resolver.scopes.pop().expect("the program scope");
+    *globals = scope.slots;
+    Ok(())
+}
+
+/// Returns whether the statement declares variables in the scope it runs in
+pub(crate) fn declares(statement: &Statement) -> bool {
+    let mut scope = Scope::new(ScopeKind::Program);
+    collect_statement(statement, &mut scope);
+    !scope.all.is_empty()
+}
+
+fn declare(declarations: &mut Declarations, name: Symbol, declaration: Declaration) {
+    declarations.entry(name).or_default().push(declaration);
+}
+
+/// Calls `f` with every identifier the pattern binds
+fn for_each_binding(pattern: &Pattern, f: &mut impl FnMut(&Identifier)) {
+    match pattern {
+        Pattern::Binding(identifier) => f(identifier),
+        Pattern::Array { elements, rest } => {
+            for element in elements {
+                for_each_binding(element, f);
+            }
+            // `..._` skips the rest of the elements without binding them
+            if let Some(rest) = rest.as_ref().filter(|rest| rest.name.as_str() != "_") {
+                f(rest);
+            }
+        }
+        Pattern::Hash(pairs) => {
+            for (_, value) in pairs {
+                for_each_binding(value, f);
+            }
+        }
+        Pattern::Wildcard | Pattern::Literal(_) => {}
+    }
+}
+
+/// Collects the declarations made by the statement in the current scope
+fn collect_statement(statement: &Statement, scope: &mut Scope) {
+    match statement {
+        Statement::LetStatement {
+            kind,
+            pattern,
+            value,
+            position,
+        } => {
+            collect_expression(value, scope);
+            let declaration = Declaration {
+                kind: *kind,
+                position: Some(*position),
+            };
+            scope.collect_pattern(pattern, declaration);
+        }
+        Statement::ReturnStatement(expr) => collect_expression(expr, scope),
+        Statement::ExpressionStatement(expr) => collect_expression(expr, scope),
+        Statement::BlockStatement { statements } => {
+            for statement in statements {
+                collect_statement(statement, scope);
+            }
+        }
+        // The loop body is a scope of its own
+        Statement::WhileStatement { condition, .. } => collect_expression(condition, scope),
+        Statement::ForStatement { iterable, .. } => collect_expression(iterable, scope),
+        Statement::EmptyStatement | Statement::BreakStatement | Statement::ContinueStatement => {}
+    }
+}
+
+/// Collects the declarations made inside the expression, without descending into function bodies
+fn collect_expression(expression: &Expression, scope: &mut Scope) {
+    match expression {
+        Expression::PrefixExpression { right, .. } => collect_expression(right, scope),
+        Expression::InfixExpression { left, right, .. } => {
+            collect_expression(left, scope);
+            collect_expression(right, scope);
+        }
+        Expression::IfExpression {
+            condition,
+            consequence,
+            alternative,
+        } => {
+            collect_expression(condition, scope);
+            collect_statement(consequence, scope);
+            if let Some(alt) = alternative {
+                collect_statement(alt, scope);
+            }
+        }
+        Expression::CallExpression {
+            function,
+            arguments,
+            ..
+        } => {
+            collect_expression(function, scope);
+            for arg in arguments {
+                let (Argument::Positional(arg) | Argument::Spread(arg) | Argument::Named(_, arg)) =
+                    arg;
+                collect_expression(arg, scope);
+            }
+        }
+        Expression::AssignExpression { value, .. } => collect_expression(value, scope),
+        // Each match arm is a scope of its own
+        Expression::MatchExpression { subject, .. } => collect_expression(subject, scope),
+        Expression::ArrayLiteral(elements) => {
+            for element in elements {
+                collect_expression(element, scope);
+            }
+        }
+        Expression::HashLiteral(pairs) => {
+            for (key, value) in pairs {
+                collect_expression(key, scope);
+                collect_expression(value, scope);
+            }
+        }
+        Expression::IndexExpression { left, index } => {
+            collect_expression(left, scope);
+            collect_expression(index, scope);
+        }
+        Expression::EmptyExpression
+        | Expression::Identifier(_)
+        | Expression::IntegerLiteral(_)
+        | Expression::StringLiteral(_)
+        | Expression::Boolean(_)
+        | Expression::FunctionLiteral { .. } => {}
+    }
+}
+
+struct Resolver {
+    scopes: Vec<Scope>,
+    // Scopes below this position in the stack are searched like scopes outside the current function
+    deferred: usize,
+}
+
+impl Resolver {
+    fn check_statement(&mut self, statement: &Statement) -> Result<()> {
+        match statement {
+            Statement::LetStatement {
+                kind,
+                pattern,
+                value,
+                position,
+            } => {
+                self.check_expression(value)?;
+                let declaration = Declaration {
+                    kind: *kind,
+                    position: Some(*position),
+                };
+                let scope = self.scopes.last_mut().unwrap();
+                scope.bind_pattern(pattern, declaration);
+                Ok(())
+            }
+            Statement::ReturnStatement(expr) => self.check_expression(expr),
+            Statement::ExpressionStatement(expr) => self.check_expression(expr),
+            Statement::BlockStatement { statements } => {
+                for statement in statements {
+                    self.check_statement(statement)?;
+                }
+                Ok(())
+            }
+            Statement::WhileStatement { condition, body } => {
+                self.check_expression(condition)?;
+                self.check_scope(Scope::new(ScopeKind::Loop), body)
+            }
+            Statement::ForStatement {
+                variable,
+                iterable,
+                body,
+            } => {
+                self.check_expression(iterable)?;
+                let mut scope = Scope::new(ScopeKind::Loop);
+                scope.bind(variable, IMMUTABLE);
+                self.check_scope(scope, body)
+            }
+            Statement::BreakStatement => self.check_loop_control("break"),
+            Statement::ContinueStatement => self.check_loop_control("continue"),
+            Statement::EmptyStatement => Ok(()),
+        }
+    }
+
+    fn check_expression(&mut self, expression: &Expression) -> Result<()> {
+        match expression {
+            Expression::PrefixExpression { right, .. } => self.check_expression(right),
+            Expression::InfixExpression { left, right, .. } => {
+                self.check_expression(left)?;
+                self.check_expression(right)
+            }
+            Expression::IfExpression {
+                condition,
+                consequence,
+                alternative,
+            } => {
+                self.check_expression(condition)?;
+                self.check_statement(consequence)?;
+                if let Some(alt) = alternative {
+                    self.check_statement(alt)?;
+                }
+                Ok(())
+            }
+            Expression::MatchExpression { subject, arms } => {
+                self.check_expression(subject)?;
+                for arm in arms {
+                    self.check_match_arm(arm)?;
+                }
+                Ok(())
+            }
+            Expression::FunctionLiteral(function) => self.check_function(function),
+            Expression::CallExpression {
+                function,
+                arguments,
+                ..
+            } => {
+                self.check_expression(function)?;
+                for arg in arguments {
+                    let (Argument::Positional(arg)
+                    | Argument::Spread(arg)
+                    | Argument::Named(_, arg)) = arg;
+                    self.check_expression(arg)?;
+                }
+                Ok(())
+            }
+            Expression::AssignExpression { name, value, .. } => {
+                self.check_expression(value)?;
+                self.check_assignment(name)
+            }
+            Expression::Identifier(identifier) => self.check_identifier(identifier),
+            Expression::ArrayLiteral(elements) => {
+                for element in elements {
+                    self.check_expression(element)?;
+                }
+                Ok(())
+            }
+            Expression::HashLiteral(pairs) => {
+                for (key, value) in pairs {
+                    self.check_expression(key)?;
+                    self.check_expression(value)?;
+                }
+                Ok(())
+            }
+            Expression::IndexExpression { left, index } => {
+                self.check_expression(left)?;
+                self.check_expression(index)
+            }
+            Expression::EmptyExpression
+            | Expression::IntegerLiteral(_)
+            | Expression::StringLiteral(_)
+            | Expression::Boolean(_) => Ok(()),
+        }
+    }
+
+    fn check_function(&mut self, function: &FunctionLiteral) -> Result<()> {
+        // Defaults are evaluated in the scope the function is defined in, but only when it is called
+        let deferred = std::mem::replace(&mut self.deferred, self.scopes.len());
+        let result = function
+            .parameters
+            .iter()
+            .filter_map(|param| param.default.as_ref())
+            .try_for_each(|default| self.check_expression(default));
+        self.deferred = deferred;
+        result?;
+
+        let mut scope = Scope::new(ScopeKind::Function);
+        for param in &function.parameters {
+            scope.bind_pattern(&param.pattern, IMMUTABLE);
+        }
+        if let Some(rest) = &function.rest {
+            scope.bind(rest, IMMUTABLE);
+        }
+        self.check_scope(scope, &function.body)
+    }
+
+    /// Checks `body` in a new scope that starts with the bindings already made in it
+    fn check_scope(&mut self, mut scope: Scope, body: &Statement) -> Result<()> {
+        scope.all = scope.seen.clone();
+        collect_statement(body, &mut scope);
+
+        self.scopes.push(scope);
+        let result = self.check_statement(body);
+        self.scopes.pop();
+        result
+    }
+
+    /// Checks the guard and body of the arm in a new scope holding the pattern bindings
+    fn check_match_arm(&mut self, arm: &MatchArm) -> Result<()> {
+        let mut scope = Scope::new(ScopeKind::MatchArm);
+        scope.bind_pattern(&arm.pattern, IMMUTABLE);
+        scope.all = scope.seen.clone();
+        if let Some(guard) = &arm.guard {
+            collect_expression(guard, &mut scope);
+        }
+        collect_statement(&arm.body, &mut scope);
+
+        self.scopes.push(scope);
+        let result = match &arm.guard {
+            Some(guard) => self.check_expression(guard),
+            None => Ok(()),
+        };
+        let result = result.and_then(|_| self.check_statement(&arm.body));
+        self.scopes.pop();
+        result
+    }
+
+    /// Reports `break` or `continue` statements that are not inside a loop of the current function
+    fn check_loop_control(&self, keyword: &str) -> Result<()> {
+        for scope in self.scopes.iter().rev() {
+            match scope.kind {
+                ScopeKind::Loop => return Ok(()),
+                ScopeKind::MatchArm => continue,
+                ScopeKind::Function | ScopeKind::Program => break,
+            }
+        }
+        bail!("{} outside of loop", keyword)
+    }
+
+    /// Finds the innermost scope that can hold a binding of `name` when the current expression runs,
+    /// and returns its position in the scope stack with the declarations of `name` in it.
+    /// Scopes outside the current function may declare the name after the function is created
+    /// but before it is called, so all of their declarations count.
+    fn lookup(&self, name: Symbol) -> Option<(usize, &[Declaration])> {
+        let mut outside_function = false;
+        for (i, scope) in self.scopes.iter().enumerate().rev() {
+            let declarations = if outside_function || i < self.deferred {
+                &scope.all
+            } else {
+                &scope.seen
+            };
+            if let Some(declarations) = declarations.get(&name) {
+                return Some((i, declarations));
+            }
+            outside_function |= scope.kind == ScopeKind::Function;
+        }
+        None
+    }
+
+    /// Stores the slot `identifier` has in the scope at position `i` of the scope stack
+    fn resolve(&self, identifier: &Identifier, i: usize) {
+        let slot = Slot {
+            depth: self.scopes.len() - 1 - i,
+            index: self.scopes[i].slots[&identifier.name],
+        };
+        identifier.slot.set(Some(slot));
+    }
+
+    /// Reports uses of names that no enclosing scope declares. Builtins are left without a slot.
+    fn check_identifier(&self, identifier: &Identifier) -> Result<()> {
+        match self.lookup(identifier.name) {
+            Some((i, _)) => {
+                self.resolve(identifier, i);
+                Ok(())
+            }
+            None if Builtin::lookup(identifier.name).is_some() => {
+                identifier.slot.set(None);
+                Ok(())
+            }
+            None => bail!("identifier not found: {}", identifier),
+        }
+    }
+
+    /// Reports an assignment to `name` if it is undefined or every binding it can refer to is immutable.
+    /// Assignments whose target depends on the order of evaluation are left to the evaluator.
+    fn check_assignment(&self, name: &Identifier) -> Result<()> {
+        let Some((i, declarations)) = self.lookup(name.name) else {
+            bail!("cannot assign to undefined variable: {}", name);
+        };
+        if !declarations.iter().any(|d| d.kind.is_mutable()) {
+            let declared_at = match declarations.last().and_then(|d| d.position) {
+                Some(position) => format!(" (declared at {})", position),
+                None => "".to_string(),
+            };
+            bail!(
+                "cannot assign to immutable binding: {}{}",
+                name,
+                declared_at
+            );
+        }
+        self.resolve(name, i);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_check_assignment() {
+        let tests = [
+            "let mut x = 1; x = 2;",
+            "let mut x = 1; let f = fn() { x += 1; };",
+            "let f = fn() { x = 1; }; let mut x = 0;",
+            "let x = 1; let mut x = 2; x = 3;",
+            "let x = 1; let f = fn() { let mut x = 2; x = 3; };",
+        ];
+        for input in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            assert!(check_program(&program).is_ok(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_check_assignment_error() {
+        let tests = [
+            (
+                "let x = 1; x = 2;",
+                "cannot assign to immutable binding: x (declared at 1:5)",
+            ),
+            (
+                "const x = 1;\nlet f = fn() { x += 1; };",
+                "cannot assign to immutable binding: x (declared at 1:7)",
+            ),
+            (
+                "let mut x = 1; let f = fn() { let x = 2; x = 3; };",
+                "cannot assign to immutable binding: x (declared at 1:35)",
+            ),
+            (
+                "let f = fn(n) { n = n - 1; };",
+                "cannot assign to immutable binding: n",
+            ),
+            (
+                "let x = 1; if (true) { x = 2; }",
+                "cannot assign to immutable binding: x (declared at 1:5)",
+            ),
+            (
+                "let [a, {\"b\": b}] = [1, {\"b\": 2}]; b = 3;",
+                "cannot assign to immutable binding: b (declared at 1:5)",
+            ),
+            (
+                "let f = fn([a, b]) { a += b; };",
+                "cannot assign to immutable binding: a",
+            ),
+            ("x = 1;", "cannot assign to undefined variable: x"),
+            (
+                "let f = fn() { while (true) { x = 1; } let mut x = 0; };",
+                "cannot assign to undefined variable: x",
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let checked = check_program(&program);
+            assert_eq!(checked.err().unwrap().to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_check_loop_control() {
+        let tests = [
+            ("while (true) { break; }", None),
+            ("while (true) { if (true) { continue; } }", None),
+            ("let f = fn() { while (true) { break; } };", None),
+            ("break;", Some("break outside of loop")),
+            (
+                "let f = fn() { continue; };",
+                Some("continue outside of loop"),
+            ),
+            (
+                "while (true) { let f = fn() { break; }; }",
+                Some("break outside of loop"),
+            ),
+            ("while (true) { match 1 { _ => { break; } } }", None),
+            (
+                "match 1 { _ => { continue; } }",
+                Some("continue outside of loop"),
+            ),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let checked = check_program(&program);
+            assert_eq!(checked.err().map(|e| e.to_string()).as_deref(), expected);
+        }
+    }
+
+    #[test]
+    fn test_check_identifier() {
+        let tests = [
+            ("let x = 1; x;", None),
+            ("let f = fn() { g() }; let g = fn() { 1 };", None),
+            ("let f = fn(n = m) { n }; let m = 1;", None),
+            ("if (true) { let x = 1; } x;", None),
+            (
+                "match [1] { [x, ...xs] => xs }; xs;",
+                Some("identifier not found: xs"),
+            ),
+            ("x; let x = 1;", Some("identifier not found: x")),
+            (
+                "let f = fn() { y }; let g = fn(y) { y };",
+                Some("identifier not found: y"),
+            ),
+            ("for (x in [1]) { x }; x;", Some("identifier not found: x")),
+        ];
+        for (input, expected) in tests {
+            let l = Lexer::new(input);
+            let mut p = Parser::new(l);
+            let program = p.parse_program().unwrap();
+            let checked = check_program(&program);
+            assert_eq!(checked.err().map(|e| e.to_string()).as_deref(), expected);
+        }
+    }
+
+    #[test]
+    fn test_resolve_program() {
+        let input = "let a = 1; let b = 2; let b = 3; fn(c) { [c, b, a] }";
+        let l = Lexer::new(input);
+        let mut p = Parser::new(l);
+        let program = p.parse_program().unwrap();
+        // Names bound by an earlier program keep their slots.
+        let mut globals = SymbolMap::from_iter([(Symbol::intern("z"), 0)]);
+        resolve_program(&program, &mut globals).unwrap();
+        assert_eq!(
+            globals,
+            SymbolMap::from_iter([
+                (Symbol::intern("z"), 0),
+                (Symbol::intern("a"), 1),
+                (Symbol::intern("b"), 2)
+            ])
+        );
+
+        let Statement::ExpressionStatement(Expression::FunctionLiteral(function)) =
+            &program.statements[3]
+        else {
+            panic!("not a function literal: {}", program.statements[3]);
+        };
+        let Statement::BlockStatement { statements } = &function.body else {
+            panic!("not a block: {}", function.body);
+        };
+        let Statement::ExpressionStatement(Expression::ArrayLiteral(elements)) = &statements[0]
+        else {
+            panic!("not an array literal: {}", statements[0]);
+        };
+        let slots = elements
+            .iter()
+            .map(|element| match element {
+                Expression::Identifier(identifier) => identifier.slot.get(),
+                _ => None,
+            })
+            .collect::<Vec<Option<Slot>>>();
+        let expected = [(0, 0), (1, 2), (1, 1)].map(|(depth, index)| Some(Slot { depth, index }));
+        assert_eq!(slots, expected);
+    }
+}
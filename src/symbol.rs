@@ -0,0 +1,179 @@
+//! Interned names.
+//!
+//! The parser, the resolver and both engines refer to variables by symbols, which are
+//! compared and hashed as pointers to their text. The symbol table is shared by every thread and
+//! never shrinks, so the text of a symbol lives as long as the process. Only interning a name takes
+//! the lock on the table; reading the name of a symbol does not.
+//!
+//! Since the table never shrinks, parsing and decoding record how much memory the names they added
+//! take (see `added_size`), and the engines count that memory against their memory limit.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::sync::{LazyLock, Mutex, PoisonError};
+
+/// An interned name
+#[derive(Clone, Copy)]
+pub struct Symbol(&'static &'static str);
+
+/// The memory a symbol takes besides its text: the reference to the text and the entry in the table
+const SYMBOL_OVERHEAD: usize = 4 * std::mem::size_of::<usize>();
+
+static ANONYMOUS_NAME: &str = "<anonymous>";
+
+static INTERNER: LazyLock<Mutex<HashMap<&'static str, Symbol>>> = LazyLock::new(|| {
+    let symbol = Symbol::ANONYMOUS;
+    Mutex::new(HashMap::from([(*symbol.0, symbol)]))
+});
+
+/// A hash map keyed by symbols, which hashes their addresses instead of running SipHash
+pub type SymbolMap<V> = HashMap<Symbol, V, BuildHasherDefault<SymbolHasher>>;
+
+/// Hashes the address of a symbol. The address is unique to the name and cannot be chosen by
+/// scripts, so it needs no protection against collisions.
+#[derive(Default)]
+pub struct SymbolHasher(u64);
+
+impl Hasher for SymbolHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u64(u64::from(byte));
+        }
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.write_u64(n as u64);
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        // Addresses are aligned, so the low bits are mixed with the high ones.
+        let hash = (self.0.rotate_left(5) ^ n).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        self.0 = hash ^ (hash >> 32);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+thread_local! {
+    /// The memory taken by the symbols the current thread added to the table
+    static ADDED_SIZE: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Returns the memory taken by the symbols the current thread has added to the table so far.
+/// The difference between two calls is what the code run in between added.
+pub fn added_size() -> usize {
+    ADDED_SIZE.with(Cell::get)
+}
+
+impl Symbol {
+    /// Names the calls whose callee is not a variable in stack traces. It is in the table from
+    /// the start, so that calls can refer to it without looking it up.
+    pub const ANONYMOUS: Symbol = Symbol(&ANONYMOUS_NAME);
+
+    /// Returns the symbol of `name`, adding it to the table if it is new
+    pub fn intern(name: &str) -> Self {
+        // The table is consistent even if a thread panicked while holding the lock.
+        let mut interner = INTERNER.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(&symbol) = interner.get(name) {
+            return symbol;
+        }
+        let text: &'static str = Box::leak(name.into());
+        let symbol = Symbol(Box::leak(Box::new(text)));
+        interner.insert(text, symbol);
+        ADDED_SIZE.with(|size| size.set(size.get() + text.len() + SYMBOL_OVERHEAD));
+        symbol
+    }
+
+    pub fn as_str(self) -> &'static str {
+        self.0
+    }
+}
+
+// Each name is interned once, so symbols are equal exactly when they refer to the same text.
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self.0, other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::ptr::hash(self.0, state)
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Self {
+        Symbol::intern(name)
+    }
+}
+
+impl Display for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+// Symbols print as their names, so that the debug output of syntax trees stays readable.
+impl Debug for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern() {
+        let a = Symbol::intern("alpha");
+        let b = Symbol::intern("beta");
+        assert_eq!(Symbol::intern("alpha"), a);
+        assert_ne!(a, b);
+        assert_eq!(a.as_str(), "alpha");
+        assert_eq!(b.to_string(), "beta");
+        assert_eq!(format!("{:?}", a), "\"alpha\"");
+        assert_eq!(Symbol::intern("<anonymous>"), Symbol::ANONYMOUS);
+
+        // Symbols are shared between threads.
+        let c = std::thread::spawn(|| Symbol::intern("alpha"))
+            .join()
+            .unwrap();
+        assert_eq!(c, a);
+    }
+
+    #[test]
+    fn test_added_size() {
+        // Only names new to the table add to it, and only on the thread that interned them.
+        let start = added_size();
+        Symbol::intern("gamma");
+        let added = added_size() - start;
+        assert_eq!(added, "gamma".len() + SYMBOL_OVERHEAD);
+        Symbol::intern("gamma");
+        assert_eq!(added_size() - start, added);
+        std::thread::spawn(|| Symbol::intern("delta"))
+            .join()
+            .unwrap();
+        assert_eq!(added_size() - start, added);
+    }
+
+    #[test]
+    fn test_symbol_map() {
+        let names: Vec<Symbol> = (0..1000)
+            .map(|i| Symbol::intern(&format!("map{}", i)))
+            .collect();
+        let map: SymbolMap<usize> = names.iter().enumerate().map(|(i, &s)| (s, i)).collect();
+        assert_eq!(map.len(), names.len());
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(map.get(name), Some(&i));
+        }
+        assert_eq!(map.get(&Symbol::intern("map1000")), None);
+    }
+}
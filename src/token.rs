@@ -1,3 +1,5 @@
+use std::borrow::Cow;
+
 #[derive(Debug, PartialEq, Clone, Copy, Eq, Hash)]
 #[allow(non_camel_case_types)]
 pub enum TokenType {
@@ -7,9 +9,14 @@ pub enum TokenType {
     // Identifiers + literals
     IDENT,
     INT,
+    STRING,
 
     // Operators
     ASSIGN,
+    PLUS_ASSIGN,
+    MINUS_ASSIGN,
+    ASTERISK_ASSIGN,
+    SLASH_ASSIGN,
     PLUS,
     MINUS,
     BANG,
@@ -21,41 +28,90 @@ pub enum TokenType {
     EQ,
     NOT_EQ,
 
+    DOT_DOT,
+    DOT_DOT_EQ,
+    ELLIPSIS,
+    FAT_ARROW,
+
     // Delimiters
     COMMA,
     SEMICOLON,
+    COLON,
 
     LPAREN,
     RPAREN,
     LBRACE,
     RBRACE,
+    LBRACKET,
+    RBRACKET,
 
     // Keywords
     FUNCTION,
     LET,
+    MUT,
+    CONST,
     TRUE,
     FALSE,
     IF,
     ELSE,
+    MATCH,
     RETURN,
+    WHILE,
+    FOR,
+    IN,
+    BREAK,
+    CONTINUE,
 }
 
+/// A token and its text. The text is borrowed from the input, except for string literals
+/// with escape sequences, whose contents are unescaped.
 #[derive(Debug, PartialEq, Clone, Eq, Hash)]
-pub struct Token {
+pub struct Token<'a> {
     pub token_type: TokenType,
-    pub literal: String,
+    pub literal: Cow<'a, str>,
+}
+
+impl Token<'_> {
+    /// Returns the token with a literal of its own, which does not borrow the input
+    pub fn into_owned(self) -> Token<'static> {
+        Token {
+            token_type: self.token_type,
+            literal: Cow::Owned(self.literal.into_owned()),
+        }
+    }
+}
+
+/// Line and column (both 1-based) of a token in the source
+#[derive(Debug, Default, PartialEq, Clone, Copy, Eq, Hash)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl std::fmt::Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
 }
 
 impl TokenType {
-    pub fn lookup_ident(ident: &String) -> TokenType {
-        match ident.as_str() {
+    pub fn lookup_ident(ident: &str) -> TokenType {
+        match ident {
             "fn" => TokenType::FUNCTION,
             "let" => TokenType::LET,
+            "mut" => TokenType::MUT,
+            "const" => TokenType::CONST,
             "true" => TokenType::TRUE,
             "false" => TokenType::FALSE,
             "if" => TokenType::IF,
             "else" => TokenType::ELSE,
+            "match" => TokenType::MATCH,
             "return" => TokenType::RETURN,
+            "while" => TokenType::WHILE,
+            "for" => TokenType::FOR,
+            "in" => TokenType::IN,
+            "break" => TokenType::BREAK,
+            "continue" => TokenType::CONTINUE,
             _ => TokenType::IDENT,
         }
     }
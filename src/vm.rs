@@ -0,0 +1,1124 @@
+use crate::ast::Program;
+use crate::builtin::Builtin;
+use crate::code::{
+    ArgumentKind, Bytecode, CallSite, CompiledFunction, CompiledPattern, Constant, ConstantIndex,
+    Instruction, PatternNode,
+};
+use crate::compiler;
+use crate::error::{self, RuntimeError};
+use crate::evaluator::{
+    eval_bang_prefix_expression, eval_index_expression, eval_infix_expression,
+    eval_minus_prefix_operator_expression, render_arguments, result_size, CancellationToken,
+    NamedArguments, CANCELLATION_CHECK_INTERVAL, DEFAULT_MAX_DEPTH, DEFAULT_SOURCE_NAME,
+};
+use crate::gc;
+use crate::object::{binding_size, Env, Environment, MemoryMeter, Object, ENVIRONMENT_SIZE};
+use crate::symbol::Symbol;
+use crate::token::Position;
+use anyhow::{anyhow, bail, Result};
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// A call being executed by the virtual machine
+struct Frame {
+    function: Rc<CompiledFunction>,
+    /// The address of the next instruction
+    ip: usize,
+    /// The current scope
+    env: Env,
+    /// The scopes entered within the call that enclose the current one, outermost first
+    scopes: Vec<Env>,
+    /// The loops the current instruction is in, innermost last
+    loops: Vec<Loop>,
+    /// The items left to the for loops the current instruction is in, innermost last
+    iterators: Vec<Box<dyn Iterator<Item = Object>>>,
+    /// The length of the stack below the function and the arguments of the call
+    stack_base: usize,
+    /// The variables bound by the parameters so far
+    bindings: Vec<(Symbol, Object)>,
+    /// The call that created the frame, or None for the top level
+    call: Option<Call>,
+}
+
+/// A call, kept for the stack trace of errors raised inside it. The arguments stay on the stack
+/// below the values of the call, where parameters bind copies of them, and they are only rendered
+/// for an error.
+struct Call {
+    function: Symbol,
+    call_site: ConstantIndex,
+    position: Position,
+    /// Where the arguments start on the stack: the positional ones, with spread arrays expanded,
+    /// then the named ones in the order of the call site
+    arguments: usize,
+    positional: usize,
+    named: usize,
+}
+
+/// What `break` and `continue` return to
+struct Loop {
+    exit: usize,
+    next: usize,
+    stack_len: usize,
+    scopes_len: usize,
+}
+
+impl Frame {
+    fn new(
+        function: Rc<CompiledFunction>,
+        env: Env,
+        stack_base: usize,
+        call: Option<Call>,
+    ) -> Self {
+        Self {
+            function,
+            ip: 0,
+            env,
+            scopes: Vec::new(),
+            loops: Vec::new(),
+            iterators: Vec::new(),
+            stack_base,
+            bindings: Vec::new(),
+            call,
+        }
+    }
+
+    fn push_scope(&mut self, bindings: Vec<(Symbol, Object)>) {
+        let mut env = Environment::new_enclosed(Rc::clone(&self.env));
+        for (name, value) in bindings {
+            env.set(name, value);
+        }
+        let outer = std::mem::replace(&mut self.env, gc::alloc(env));
+        self.scopes.push(outer);
+    }
+
+    /// Returns the call of the frame, which only the top level does not have
+    fn call(&self) -> Result<&Call> {
+        self.call
+            .as_ref()
+            .ok_or_else(|| anyhow!("invalid bytecode: parameters at the top level"))
+    }
+
+    /// Returns how many arguments the call passed in total, for errors
+    fn got(&self) -> usize {
+        self.call
+            .as_ref()
+            .map_or(0, |call| call.positional + call.named)
+    }
+
+    fn pop_scope(&mut self) -> Result<()> {
+        match self.scopes.pop() {
+            Some(env) => self.env = env,
+            None => bail!("invalid bytecode: no scope to leave"),
+        }
+        Ok(())
+    }
+}
+
+/// Runs compiled programs under the configured limits, with the same results as the evaluator.
+/// Memory is estimated the same way as in the evaluator, so both engines fail at the same limit.
+pub struct Vm {
+    max_depth: usize,
+    max_steps: Option<u64>,
+    max_memory: Option<usize>,
+    timeout: Option<Duration>,
+    cancellation: Option<CancellationToken>,
+    /// When the running program times out
+    deadline: Option<Instant>,
+    /// The number of instructions executed by the last call to run
+    steps: u64,
+    /// The memory in use by the environments the virtual machine created
+    meter: MemoryMeter,
+    /// The memory the names of the running program added to the symbol table
+    names_size: usize,
+    /// The most memory in use during the last call to run, as checked by `reserve`
+    peak_memory: usize,
+    /// The name of the script in stack traces
+    source_name: String,
+    stack: Vec<Object>,
+    /// The active calls, outermost first
+    frames: Vec<Frame>,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Compiles and runs the program with the default limits
+pub fn run_program(program: &Program) -> Result<Object> {
+    let bytecode = compiler::compile(program)?;
+    Vm::new().run(&bytecode)
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Self {
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_steps: None,
+            max_memory: None,
+            timeout: None,
+            cancellation: None,
+            deadline: None,
+            steps: 0,
+            meter: MemoryMeter::default(),
+            names_size: 0,
+            peak_memory: 0,
+            source_name: DEFAULT_SOURCE_NAME.to_string(),
+            stack: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    /// Sets the number of nested calls after which execution fails with `RuntimeError::StackOverflow`
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Sets the number of instructions after which execution fails with `RuntimeError::OutOfFuel`
+    pub fn with_max_steps(mut self, max_steps: u64) -> Self {
+        self.max_steps = Some(max_steps);
+        self
+    }
+
+    /// Sets the number of bytes the arrays, strings, hashes and scopes created by a program may
+    /// take before execution fails with `RuntimeError::OutOfMemory`, estimated as in the evaluator
+    pub fn with_max_memory(mut self, max_memory: usize) -> Self {
+        self.max_memory = Some(max_memory);
+        self
+    }
+
+    /// Sets how long a program may run before failing with `RuntimeError::Cancelled`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Sets the name of the script in stack traces
+    pub fn with_source_name(mut self, source_name: &str) -> Self {
+        self.source_name = source_name.to_string();
+        self
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Returns the most memory the last run program had in use, as estimated for the memory limit
+    pub fn peak_memory(&self) -> usize {
+        self.peak_memory
+    }
+
+    pub fn run(&mut self, bytecode: &Bytecode) -> Result<Object> {
+        let env = gc::alloc(Environment::new());
+        self.run_with_env(bytecode, &env)
+    }
+
+    /// Runs the program in an existing environment, e.g. one kept between the lines of a REPL
+    pub fn run_with_env(&mut self, bytecode: &Bytecode, env: &Env) -> Result<Object> {
+        self.steps = 0;
+        env.borrow_mut().set_meter(&self.meter);
+        self.names_size = bytecode.names_size;
+        self.peak_memory = self.in_use();
+        self.reserve(0)?;
+        // A timeout too long to represent never expires.
+        self.deadline = self
+            .timeout
+            .and_then(|timeout| Instant::now().checked_add(timeout));
+        self.check_cancellation()?;
+        self.stack.clear();
+        self.frames = vec![Frame::new(
+            Rc::clone(&bytecode.main),
+            Rc::clone(env),
+            0,
+            None,
+        )];
+        let result = self
+            .execute(&bytecode.constants)
+            .map_err(|err| self.add_stack_trace(err, &bytecode.constants));
+        self.frames.clear();
+        self.stack.clear();
+        result
+    }
+
+    /// Adds the frames of the calls that were active to the stack trace of the error, innermost first
+    fn add_stack_trace(&self, mut err: anyhow::Error, constants: &[Constant]) -> anyhow::Error {
+        for frame in self.frames.iter().rev() {
+            if let Some(call) = &frame.call {
+                let frame = error::Frame {
+                    function: call.function.to_string(),
+                    source: self.source_name.clone(),
+                    position: call.position,
+                    arguments: self.render_arguments(call, constants),
+                };
+                err = error::add_frame(err, frame);
+            }
+        }
+        err
+    }
+
+    /// Renders the arguments of a call, which are still on the stack, for its frame in a stack trace
+    fn render_arguments(&self, call: &Call, constants: &[Constant]) -> Vec<String> {
+        let end = call.arguments + call.positional;
+        let positional = self.stack.get(call.arguments..end).unwrap_or_default();
+        let named = self.stack.get(end..end + call.named).unwrap_or_default();
+        let names = call_site_constant(constants, call.call_site)
+            .map(named_arguments)
+            .into_iter()
+            .flatten();
+        let named = names.zip(named.iter().cloned()).collect::<NamedArguments>();
+        render_arguments(positional, &named)
+    }
+
+    fn execute(&mut self, constants: &[Constant]) -> Result<Object> {
+        loop {
+            let frame = self.frame()?;
+            let Some(&instruction) = frame.function.instructions.get(frame.ip) else {
+                bail!("invalid bytecode: no instruction at {}", frame.ip);
+            };
+            frame.ip += 1;
+            self.use_fuel()?;
+            match instruction {
+                Instruction::Constant(index) => match constant(constants, index)?.to_object() {
+                    Some(value) => self.push(value),
+                    None => bail!("invalid bytecode: constant {} is not a value", index),
+                },
+                Instruction::True => self.push(Object::Boolean(true)),
+                Instruction::False => self.push(Object::Boolean(false)),
+                Instruction::Null => self.push(Object::Null),
+                Instruction::Pop => {
+                    self.pop()?;
+                }
+                Instruction::GetName(name) => {
+                    let name = name_constant(constants, name)?;
+                    let value = self.frame()?.env.borrow().get(name);
+                    // Names that no scope binds may be builtins.
+                    match value.or_else(|| Builtin::lookup(name).map(Object::Builtin)) {
+                        Some(value) => self.push(value),
+                        None => bail!("identifier not found: {}", name),
+                    }
+                }
+                Instruction::Let {
+                    pattern,
+                    kind,
+                    position,
+                } => {
+                    let pattern = pattern_constant(constants, pattern)?;
+                    let value = self.pop()?;
+                    let mut bindings = Vec::new();
+                    if !match_pattern(&pattern.pattern, &value, &mut bindings) {
+                        bail!("pattern {} does not match {:?}", pattern.source, value);
+                    }
+                    self.reserve(bindings.iter().map(|(_, value)| binding_size(value)).sum())?;
+                    let env = &self.frame()?.env;
+                    for (name, value) in bindings {
+                        env.borrow_mut().declare(name, value, kind, position)?;
+                    }
+                }
+                Instruction::Assign(name) => {
+                    let name = name_constant(constants, name)?;
+                    let value = self.pop()?;
+                    self.frame()?.env.borrow_mut().assign(name, value.clone())?;
+                    self.push(value);
+                }
+                Instruction::CompoundAssign { name, operator } => {
+                    let name = name_constant(constants, name)?;
+                    let value = self.pop()?;
+                    let env = Rc::clone(&self.frame()?.env);
+                    let current = match env.borrow().get(name) {
+                        Some(current) => current,
+                        None => bail!("cannot assign to undefined variable: {}", name),
+                    };
+                    self.reserve(result_size(operator.symbol(), &current, &value))?;
+                    let value = eval_infix_expression(operator.symbol(), current, value)?;
+                    env.borrow_mut().assign(name, value.clone())?;
+                    self.push(value);
+                }
+                Instruction::Bang => {
+                    let right = self.pop()?;
+                    self.push(eval_bang_prefix_expression(right)?);
+                }
+                Instruction::Minus => {
+                    let right = self.pop()?;
+                    self.push(eval_minus_prefix_operator_expression(right)?);
+                }
+                Instruction::Infix(operator) => {
+                    let right = self.pop()?;
+                    let left = self.pop()?;
+                    self.reserve(result_size(operator.symbol(), &left, &right))?;
+                    self.push(eval_infix_expression(operator.symbol(), left, right)?);
+                }
+                Instruction::Index => {
+                    let index = self.pop()?;
+                    let left = self.pop()?;
+                    self.push(eval_index_expression(left, index)?);
+                }
+                Instruction::Array(length) => {
+                    let array = Object::Array(self.pop_n(length as usize)?);
+                    self.reserve(array.size())?;
+                    self.push(array);
+                }
+                Instruction::CheckHashKey => {
+                    self.peek()?.hash_key()?;
+                }
+                Instruction::Hash(length) => {
+                    let mut values = self.pop_n(2 * length as usize)?.into_iter();
+                    let mut hash = BTreeMap::new();
+                    while let (Some(key), Some(value)) = (values.next(), values.next()) {
+                        hash.insert(key.hash_key()?, value);
+                    }
+                    let hash = Object::Hash(hash);
+                    self.reserve(hash.size())?;
+                    self.push(hash);
+                }
+                Instruction::Jump(target) => self.frame()?.ip = target as usize,
+                Instruction::JumpIfNotTrue(target) => {
+                    let condition = self.pop()?.cast_to_boolean()?;
+                    if condition != Object::Boolean(true) {
+                        self.frame()?.ip = target as usize;
+                    }
+                }
+                Instruction::Closure(index) => {
+                    let Constant::Function(function) = constant(constants, index)? else {
+                        bail!("invalid bytecode: constant {} is not a function", index);
+                    };
+                    let env = Rc::clone(&self.frame()?.env);
+                    self.push(Object::Closure {
+                        function: Rc::clone(function),
+                        env,
+                    });
+                }
+                Instruction::CheckCallable => {
+                    let function = self.peek()?;
+                    if !matches!(function, Object::Closure { .. } | Object::Builtin(_)) {
+                        bail!("not a function: {:?}", function);
+                    }
+                }
+                Instruction::Spread => {
+                    // Ranges are spread lazily into any number of items, so each item is a step,
+                    // which also checks for cancellation, and is counted before it is stored.
+                    let mut items = Vec::new();
+                    let mut spread = 0;
+                    for item in self.pop()?.iterate()? {
+                        self.use_fuel()?;
+                        spread += item.size();
+                        self.reserve(spread)?;
+                        items.push(item);
+                    }
+                    self.push(Object::Array(items));
+                }
+                Instruction::Call(call_site) => self.call(constants, call_site, false)?,
+                Instruction::TailCall(call_site) => self.call(constants, call_site, true)?,
+                Instruction::Return => {
+                    let value = self.pop()?;
+                    let frame = self.frames.pop().ok_or_else(no_frame)?;
+                    if self.frames.is_empty() {
+                        return Ok(value);
+                    }
+                    self.stack.truncate(frame.stack_base);
+                    self.push(value);
+                }
+                Instruction::ArgumentOrJump { parameter, target } => {
+                    let frame = self.frames.last_mut().ok_or_else(no_frame)?;
+                    let parameter = parameter as usize;
+                    let Some(compiled) = frame.function.parameters.get(parameter) else {
+                        bail!("invalid bytecode: no parameter {}", parameter);
+                    };
+                    let call = frame.call()?;
+                    // Named arguments go to the parameters that are plain identifiers, past the positional ones.
+                    let index = match compiled.pattern.pattern {
+                        _ if parameter < call.positional => Some(call.arguments + parameter),
+                        PatternNode::Binding(name) if call.named > 0 => {
+                            let call_site = call_site_constant(constants, call.call_site)?;
+                            named_arguments(call_site)
+                                .position(|named| named == name)
+                                .map(|index| call.arguments + call.positional + index)
+                        }
+                        _ => None,
+                    };
+                    match index {
+                        Some(index) => {
+                            frame.ip = target as usize;
+                            let Some(value) = self.stack.get(index) else {
+                                bail!("invalid bytecode: arguments are not on the stack");
+                            };
+                            let value = value.clone();
+                            self.push(value);
+                        }
+                        None if compiled.has_default => {}
+                        None => bail!(
+                            "wrong number of arguments: expected={}, got={}",
+                            expected_arguments(&frame.function),
+                            frame.got()
+                        ),
+                    }
+                }
+                Instruction::BindParameter(parameter) => {
+                    let value = self.pop()?;
+                    let frame = self.frame()?;
+                    let Some(compiled) = frame.function.parameters.get(parameter as usize) else {
+                        bail!("invalid bytecode: no parameter {}", parameter);
+                    };
+                    let pattern = &compiled.pattern;
+                    // The value is a copy already, which a plain parameter binds as it is.
+                    if let PatternNode::Binding(name) = pattern.pattern {
+                        frame.bindings.push((name, value));
+                    } else if !match_pattern(&pattern.pattern, &value, &mut frame.bindings) {
+                        bail!(
+                            "argument {:?} does not match parameter {}",
+                            value,
+                            pattern.source
+                        );
+                    }
+                }
+                Instruction::EnterBody => {
+                    let frame = self.frames.last_mut().ok_or_else(no_frame)?;
+                    let mut bindings = std::mem::take(&mut frame.bindings);
+                    if let Some(rest) = frame.function.rest {
+                        let call = frame.call()?;
+                        let parameters = frame.function.parameters.len().min(call.positional);
+                        let rest_values = self
+                            .stack
+                            .get(call.arguments + parameters..call.arguments + call.positional)
+                            .ok_or_else(|| {
+                                anyhow!("invalid bytecode: arguments are not on the stack")
+                            })?;
+                        bindings.push((rest, Object::Array(rest_values.to_vec())));
+                    }
+                    self.reserve_scope(&bindings)?;
+                    let frame = self.frame()?;
+                    let mut env = Environment::new_enclosed(Rc::clone(&frame.env));
+                    for (name, value) in bindings {
+                        env.set(name, value);
+                    }
+                    frame.env = gc::alloc(env);
+                }
+                Instruction::PushScope => {
+                    self.reserve_scope(&[])?;
+                    self.frame()?.push_scope(Vec::new());
+                }
+                Instruction::PopScope => self.frame()?.pop_scope()?,
+                Instruction::EnterLoop { exit, next } => {
+                    let stack_len = self.stack.len();
+                    let frame = self.frame()?;
+                    let scopes_len = frame.scopes.len();
+                    frame.loops.push(Loop {
+                        exit: exit as usize,
+                        next: next as usize,
+                        stack_len,
+                        scopes_len,
+                    });
+                }
+                Instruction::ExitLoop => {
+                    self.frame()?.loops.pop();
+                }
+                Instruction::Break => self.leave_iteration("break", |l| l.exit)?,
+                Instruction::Continue => self.leave_iteration("continue", |l| l.next)?,
+                Instruction::Iterate => {
+                    let items = self.pop()?.iterate()?;
+                    self.frame()?.iterators.push(items);
+                }
+                Instruction::IterateNext { variable, exit } => {
+                    let variable = name_constant(constants, variable)?;
+                    let frame = self.frame()?;
+                    let Some(items) = frame.iterators.last_mut() else {
+                        bail!("invalid bytecode: no iteration in progress");
+                    };
+                    match items.next() {
+                        Some(item) => {
+                            let bindings = vec![(variable, item)];
+                            self.reserve_scope(&bindings)?;
+                            self.frame()?.push_scope(bindings);
+                        }
+                        None => frame.ip = exit as usize,
+                    }
+                }
+                Instruction::EndIteration => {
+                    self.frame()?.iterators.pop();
+                }
+                Instruction::MatchArm { pattern, next } => {
+                    let pattern = pattern_constant(constants, pattern)?;
+                    let mut bindings = Vec::new();
+                    if match_pattern(&pattern.pattern, self.peek()?, &mut bindings) {
+                        self.reserve_scope(&bindings)?;
+                        self.frame()?.push_scope(bindings);
+                    } else {
+                        self.frame()?.ip = next as usize;
+                    }
+                }
+                Instruction::NoMatch => {
+                    let subject = self.pop()?;
+                    bail!("no match arm matched {:?}", subject);
+                }
+            }
+        }
+    }
+
+    fn frame(&mut self) -> Result<&mut Frame> {
+        self.frames.last_mut().ok_or_else(no_frame)
+    }
+
+    fn push(&mut self, value: Object) {
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self) -> Result<Object> {
+        self.stack
+            .pop()
+            .ok_or_else(|| anyhow!("invalid bytecode: pop from an empty stack"))
+    }
+
+    fn peek(&self) -> Result<&Object> {
+        self.stack
+            .last()
+            .ok_or_else(|| anyhow!("invalid bytecode: peek at an empty stack"))
+    }
+
+    /// Pops the given number of values, in the order they were pushed
+    fn pop_n(&mut self, n: usize) -> Result<Vec<Object>> {
+        match self.stack.len().checked_sub(n) {
+            Some(len) => Ok(self.stack.split_off(len)),
+            None => bail!("invalid bytecode: pop from an empty stack"),
+        }
+    }
+
+    /// Leaves the current iteration of the innermost loop for the address chosen from it
+    fn leave_iteration(&mut self, keyword: &str, target: fn(&Loop) -> usize) -> Result<()> {
+        let frame = self.frames.last_mut().ok_or_else(no_frame)?;
+        let Some(innermost) = frame.loops.last() else {
+            bail!("{} outside of loop", keyword);
+        };
+        let (stack_len, scopes_len, ip) =
+            (innermost.stack_len, innermost.scopes_len, target(innermost));
+        if frame.scopes.len() > scopes_len {
+            frame.env = Rc::clone(&frame.scopes[scopes_len]);
+            frame.scopes.truncate(scopes_len);
+        }
+        frame.ip = ip;
+        self.stack.truncate(stack_len);
+        Ok(())
+    }
+
+    /// Calls the function below the arguments on the stack in a new frame, or in place of the current one
+    /// for a tail call. The arguments stay on the stack, below the values of the call.
+    fn call(&mut self, constants: &[Constant], index: ConstantIndex, tail: bool) -> Result<()> {
+        let call_site = call_site_constant(constants, index)?;
+        let Some(function_index) = self.stack.len().checked_sub(call_site.arguments.len() + 1)
+        else {
+            bail!("invalid bytecode: pop from an empty stack");
+        };
+        let (positional, named) = self.arrange_arguments(call_site, function_index + 1)?;
+        let (function, env) = match std::mem::replace(&mut self.stack[function_index], Object::Null)
+        {
+            Object::Closure { function, env } => (function, env),
+            // A builtin returns at once, also from the function whose tail call it is.
+            Object::Builtin(builtin) => {
+                let arguments = &self.stack[function_index + 1..];
+                let (positional, named) = arguments.split_at(positional);
+                let named = named_arguments(call_site)
+                    .zip(named.iter().cloned())
+                    .collect::<NamedArguments>();
+                let value = builtin.apply(positional, &named)?;
+                let stack_base = match tail {
+                    true => self.frames.pop().ok_or_else(no_frame)?.stack_base,
+                    false => function_index,
+                };
+                self.stack.truncate(stack_base);
+                self.push(value);
+                return Ok(());
+            }
+            function => bail!("not a function: {:?}", function),
+        };
+
+        let stack_base = if tail {
+            // The tail call takes over the frame of the call it replaces, and its arguments
+            // move down to where those of the replaced call were.
+            let replaced = self.frames.pop().ok_or_else(no_frame)?;
+            self.stack.drain(replaced.stack_base..function_index);
+            replaced.stack_base
+        } else {
+            if self.frames.len() > self.max_depth {
+                return Err(self.stack_overflow());
+            }
+            function_index
+        };
+        let call = Call {
+            // Functions are named by the `let` binding they were defined in, or else by their callee.
+            function: function.name.unwrap_or(call_site.callee),
+            call_site: index,
+            position: call_site.position,
+            arguments: stack_base + 1,
+            positional,
+            named,
+        };
+        // Arguments that do not fit the parameters fail the call inside its frame, as in the evaluator.
+        let checked = check_arguments(&function, call_site, positional, named);
+        self.frames
+            .push(Frame::new(function, env, stack_base, Some(call)));
+        checked
+    }
+
+    /// Arranges the arguments of a call, which are on the stack from `start` as the call site lists them,
+    /// into the positional ones, with spread arrays expanded, followed by the named ones.
+    /// Returns how many there are of each.
+    fn arrange_arguments(&mut self, call_site: &CallSite, start: usize) -> Result<(usize, usize)> {
+        let kinds = &call_site.arguments;
+        if kinds.iter().all(|kind| *kind == ArgumentKind::Positional) {
+            return Ok((kinds.len(), 0));
+        }
+        let values = self.stack.split_off(start);
+        let mut named = Vec::new();
+        for (kind, value) in kinds.iter().zip(values) {
+            match (kind, value) {
+                (ArgumentKind::Positional, value) => self.stack.push(value),
+                (ArgumentKind::Spread, Object::Array(items)) => self.stack.extend(items),
+                (ArgumentKind::Spread, value) => {
+                    bail!(
+                        "invalid bytecode: spread argument {:?} is not an array",
+                        value
+                    )
+                }
+                (ArgumentKind::Named(_), value) => named.push(value),
+            }
+        }
+        let positional = self.stack.len() - start;
+        let count = named.len();
+        self.stack.extend(named);
+        Ok((positional, count))
+    }
+
+    /// Fails unless a new scope holding the given bindings fits in the memory limit
+    fn reserve_scope(&mut self, bindings: &[(Symbol, Object)]) -> Result<()> {
+        let size = bindings
+            .iter()
+            .map(|(_, value)| binding_size(value))
+            .sum::<usize>();
+        self.reserve(ENVIRONMENT_SIZE + size)
+    }
+
+    /// Returns the memory in use by the environments and the names of the running program
+    fn in_use(&self) -> usize {
+        self.meter.get().saturating_add(self.names_size)
+    }
+
+    /// Fails unless `bytes` more than the memory in use fit in the limit. Environments kept
+    /// alive only by cycles count until they are collected, which is done before failing.
+    fn reserve(&mut self, bytes: usize) -> Result<()> {
+        let mut in_use = self.in_use().saturating_add(bytes);
+        if self
+            .max_memory
+            .is_some_and(|max_memory| in_use > max_memory)
+        {
+            gc::collect();
+            in_use = self.in_use().saturating_add(bytes);
+        }
+        self.peak_memory = self.peak_memory.max(in_use);
+        match self.max_memory {
+            Some(max_memory) if in_use > max_memory => {
+                Err(RuntimeError::OutOfMemory { limit: max_memory }.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts one instruction against the budget, checking for cancellation every so often
+    fn use_fuel(&mut self) -> Result<()> {
+        self.steps += 1;
+        match self.max_steps {
+            Some(max_steps) if self.steps > max_steps => {
+                Err(RuntimeError::OutOfFuel { steps: max_steps }.into())
+            }
+            _ if self.steps.is_multiple_of(CANCELLATION_CHECK_INTERVAL) => {
+                self.check_cancellation()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_cancellation(&self) -> Result<()> {
+        let cancelled = self.cancellation.as_ref().is_some_and(|t| t.is_cancelled());
+        let timed_out = self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline);
+        if cancelled || timed_out {
+            return Err(RuntimeError::Cancelled.into());
+        }
+        Ok(())
+    }
+
+    fn stack_overflow(&self) -> anyhow::Error {
+        let call_stack = self
+            .frames
+            .iter()
+            .filter_map(|frame| frame.call.as_ref())
+            .map(|call| call.function.to_string())
+            .collect();
+        RuntimeError::StackOverflow { call_stack }.into()
+    }
+}
+
+fn no_frame() -> anyhow::Error {
+    anyhow!("invalid bytecode: return from the top level")
+}
+
+fn constant(constants: &[Constant], index: ConstantIndex) -> Result<&Constant> {
+    constants
+        .get(index as usize)
+        .ok_or_else(|| anyhow!("invalid bytecode: no constant {}", index))
+}
+
+fn name_constant(constants: &[Constant], index: ConstantIndex) -> Result<Symbol> {
+    match constant(constants, index)? {
+        Constant::Name(name) => Ok(*name),
+        _ => bail!("invalid bytecode: constant {} is not a name", index),
+    }
+}
+
+fn pattern_constant(constants: &[Constant], index: ConstantIndex) -> Result<&CompiledPattern> {
+    match constant(constants, index)? {
+        Constant::Pattern(pattern) => Ok(pattern),
+        _ => bail!("invalid bytecode: constant {} is not a pattern", index),
+    }
+}
+
+fn call_site_constant(constants: &[Constant], index: ConstantIndex) -> Result<&CallSite> {
+    match constant(constants, index)? {
+        Constant::CallSite(call_site) => Ok(call_site),
+        _ => bail!("invalid bytecode: constant {} is not a call site", index),
+    }
+}
+
+/// Checks that the function takes the arguments of the call. Its instructions then bind them,
+/// evaluating defaults for the parameters that no argument fills.
+fn check_arguments(
+    function: &CompiledFunction,
+    call_site: &CallSite,
+    positional: usize,
+    named: usize,
+) -> Result<()> {
+    let parameters = &function.parameters;
+    if positional > parameters.len() && function.rest.is_none() {
+        bail!(
+            "wrong number of arguments: expected={}, got={}",
+            expected_arguments(function),
+            positional + named
+        );
+    }
+    // Named arguments fill the parameters that are plain identifiers, which no other argument fills.
+    for (i, name) in named_arguments(call_site).enumerate() {
+        let parameter = parameters.iter().position(
+            |p| matches!(&p.pattern.pattern, PatternNode::Binding(binding) if *binding == name),
+        );
+        match parameter {
+            Some(parameter)
+                if parameter >= positional
+                    && !named_arguments(call_site).take(i).any(|n| n == name) => {}
+            Some(_) => bail!("parameter {} is given more than once", name),
+            None => bail!("unknown parameter: {}", name),
+        }
+    }
+    Ok(())
+}
+
+/// Returns the names of the named arguments of a call, in the order they are passed
+fn named_arguments(call_site: &CallSite) -> impl Iterator<Item = Symbol> + '_ {
+    call_site.arguments.iter().filter_map(|kind| match kind {
+        ArgumentKind::Named(name) => Some(*name),
+        _ => None,
+    })
+}
+
+/// Describes the number of arguments the function takes, for errors
+fn expected_arguments(function: &CompiledFunction) -> String {
+    let parameters = &function.parameters;
+    let required = parameters.iter().filter(|p| !p.has_default).count();
+    match function.rest {
+        Some(_) => format!("at least {}", required),
+        None if required == parameters.len() => required.to_string(),
+        None => format!("{}..={}", required, parameters.len()),
+    }
+}
+
+/// Checks whether the value matches the pattern, collecting the values of the pattern variables into `bindings`
+fn match_pattern(
+    pattern: &PatternNode,
+    value: &Object,
+    bindings: &mut Vec<(Symbol, Object)>,
+) -> bool {
+    match (pattern, value) {
+        (PatternNode::Wildcard, _) => true,
+        (PatternNode::Binding(name), value) => {
+            bindings.push((*name, value.clone()));
+            true
+        }
+        (PatternNode::Literal(literal), value) => Object::from(literal.clone()) == *value,
+
+        // An array pattern matches arrays of the same length, or at least as long if it has a rest binding.
+        (PatternNode::Array { elements, rest }, Object::Array(values)) => {
+            let length_matches = match rest {
+                Some(_) => values.len() >= elements.len(),
+                None => values.len() == elements.len(),
+            };
+            if !length_matches {
+                return false;
+            }
+            for (element, value) in elements.iter().zip(values) {
+                if !match_pattern(element, value, bindings) {
+                    return false;
+                }
+            }
+            if let Some(&rest) = rest.as_ref().filter(|rest| rest.as_str() != "_") {
+                let rest_values = Object::Array(values[elements.len()..].to_vec());
+                bindings.push((rest, rest_values));
+            }
+            true
+        }
+
+        // A hash pattern matches hashes that have all of its keys, ignoring any other keys.
+        (PatternNode::Hash(pairs), Object::Hash(hash)) => pairs.iter().all(|(key, pattern)| {
+            hash.get(key)
+                .is_some_and(|value| match_pattern(pattern, value, bindings))
+        }),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::StackTrace;
+    use crate::evaluator::Evaluator;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn run(input: &str, mut vm: Vm) -> Result<Object> {
+        let program = Parser::new(Lexer::new(input)).parse_program().unwrap();
+        let bytecode = compiler::compile(&program)?;
+        vm.run(&bytecode)
+    }
+
+    #[test]
+    fn test_compile() {
+        let program = Parser::new(Lexer::new("let x = 1; x + 1"))
+            .parse_program()
+            .unwrap();
+        let bytecode = compiler::compile(&program).unwrap();
+        assert_eq!(
+            bytecode.main.instructions,
+            vec![
+                Instruction::Constant(0),
+                Instruction::Let {
+                    pattern: 1,
+                    kind: crate::ast::BindingKind::Let,
+                    position: Position { line: 1, column: 5 },
+                },
+                Instruction::GetName(2),
+                Instruction::Constant(0),
+                Instruction::Infix(crate::code::Operator::Add),
+                Instruction::Return,
+            ]
+        );
+        // Constants used more than once are stored once.
+        assert_eq!(bytecode.constants.len(), 3);
+
+        let program = Parser::new(Lexer::new("match 1 { x if x => 1, _ => 2 }"))
+            .parse_program()
+            .unwrap();
+        assert!(compiler::compile(&program).is_ok());
+    }
+
+    #[test]
+    fn test_deep_recursion() {
+        // Calls do not use the Rust stack, so the call depth is only limited by max_depth.
+        let input = "let f = fn(n) { if (n == 0) { 0 } else { 1 + f(n - 1) } }; f(50000)";
+        let result = run(input, Vm::new().with_max_depth(100_000));
+        assert_eq!(result.unwrap(), Object::Integer(50000));
+
+        let err = run(input, Vm::new()).unwrap_err();
+        match err.downcast_ref::<RuntimeError>() {
+            Some(RuntimeError::StackOverflow { call_stack }) => {
+                assert_eq!(call_stack.len(), DEFAULT_MAX_DEPTH)
+            }
+            _ => panic!("expected a stack overflow, got {}", err),
+        }
+        let trace = err.downcast_ref::<StackTrace>().unwrap();
+        assert_eq!(trace.frames.len(), DEFAULT_MAX_DEPTH);
+
+        // Tail calls replace the current call.
+        let input = "let f = fn(n) { if (n == 0) { 0 } else { f(n - 1) } }; f(100000)";
+        assert_eq!(run(input, Vm::new()).unwrap(), Object::Integer(0));
+    }
+
+    #[test]
+    fn test_limits() {
+        let err = run("while (true) { }", Vm::new().with_max_steps(1000)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::OutOfFuel { steps: 1000 })
+        );
+
+        // Spreading uses a step for each item.
+        let input = "let f = fn(...r) { 1 }; f(...0..100000000000)";
+        let err = run(input, Vm::new().with_max_steps(1000)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::OutOfFuel { steps: 1000 })
+        );
+
+        // Each instruction is a step.
+        assert!(run("1 + 2", Vm::new().with_max_steps(4)).is_ok());
+        assert!(run("1 + 2", Vm::new().with_max_steps(3)).is_err());
+
+        let token = CancellationToken::new();
+        token.cancel();
+        let err = run("while (true) { }", Vm::new().with_cancellation(token)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::Cancelled)
+        );
+
+        let vm = Vm::new().with_timeout(Duration::from_millis(20));
+        let err = run("let mut n = 0; while (true) { n += 1; }", vm).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::Cancelled)
+        );
+        let vm = Vm::new().with_timeout(Duration::from_millis(20));
+        let err = run("let f = fn(...r) { 1 }; f(...0..100000000000)", vm).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::Cancelled)
+        );
+    }
+
+    #[test]
+    fn test_out_of_memory() {
+        let compile = |input: &str| {
+            let program = Parser::new(Lexer::new(input)).parse_program().unwrap();
+            compiler::compile(&program).unwrap()
+        };
+        let limit = 1024 * 1024;
+        let mut vm = Vm::new().with_max_memory(limit);
+
+        let tests = [
+            r#"let mut s = "ab"; while (true) { s += s; }"#,
+            "let f = fn(...xs) { xs }; f(...0..1000000000)",
+            "let f = fn(xs) { f([xs, xs, xs, xs]) }; f([])",
+            r#"let mut h = {}; while (true) { h = {"n": 1, "m": [h, h]}; }"#,
+            r#"for (c in "ab") { let mut s = "ab"; while (true) { s = s + s; } }"#,
+        ];
+        for input in tests {
+            let err = vm.run(&compile(input)).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<RuntimeError>(),
+                Some(&RuntimeError::OutOfMemory { limit }),
+                "{}",
+                input
+            );
+            assert!(vm.peak_memory() > limit);
+        }
+        let input = "let mut i = 0; while (i < 100000) { i += 1; } i";
+        assert_eq!(vm.run(&compile(input)).unwrap(), Object::Integer(100000));
+        assert!(vm.peak_memory() < limit / 8);
+
+        // Memory is estimated as in the evaluator.
+        let input = r#"let f = fn(x, ...r) { match (r) { [b] => x + b, _ => "" } };
+            let mut s = ""; for (c in "ab") { s += f(c, "!"); } let h = {s: [s]}; h"#;
+        let program = Parser::new(Lexer::new(input)).parse_program().unwrap();
+        let mut evaluator = Evaluator::new();
+        let evaluated = evaluator.eval_program(&program).unwrap();
+        let result = vm.run(&compiler::compile(&program).unwrap()).unwrap();
+        assert_eq!(result.to_string(), evaluated.to_string());
+        assert!(vm.peak_memory() > 0 && vm.peak_memory() < limit);
+        assert_eq!(vm.peak_memory(), evaluator.peak_memory());
+
+        // So are the names the program adds to the symbol table.
+        let input = (0..30000)
+            .map(|i: u32| {
+                // Identifiers have no digits, so the number is spelled in letters.
+                let name = i.to_string();
+                let name = name.bytes().map(|d| (d - b'0' + b'a') as char);
+                format!("let vm_memory_test_{} = 0;", name.collect::<String>())
+            })
+            .collect::<String>();
+        let input = format!("fn() {{ {} }}; 1", input);
+        let err = vm.run(&compile(&input)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::OutOfMemory { limit })
+        );
+        assert_eq!(vm.run(&compile(&input)).unwrap(), Object::Integer(1));
+    }
+
+    #[test]
+    fn test_stack_trace() {
+        let input = "let g = fn(x) { x + true };\nlet f = fn(x) { g(x) + 1 };\nf(1)";
+        let err = run(input, Vm::new().with_source_name("script.mnk")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "type mismatch: Integer(1) + Boolean(true)\n    \
+             at g (script.mnk:2:17)\n    \
+             at f (script.mnk:3:1)"
+        );
+
+        // Frames hold the arguments as passed, whatever the parameters bind.
+        let input = "let f = fn([a], b = 2, ...r) { a + true };\nf([1], 3, 4, 5)";
+        let err = run(input, Vm::new()).unwrap_err();
+        let trace = err.downcast_ref::<StackTrace>().unwrap();
+        assert_eq!(trace.frames[0].arguments, ["[1]", "3", "4", "5"]);
+        let err = run("let f = fn(a, b) { a + b }; f(b: true, a: 1)", Vm::new()).unwrap_err();
+        let trace = err.downcast_ref::<StackTrace>().unwrap();
+        assert_eq!(trace.frames[0].arguments, ["b: true", "a: 1"]);
+    }
+
+    #[test]
+    fn test_invalid_bytecode() {
+        // Malformed bytecode fails instead of panicking.
+        let function = |instructions| CompiledFunction {
+            name: None,
+            parameters: Vec::new(),
+            rest: None,
+            instructions,
+            lines: Vec::new(),
+            source: String::new(),
+        };
+        let tests = [
+            (
+                vec![Instruction::Pop],
+                "invalid bytecode: pop from an empty stack",
+            ),
+            (
+                vec![Instruction::Null],
+                "invalid bytecode: no instruction at 1",
+            ),
+            (
+                vec![Instruction::Constant(7)],
+                "invalid bytecode: no constant 7",
+            ),
+            (
+                vec![Instruction::Jump(100)],
+                "invalid bytecode: no instruction at 100",
+            ),
+            (
+                vec![Instruction::PopScope],
+                "invalid bytecode: no scope to leave",
+            ),
+        ];
+        for (instructions, expected) in tests {
+            let bytecode = Bytecode {
+                main: Rc::new(function(instructions)),
+                constants: Vec::new(),
+                names_size: 0,
+            };
+            let err = Vm::new().run(&bytecode).unwrap_err();
+            assert_eq!(err.to_string(), expected);
+        }
+    }
+}